@@ -32,6 +32,24 @@ fn mlua_sandbox_eval(bencher: &mut Bencher) {
     bencher.iter(|| vm.load(SCRIPT).eval::<bool>());
 }
 
+/// pool
+
+fn lmb_build_per_call(bencher: &mut Bencher) {
+    bencher.iter(|| {
+        let e = EvaluationBuilder::new(SCRIPT, Cursor::new("0")).build();
+        e.evaluate().unwrap()
+    });
+}
+
+fn lmb_pool(bencher: &mut Bencher) {
+    let pool = EvaluationBuilder::new(SCRIPT, Cursor::new("")).build_pool(1);
+    bencher.iter(|| {
+        let e = pool.get();
+        e.set_input(Cursor::new("0"));
+        let _ = e.evaluate().unwrap();
+    });
+}
+
 /// store
 
 fn lmb_no_store(bencher: &mut Bencher) {
@@ -123,5 +141,6 @@ benchmark_group!(
     lmb_read_unicode,
     read_from_buf_reader,
 );
+benchmark_group!(pool, lmb_build_per_call, lmb_pool);
 benchmark_group!(store, lmb_default_store, lmb_no_store, lmb_update);
-benchmark_main!(evaluation, read, store);
+benchmark_main!(evaluation, pool, read, store);
@@ -0,0 +1,2 @@
+ALTER TABLE store ADD COLUMN expires_at TEXT;
+CREATE INDEX store_expires_at ON store (expires_at) WHERE expires_at IS NOT NULL;
@@ -0,0 +1,408 @@
+use axum::{
+    body::Bytes,
+    http::{
+        header::{
+            AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, HOST,
+            IF_NONE_MATCH, SET_COOKIE, VARY,
+        },
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+
+/// Number of cached URLs before expired responses are pruned.
+/// New URLs are not cached while the cache is still full.
+const CACHE_CAPACITY: usize = 1_000;
+
+/// Number of cached responses of a URL selected by `vary`.
+/// The oldest response is evicted when a new one is cached.
+const CACHE_VARIANTS: usize = 16;
+
+/// Headers sent along with "304 Not Modified".
+///
+/// ref: <https://www.rfc-editor.org/rfc/rfc9110#section-15.4.5>
+const NOT_MODIFIED_HEADERS: [HeaderName; 6] =
+    [AGE, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES];
+
+/// Cache policy of the response, set by the script with `require('@lmb').response.cache`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CachePolicy {
+    /// Entity tag of the response, computed from the body when absent
+    pub etag: Option<String>,
+    /// Seconds the response is fresh, both in the server and in clients
+    pub max_age: Option<u64>,
+    /// Request headers selecting the response besides the URL
+    #[serde(default)]
+    pub vary: Vec<String>,
+}
+
+impl CachePolicy {
+    /// Set the headers of the policy, unless they are set by the script.
+    pub fn apply(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        if let Some(etag) = &self.etag {
+            let etag = if etag.ends_with('"') {
+                etag.clone()
+            } else {
+                format!("\"{etag}\"")
+            };
+            headers.entry(ETAG).or_insert(HeaderValue::from_str(&etag)?);
+        }
+        if let Some(max_age) = self.max_age {
+            headers
+                .entry(CACHE_CONTROL)
+                .or_insert(HeaderValue::from_str(&format!("max-age={max_age}"))?);
+        }
+        if !self.vary.is_empty() {
+            headers
+                .entry(VARY)
+                .or_insert(HeaderValue::from_str(&self.vary.join(", "))?);
+        }
+        Ok(())
+    }
+}
+
+struct Entry {
+    body: Bytes,
+    expires: Instant,
+    headers: HeaderMap,
+    stored: Instant,
+    // values of the request headers listed in the vary header
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl Entry {
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+/// In-process cache of successful GET responses, which are fresh according to `cache-control`.
+/// Responses are selected by the host, the path, the query, and the request headers
+/// listed in `vary`.
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: DashMap<String, Vec<Entry>>,
+}
+
+impl ResponseCache {
+    /// Respond with the fresh response cached for the request.
+    pub fn get(&self, parts: &Parts) -> Option<Response> {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return None;
+        }
+        let entries = self.entries.get(&cache_key(parts))?;
+        let now = Instant::now();
+        let authorized = parts.headers.contains_key(AUTHORIZATION);
+        let entry = entries.iter().find(|e| {
+            e.expires > now
+                && e.matches(&parts.headers)
+                && (!authorized || is_shareable(&e.headers))
+        })?;
+        let mut headers = entry.headers.clone();
+        headers.insert(AGE, now.duration_since(entry.stored).as_secs().into());
+        Some(respond(parts, StatusCode::OK, headers, entry.body.clone()))
+    }
+
+    /// Tag and cache the response built by the script, then respond to the request,
+    /// which may be conditional.
+    pub fn put(
+        &self,
+        parts: &Parts,
+        status: StatusCode,
+        mut headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let is_get = parts.method == Method::GET;
+        if (is_get || parts.method == Method::HEAD) && status == StatusCode::OK {
+            if !headers.contains_key(ETAG) {
+                let digest = format!("{:x}", Sha256::digest(&body));
+                if let Ok(etag) = HeaderValue::from_str(&format!("W/\"{}\"", &digest[..32])) {
+                    headers.insert(ETAG, etag);
+                }
+            }
+            if is_get {
+                self.store(parts, &headers, &body);
+            }
+        }
+        respond(parts, status, headers, body)
+    }
+
+    fn store(&self, parts: &Parts, headers: &HeaderMap, body: &Bytes) {
+        if headers.contains_key(SET_COOKIE) {
+            return;
+        }
+        if parts.headers.contains_key(AUTHORIZATION) && !is_shareable(headers) {
+            return;
+        }
+        let Some(ttl) = freshness(headers) else {
+            return;
+        };
+        let Some(vary) = vary(headers) else {
+            return;
+        };
+        let key = cache_key(parts);
+        let now = Instant::now();
+        if self.entries.len() >= CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entries| {
+                entries.retain(|e| e.expires > now);
+                !entries.is_empty()
+            });
+            if self.entries.len() >= CACHE_CAPACITY {
+                return;
+            }
+        }
+        let entry = Entry {
+            body: body.clone(),
+            expires: now + ttl,
+            headers: headers.clone(),
+            stored: now,
+            vary: vary
+                .into_iter()
+                .map(|name| {
+                    let value = parts.headers.get(&name).cloned();
+                    (name, value)
+                })
+                .collect(),
+        };
+        let mut entries = self.entries.entry(key).or_default();
+        entries.retain(|e| e.expires > now && !e.matches(&parts.headers));
+        if entries.len() >= CACHE_VARIANTS {
+            entries.remove(0);
+        }
+        entries.push(entry);
+    }
+}
+
+/// Get the key of the request from the host, which is the authority of HTTP/2 requests,
+/// and the path with the query.
+fn cache_key(parts: &Parts) -> String {
+    let host = parts
+        .headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| parts.uri.authority().map(|a| a.as_str()))
+        .unwrap_or_default();
+    let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    format!("{host}{path_and_query}")
+}
+
+/// Get how long the response is fresh in a shared cache from `cache-control`.
+fn freshness(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    for value in headers.get_all(CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("s-maxage", secs)) => max_age = Some(secs.trim_matches('"').parse().ok()?),
+                Some(("max-age", secs)) => {
+                    max_age = max_age.or(Some(secs.trim_matches('"').parse().ok()?));
+                }
+                None if matches!(directive.as_str(), "no-cache" | "no-store" | "private") => {
+                    return None
+                }
+                _ => {}
+            }
+        }
+    }
+    max_age.filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
+/// Check whether the response to a request with `authorization` may be cached and reused.
+///
+/// ref: <https://www.rfc-editor.org/rfc/rfc9111#section-3.5>
+fn is_shareable(headers: &HeaderMap) -> bool {
+    headers.get_all(CACHE_CONTROL).iter().any(|value| {
+        value.to_str().is_ok_and(|directives| {
+            directives.split(',').any(|directive| {
+                let directive = directive.trim().to_ascii_lowercase();
+                let name = directive
+                    .split_once('=')
+                    .map_or(directive.as_str(), |(n, _)| n);
+                matches!(name, "public" | "s-maxage" | "must-revalidate")
+            })
+        })
+    })
+}
+
+/// Get the request headers listed in `vary`, or none when the response varies on anything.
+fn vary(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = vec![];
+    for value in headers.get_all(VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if !name.is_empty() {
+                names.push(HeaderName::from_bytes(name.as_bytes()).ok()?);
+            }
+        }
+    }
+    Some(names)
+}
+
+/// Check `if-none-match` with the weak comparison.
+///
+/// ref: <https://www.rfc-editor.org/rfc/rfc9110#section-13.1.2>
+fn is_not_modified(request: &HeaderMap, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(etag) = etag.to_str().map(opaque) else {
+        return false;
+    };
+    request.get_all(IF_NONE_MATCH).iter().any(|value| {
+        value
+            .to_str()
+            .is_ok_and(|tags| tags.trim() == "*" || tags.split(',').any(|t| opaque(t) == etag))
+    })
+}
+
+fn respond(parts: &Parts, status: StatusCode, headers: HeaderMap, body: Bytes) -> Response {
+    let etag = headers.get(ETAG);
+    let is_safe = parts.method == Method::GET || parts.method == Method::HEAD;
+    if is_safe
+        && status == StatusCode::OK
+        && etag.is_some_and(|e| is_not_modified(&parts.headers, e))
+    {
+        let mut not_modified = HeaderMap::new();
+        for name in NOT_MODIFIED_HEADERS.iter().chain([&VARY]) {
+            for value in headers.get_all(name) {
+                not_modified.append(name, value.clone());
+            }
+        }
+        return (StatusCode::NOT_MODIFIED, not_modified).into_response();
+    }
+    (status, headers, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{freshness, is_not_modified, vary, CachePolicy, ResponseCache, CACHE_VARIANTS};
+    use axum::{
+        body::Bytes,
+        http::{
+            header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY},
+            request::Parts,
+            HeaderMap, HeaderValue, Request, StatusCode,
+        },
+    };
+    use std::time::Duration;
+
+    fn headers(name: axum::http::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Parts {
+        let mut builder = Request::get("/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn cache_authorization() {
+        let cache = ResponseCache::default();
+        let authorized = request(&[("authorization", "Bearer token")]);
+        let h = headers(CACHE_CONTROL, "max-age=60");
+        cache.put(&authorized, StatusCode::OK, h.clone(), Bytes::new());
+        assert!(cache.get(&authorized).is_none());
+
+        cache.put(&request(&[]), StatusCode::OK, h, Bytes::new());
+        assert!(cache.get(&request(&[])).is_some());
+        assert!(cache.get(&authorized).is_none());
+
+        let h = headers(CACHE_CONTROL, "public, max-age=60");
+        cache.put(&authorized, StatusCode::OK, h, Bytes::new());
+        assert!(cache.get(&authorized).is_some());
+        assert!(cache.get(&request(&[])).is_some());
+    }
+
+    #[test]
+    fn cache_variants() {
+        let cache = ResponseCache::default();
+        let mut h = headers(CACHE_CONTROL, "max-age=60");
+        h.insert(VARY, HeaderValue::from_static("accept-language"));
+        for i in 0..CACHE_VARIANTS + 2 {
+            let parts = request(&[("accept-language", &i.to_string())]);
+            cache.put(&parts, StatusCode::OK, h.clone(), Bytes::new());
+        }
+        assert_eq!(CACHE_VARIANTS, cache.entries.get("/").unwrap().len());
+        assert!(cache.get(&request(&[("accept-language", "0")])).is_none());
+        assert!(cache.get(&request(&[("accept-language", "2")])).is_some());
+    }
+
+    #[test]
+    fn cache_host() {
+        let cache = ResponseCache::default();
+        let h = headers(CACHE_CONTROL, "max-age=60");
+        let a = request(&[("host", "a.example.com")]);
+        cache.put(&a, StatusCode::OK, h, Bytes::from("a"));
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&request(&[("host", "b.example.com")])).is_none());
+        assert!(cache.get(&request(&[])).is_none());
+    }
+
+    #[test]
+    fn cache_policy_apply() {
+        let policy = CachePolicy {
+            etag: Some("v1".to_string()),
+            max_age: Some(60),
+            vary: vec!["accept".to_string(), "accept-language".to_string()],
+        };
+        let mut h = headers(CACHE_CONTROL, "no-store");
+        policy.apply(&mut h).unwrap();
+        assert_eq!("\"v1\"", h[ETAG]);
+        assert_eq!("no-store", h[CACHE_CONTROL]);
+        assert_eq!("accept, accept-language", h[VARY]);
+    }
+
+    #[test]
+    fn cache_freshness() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            Some(secs(60)),
+            freshness(&headers(CACHE_CONTROL, "max-age=60"))
+        );
+        assert_eq!(
+            Some(secs(10)),
+            freshness(&headers(CACHE_CONTROL, "max-age=60, s-maxage=10"))
+        );
+        assert_eq!(
+            None,
+            freshness(&headers(CACHE_CONTROL, "private, max-age=60"))
+        );
+        assert_eq!(None, freshness(&headers(CACHE_CONTROL, "max-age=0")));
+        assert_eq!(None, freshness(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn cache_vary() {
+        let h = headers(VARY, "Accept, accept-language");
+        let names = vary(&h).unwrap();
+        assert_eq!(vec!["accept", "accept-language"], names);
+        assert_eq!(None, vary(&headers(VARY, "*")));
+    }
+
+    #[test]
+    fn cache_not_modified() {
+        let etag = HeaderValue::from_static("W/\"a\"");
+        assert!(is_not_modified(&headers(IF_NONE_MATCH, "\"a\""), &etag));
+        assert!(is_not_modified(
+            &headers(IF_NONE_MATCH, "\"b\", W/\"a\""),
+            &etag
+        ));
+        assert!(is_not_modified(&headers(IF_NONE_MATCH, "*"), &etag));
+        assert!(!is_not_modified(&headers(IF_NONE_MATCH, "\"b\""), &etag));
+        assert!(!is_not_modified(&HeaderMap::new(), &etag));
+    }
+}
@@ -1,4 +1,7 @@
-use std::{fmt::Write, io::Read};
+use std::{
+    fmt::{self, Write},
+    io::Read,
+};
 
 use ariadne::{CharSet, ColorGenerator, Label, Report, ReportKind, Source};
 use lazy_regex::{lazy_regex, Regex};
@@ -8,7 +11,8 @@ use thiserror::Error;
 
 use crate::{Evaluation, Result};
 
-static LUA_ERROR_REGEX: Lazy<Regex> = lazy_regex!(r"\[[^\]]+\]:(\d+):(.+)");
+// the chunk name is empty when the evaluation is not named
+static LUA_ERROR_REGEX: Lazy<Regex> = lazy_regex!(r#"\[string "([^"]*)"\]:(\d+):(.+)"#);
 
 /// Custom error type for handling various error scenarios.
 #[derive(Debug, Error)]
@@ -16,12 +20,18 @@ pub enum Error {
     /// Error from the [`bat`] library
     #[error("bat error: {0}")]
     Bat(#[from] bat::error::Error),
+    /// Evaluation was cancelled by a [`crate::CancellationToken`]
+    #[error("evaluation cancelled")]
+    Cancelled,
     /// Error from the `SQLite` database
     #[error("sqlite error: {0}")]
     Database(#[from] rusqlite::Error),
     /// Error from database migration
     #[error("migration error: {0}")]
     DatabaseMigration(#[from] rusqlite_migration::Error),
+    /// Payload doesn't match the type to deserialize into
+    #[error("failed to deserialize the payload: {0}")]
+    Deserialize(serde_json::Error),
     /// Error in formatting output
     #[error("format error: {0}")]
     Format(#[from] std::fmt::Error),
@@ -31,9 +41,15 @@ pub enum Error {
     /// IO error
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    /// Error joining the task of an asynchronous evaluation
+    #[error("join error: {0}")]
+    Join(#[from] tokio::task::JoinError),
     /// Error from the Lua engine
     #[error("lua error: {0}")]
     Lua(#[from] LuaError),
+    /// Evaluation exceeded the memory limit in bytes
+    #[error("memory limit exceeded: {0} bytes")]
+    MemoryLimitExceeded(usize),
     /// Error decoding value from `MessagePack` format
     #[error("RMP decode error: {0}")]
     RMPDecode(#[from] rmp_serde::decode::Error),
@@ -45,7 +61,43 @@ pub enum Error {
     SerdeJSONError(#[from] serde_json::Error),
 }
 
+/// HTTP error raised by the script with `error(require('@lmb'):http_error(status, message))`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpError {
+    /// Status code, from 400 to 599
+    pub status: u16,
+    /// Message to the client
+    pub message: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
 impl Error {
+    /// Get the HTTP error raised by the script, which could be wrapped when raised in a callback.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    /// let script = "error(require('@lmb'):http_error(404, 'not found'))";
+    /// let e = EvaluationBuilder::new(script, empty()).build();
+    /// let err = e.evaluate().unwrap_err();
+    /// let http_error = err.http_error().unwrap();
+    /// assert_eq!(404, http_error.status);
+    /// assert_eq!("not found", http_error.message);
+    /// ```
+    pub fn http_error(&self) -> Option<&HttpError> {
+        match self {
+            Self::Lua(e) => lua_http_error(e),
+            _ => None,
+        }
+    }
+
     /// Render a Lua runtime or syntax error.
     pub fn write_lua_error<R, W>(&self, mut f: W, e: &Evaluation<R>, no_color: bool) -> Result<()>
     where
@@ -53,9 +105,10 @@ impl Error {
         W: Write,
     {
         let message = match self {
-            Self::Lua(LuaError::RuntimeError(message) | LuaError::SyntaxError { message, .. }) => {
-                message
-            }
+            Self::Lua(e) => match lua_error_message(e) {
+                Some(message) => message,
+                None => return Ok(()),
+            },
             _ => return Ok(()),
         };
 
@@ -64,8 +117,15 @@ impl Error {
             return Ok(write!(f, "{}", first_line)?);
         };
 
+        // the error could be raised in a module required by the script
+        let chunk_name = captures.get(1).map_or(e.name(), |n| n.as_str());
+        let (name, script) = match e.module_source(chunk_name) {
+            Some(source) if chunk_name != e.name() => (chunk_name, source),
+            _ => (e.name(), e.script().to_string()),
+        };
+
         let Some(line_number) = captures
-            .get(1)
+            .get(2)
             .and_then(|n| n.as_str().parse::<usize>().ok())
         else {
             return Ok(write!(f, "{}", first_line)?);
@@ -73,15 +133,15 @@ impl Error {
 
         let mut colors = ColorGenerator::new();
 
-        let source = Source::from(e.script());
+        let source = Source::from(script);
         let line = source
             .line(line_number - 1) // index, not line number
             .expect("cannot find line in source");
         let span = line.span();
 
-        let message = captures.get(2).map_or(first_line, |s| s.as_str().trim());
+        let message = captures.get(3).map_or(first_line, |s| s.as_str().trim());
         let mut buf = Vec::new();
-        Report::build(ReportKind::Error, e.name(), span.start)
+        Report::build(ReportKind::Error, name, span.start)
             .with_config(
                 ariadne::Config::default()
                     .with_char_set(CharSet::Ascii)
@@ -89,23 +149,41 @@ impl Error {
                     .with_color(!no_color),
             )
             .with_label(
-                Label::new((e.name(), span))
+                Label::new((name, span))
                     .with_color(colors.next())
                     .with_message(message),
             )
             .with_message(message)
             .finish()
-            .write((e.name(), source), &mut buf)?;
+            .write((name, source), &mut buf)?;
         write!(f, "{}", String::from_utf8_lossy(&buf))?;
         Ok(())
     }
 }
 
+/// Get the message of a Lua runtime or syntax error,
+/// which could be wrapped when raised in a callback e.g. loading a module.
+fn lua_error_message(e: &LuaError) -> Option<&String> {
+    match e {
+        LuaError::RuntimeError(message) | LuaError::SyntaxError { message, .. } => Some(message),
+        LuaError::CallbackError { cause, .. } => lua_error_message(cause),
+        _ => None,
+    }
+}
+
+fn lua_http_error(e: &LuaError) -> Option<&HttpError> {
+    match e {
+        LuaError::ExternalError(e) => e.downcast_ref(),
+        LuaError::CallbackError { cause, .. } => lua_http_error(cause),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::empty;
 
-    use crate::EvaluationBuilder;
+    use crate::{EvaluationBuilder, HttpError};
 
     #[test]
     fn write_error() {
@@ -117,5 +195,33 @@ mod tests {
         let mut buf = String::new();
         err.write_lua_error(&mut buf, &e, true).unwrap();
         assert!(buf.contains("attempt to perform arithmetic (add) on nil and number"));
+        // annotated with the source, even without a name
+        assert!(buf.contains(",-[:1:1]"), "{buf}");
+        assert!(buf.contains(" 1 |return nil+1"), "{buf}");
+    }
+
+    #[test]
+    fn http_error() {
+        let script = r#"
+        local m = require('@lmb')
+        local ok, err = pcall(function() error(m:http_error(403)) end)
+        assert(not ok)
+        error(err)
+        "#;
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let err = e.evaluate().unwrap_err();
+        let expected = HttpError {
+            status: 403,
+            message: "Forbidden".to_string(),
+        };
+        assert_eq!(Some(&expected), err.http_error());
+
+        let script = "require('@lmb'):http_error(200)";
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let err = e.evaluate().unwrap_err();
+        assert!(err.to_string().contains("invalid HTTP error status: 200"));
+
+        let e = EvaluationBuilder::new("error('a')", empty()).build();
+        assert!(e.evaluate().unwrap_err().http_error().is_none());
     }
 }
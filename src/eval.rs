@@ -7,22 +7,33 @@ use bat::{
 use chrono::Utc;
 use console::Term;
 use mlua::{prelude::*, Compiler};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::{
     fmt::{Display, Write},
-    io::{stdout, BufReader, IsTerminal as _, Read},
+    future::Future,
+    io::{self, stdout, BufReader, IsTerminal as _, Read},
+    path::PathBuf,
+    pin::pin,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
-    thread,
+    task::{Context as TaskContext, Poll, Wake, Waker},
+    thread::{self, Thread},
     time::{Duration, Instant},
 };
+use tokio::task;
 use tracing::{debug, error, trace_span, warn};
 
 use crate::{
-    Input, LuaBinding, PrintOptions, Result, ScheduleOptions, State, Store, DEFAULT_TIMEOUT,
+    metrics::{
+        DURATION_BUCKETS, EVALUATION_DURATION, EVALUATION_ERRORS, EVALUATION_MEMORY,
+        EVALUATION_TIMEOUTS, MEMORY_BUCKETS,
+    },
+    Context, Error, EvaluationPool, Input, LuaBinding, LuaModuleLoader, Metrics, Output,
+    PrintOptions, Result, ScheduleOptions, State, StateSlot, Store, WebSocket, DEFAULT_TIMEOUT,
 };
 
 /// Evaluation builder.
@@ -31,7 +42,11 @@ pub struct EvaluationBuilder<R>
 where
     R: Read,
 {
+    context: Arc<Context>,
     input: Arc<Mutex<BufReader<R>>>,
+    memory_limit: Option<usize>,
+    metrics: Metrics,
+    module_loader: Arc<LuaModuleLoader>,
     name: Option<String>,
     script: String,
     store: Option<Store>,
@@ -55,7 +70,11 @@ where
     {
         let input = Arc::new(Mutex::new(BufReader::new(input)));
         Self {
+            context: Arc::default(),
             input,
+            memory_limit: None,
+            metrics: Metrics::default(),
+            module_loader: Arc::default(),
             name: None,
             script: script.to_string(),
             store: None,
@@ -77,7 +96,11 @@ where
         S: Display,
     {
         Self {
+            context: Arc::default(),
             input,
+            memory_limit: None,
+            metrics: Metrics::default(),
+            module_loader: Arc::default(),
             name: None,
             script: script.to_string(),
             store: None,
@@ -85,6 +108,29 @@ where
         }
     }
 
+    /// Set command-line arguments and environment variables exposed to the script.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut context = Context::new();
+    /// context.set_args(vec!["a".to_string()]);
+    /// let e = EvaluationBuilder::new("return require('@lmb').args[1]", empty())
+    ///     .context(context)
+    ///     .build();
+    /// let res = e.evaluate()?;
+    /// assert_eq!(&json!("a"), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn context(&mut self, context: Context) -> &mut Self {
+        self.context = Arc::new(context);
+        self
+    }
+
     /// Attach an in-memory store.
     /// <div class="warning">Data will be lost after the program finishes.</div>
     ///
@@ -98,6 +144,46 @@ where
         self
     }
 
+    /// Set or unset memory limit in bytes. The evaluation is aborted with
+    /// [`crate::Error::MemoryLimitExceeded`] when the limit is exceeded.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    /// let _ = EvaluationBuilder::new("", empty()).memory_limit(Some(1024 * 1024));
+    /// ```
+    pub fn memory_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Set metrics to record evaluations, store operations, and HTTP requests of the script.
+    /// Metrics are labeled with the name of the script.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    /// let _ = EvaluationBuilder::new("", empty()).metrics(Metrics::new());
+    /// ```
+    pub fn metrics(&mut self, metrics: Metrics) -> &mut Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set directories to search for modules required by the script, e.g.
+    /// `require('./lib/util')` loads `lib/util.luau` or `lib/util.lua` under one of the directories.
+    /// Modules are compiled once and cached. Without directories, only built-in modules can be required.
+    ///
+    /// ```rust
+    /// # use std::{io::empty, path::PathBuf};
+    /// use lmb::*;
+    /// let _ = EvaluationBuilder::new("", empty()).module_paths(vec![PathBuf::from("lib")]);
+    /// ```
+    pub fn module_paths(&mut self, paths: Vec<PathBuf>) -> &mut Self {
+        self.module_loader = Arc::new(LuaModuleLoader::new(paths));
+        self
+    }
+
     /// Name the function for debugging and/or verbosity.
     ///
     /// ```rust
@@ -113,16 +199,17 @@ where
         self
     }
 
-    /// Attach a store to the function.
+    /// Attach a store, or a store backend, to the function.
     ///
     /// ```rust
     /// # use std::io::empty;
     /// use lmb::*;
     /// let store = Store::default();
     /// let _ = EvaluationBuilder::new("", empty()).store(store);
+    /// let _ = EvaluationBuilder::new("", empty()).store(MemoryBackend::new());
     /// ```
-    pub fn store(&mut self, store: Store) -> &mut Self {
-        self.store = Some(store);
+    pub fn store<S: Into<Store>>(&mut self, store: S) -> &mut Self {
+        self.store = Some(store.into());
         self
     }
 
@@ -159,25 +246,223 @@ where
     /// # }
     /// ```
     pub fn build(&self) -> Arc<Evaluation<R>> {
+        Arc::new(self.build_with_input(self.input.clone()))
+    }
+
+    fn build_with_input(&self, input: Input<R>) -> Evaluation<R> {
         let vm = Lua::new();
-        vm.sandbox(true).expect("failed to enable sandbox");
 
-        let compiled = {
-            let compiler = Compiler::new();
-            let _s = trace_span!("compile_script").entered();
-            compiler.compile(&self.script)
-        };
-        LuaBinding::register(&vm, self.input.clone(), self.store.clone(), None)
-            .expect("failed to initalize the binding");
-        Arc::new(Evaluation {
+        let compiled = compile(&self.script);
+        let name = self.name.clone().unwrap_or_default();
+        let metrics = self.metrics.with_label("script", &name);
+        let output = Output::default();
+        let state = StateSlot::default();
+        // registered before the sandbox is enabled, so the binding survives its reset
+        LuaBinding::register(
+            &vm,
+            input.clone(),
+            self.store.clone(),
+            state.clone(),
+            self.context.clone(),
+            output.clone(),
+            metrics.clone(),
+            self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+        )
+        .expect("failed to initialize the binding");
+        self.module_loader
+            .register(&vm)
+            .expect("failed to initialize the module loader");
+        vm.sandbox(true).expect("failed to enable sandbox");
+        Evaluation {
             compiled,
-            input: self.input.clone(),
-            name: self.name.clone().unwrap_or_default(),
+            context: self.context.clone(),
+            input,
+            memory_limit: self.memory_limit,
+            metrics,
+            module_loader: self.module_loader.clone(),
+            name,
+            output,
             script: self.script.clone(),
+            state,
             store: self.store.clone(),
             timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
-            vm,
-        })
+            vm: Arc::new(Mutex::new(vm)),
+        }
+    }
+}
+
+/// Interval to check the timeout and the cancellation while the script is pending.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drive the future on the current thread until it's ready or aborted by `abort`.
+fn block_on<F, T>(future: F, abort: impl Fn() -> Option<LuaError>) -> LuaResult<T>
+where
+    F: Future<Output = LuaResult<T>>,
+{
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = TaskContext::from_waker(&waker);
+    loop {
+        if let Poll::Ready(res) = future.as_mut().poll(&mut cx) {
+            return res;
+        }
+        if let Some(err) = abort() {
+            return Err(err);
+        }
+        thread::park_timeout(POLL_INTERVAL);
+    }
+}
+
+fn is_memory_error(err: &LuaError) -> bool {
+    match err {
+        LuaError::MemoryError(_) => true,
+        LuaError::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+fn compile(script: &str) -> Vec<u8> {
+    let compiler = Compiler::new();
+    let _s = trace_span!("compile_script").entered();
+    compiler.compile(script)
+}
+
+impl<R> EvaluationBuilder<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    /// Build a pool of [`Evaluation`]s sharing the same script and options.
+    /// Each evaluation in the pool has its own virtual machine and input,
+    /// so the input of the builder is not used.
+    ///
+    /// ```rust
+    /// # use std::io::Cursor;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let pool = EvaluationBuilder::new("return io.read('*a')", Cursor::new("")).build_pool(2);
+    /// let e = pool.get();
+    /// e.set_input(Cursor::new("1"));
+    /// let res = e.evaluate()?;
+    /// assert_eq!(&json!("1"), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build_pool(&self, size: usize) -> Arc<EvaluationPool<R>> {
+        EvaluationPool::new(self.clone(), size)
+    }
+
+    pub(crate) fn module_files(&self) -> Vec<PathBuf> {
+        self.module_loader.files()
+    }
+
+    pub(crate) fn build_pooled(&self) -> Evaluation<R> {
+        let input = Arc::new(Mutex::new(BufReader::new(R::default())));
+        self.build_with_input(input)
+    }
+}
+
+impl<R> Clone for EvaluationBuilder<R>
+where
+    R: Read,
+{
+    fn clone(&self) -> Self {
+        Self {
+            context: self.context.clone(),
+            input: self.input.clone(),
+            memory_limit: self.memory_limit,
+            metrics: self.metrics.clone(),
+            module_loader: self.module_loader.clone(),
+            name: self.name.clone(),
+            script: self.script.clone(),
+            store: self.store.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// Token to cancel running evaluations from another thread.
+///
+/// ```rust
+/// use lmb::*;
+/// let token = CancellationToken::new();
+/// assert!(!token.is_cancelled());
+/// token.clone().cancel();
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    condvar: Condvar,
+    mutex: Mutex<()>,
+}
+
+impl CancellationToken {
+    /// Create a new token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel evaluations using the token and wake up threads waiting on it.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Release);
+        let _guard = self.inner.mutex.lock();
+        self.inner.condvar.notify_all();
+    }
+
+    /// Check whether the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Block the current thread until the token is cancelled or the timeout elapses.
+    /// Return `true` when the token is cancelled.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use lmb::*;
+    /// let token = CancellationToken::new();
+    /// assert!(!token.wait_timeout(Duration::from_millis(1)));
+    /// token.cancel();
+    /// assert!(token.wait_timeout(Duration::from_secs(1)));
+    /// ```
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.inner.mutex.lock();
+        while !self.is_cancelled() {
+            if self
+                .inner
+                .condvar
+                .wait_until(&mut guard, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+        self.is_cancelled()
+    }
+}
+
+/// Cancel the token when dropped e.g. the future of an asynchronous evaluation is dropped.
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
     }
 }
 
@@ -187,10 +472,11 @@ pub struct Solution<R>
 where
     for<'lua> R: 'lua + Read,
 {
+    bytes: Vec<Option<Vec<u8>>>,
     duration: Duration,
     evaluation: Arc<Evaluation<R>>,
     max_memory_usage: usize,
-    payload: Value,
+    payloads: Vec<Value>,
 }
 
 impl<R> Solution<R>
@@ -212,24 +498,163 @@ where
         self.max_memory_usage
     }
 
-    /// Get evaluated payload.
+    /// Get evaluated payload, which is the first returned value.
+    /// [`serde_json::Value::Null`] is returned when nothing is returned.
     pub fn payload(&self) -> &Value {
-        &self.payload
+        static NULL: Value = Value::Null;
+        self.payloads.first().unwrap_or(&NULL)
+    }
+
+    /// Get the first returned value as bytes when it's a string.
+    /// Unlike [`Solution::payload`], where invalid UTF-8 sequences are replaced,
+    /// a binary string is kept as it is.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new(r#"return "\xff\x00""#, empty()).build();
+    /// let res = e.evaluate()?;
+    /// assert_eq!(Some(&[0xff, 0x00][..]), res.payload_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payload_bytes(&self) -> Option<&[u8]> {
+        self.payloads_bytes().next().flatten()
+    }
+
+    /// Get all returned values as bytes, in the same positions as [`Solution::payloads`].
+    /// A value which is not a string is [`None`].
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new(r#"return 1, "\xff""#, empty()).build();
+    /// let res = e.evaluate()?;
+    /// let bytes = res.payloads_bytes().collect::<Vec<_>>();
+    /// assert_eq!(vec![None, Some(&[0xff][..])], bytes);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payloads_bytes(&self) -> impl Iterator<Item = Option<&[u8]>> {
+        self.bytes.iter().map(Option::as_deref)
+    }
+
+    /// Get all returned values.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new("return 1, 'a'", empty()).build();
+    /// let res = e.evaluate()?;
+    /// assert_eq!(&[json!(1), json!("a")], res.payloads());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payloads(&self) -> &[Value] {
+        &self.payloads
+    }
+
+    /// Deserialize the payload into a type. [`crate::Error::Deserialize`] is returned
+    /// when the payload doesn't match the type.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde::Deserialize;
+    /// use lmb::*;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Point {
+    ///     x: i32,
+    ///     y: i32,
+    /// }
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new("return { x = 1, y = 2 }", empty()).build();
+    /// let point: Point = e.evaluate()?.deserialize()?;
+    /// assert_eq!((1, 2), (point.x, point.y));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn deserialize<T>(&self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        T::deserialize(self.payload()).map_err(Error::Deserialize)
     }
 
-    /// Render the solution.
+    /// Render the solution. Each returned value is rendered on its own line,
+    /// and `null` is rendered when nothing is returned.
     pub fn write<W>(&self, mut f: W, json: bool) -> Result<()>
     where
         W: Write,
     {
-        if json {
-            let res = serde_json::to_string(&self.payload)?;
-            Ok(write!(f, "{}", res)?)
-        } else {
-            match &self.payload {
-                Value::String(s) => Ok(write!(f, "{}", s)?),
-                _ => Ok(write!(f, "{}", self.payload)?),
+        for (idx, payload) in self.rendered_payloads().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
             }
+            write_value(&mut f, payload, json)?;
+        }
+        Ok(())
+    }
+
+    /// Render the payload, which is the first returned value, on a single line,
+    /// e.g. one line per record in batch mode.
+    pub fn write_payload<W>(&self, f: W, json: bool) -> Result<()>
+    where
+        W: Write,
+    {
+        write_value(f, self.payload(), json)
+    }
+
+    /// Render the solution like [`Solution::write`], except binary strings are written
+    /// as they are when the solution is not rendered as JSON.
+    pub fn write_bytes<W>(&self, mut f: W, json: bool) -> Result<()>
+    where
+        W: io::Write,
+    {
+        let bytes = self.payloads_bytes().chain(std::iter::repeat(None));
+        for (idx, (payload, bytes)) in self.rendered_payloads().zip(bytes).enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            if let (false, Some(bytes)) = (json, bytes) {
+                f.write_all(bytes)?;
+            } else {
+                let mut buf = String::new();
+                write_value(&mut buf, payload, json)?;
+                f.write_all(buf.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rendered_payloads(&self) -> impl Iterator<Item = &Value> {
+        static NULLS: [Value; 1] = [Value::Null];
+        match self.payloads.as_slice() {
+            [] => NULLS.iter(),
+            payloads => payloads.iter(),
+        }
+    }
+}
+
+fn write_value<W>(mut f: W, payload: &Value, json: bool) -> Result<()>
+where
+    W: Write,
+{
+    if json {
+        let res = serde_json::to_string(payload)?;
+        Ok(write!(f, "{}", res)?)
+    } else {
+        match payload {
+            Value::String(s) => Ok(write!(f, "{}", s)?),
+            _ => Ok(write!(f, "{}", payload)?),
         }
     }
 }
@@ -241,12 +666,19 @@ where
     for<'lua> R: 'lua + Read,
 {
     compiled: Vec<u8>,
+    context: Arc<Context>,
     input: Input<R>,
+    memory_limit: Option<usize>,
+    metrics: Metrics,
+    module_loader: Arc<LuaModuleLoader>,
     name: String,
+    output: Output,
     script: String,
+    state: StateSlot,
     store: Option<Store>,
     timeout: Duration,
-    vm: Lua,
+    // The virtual machine is not thread-safe, so evaluations on the same VM are serialized.
+    vm: Arc<Mutex<Lua>>,
 }
 
 impl<R> Evaluation<R>
@@ -268,7 +700,7 @@ where
     /// # }
     /// ```
     pub fn evaluate(self: &Arc<Self>) -> Result<Solution<R>> {
-        self.do_evaluate(None)
+        self.do_evaluate(None, None)
     }
 
     /// Evaluate the function with a state.
@@ -288,7 +720,127 @@ where
     /// # }
     /// ```
     pub fn evaluate_with_state(self: &Arc<Self>, state: Arc<State>) -> Result<Solution<R>> {
-        self.do_evaluate(Some(state))
+        self.do_evaluate(Some(state), None)
+    }
+
+    /// Evaluate the function with an optional state. The evaluation is aborted
+    /// with [`crate::Error::Cancelled`] once the token is cancelled.
+    ///
+    /// ```rust
+    /// # use std::{io::empty, thread, time::Duration};
+    /// use lmb::*;
+    ///
+    /// let e = EvaluationBuilder::new("while true do end", empty()).build();
+    /// let token = CancellationToken::new();
+    /// thread::spawn({
+    ///     let token = token.clone();
+    ///     move || {
+    ///         thread::sleep(Duration::from_millis(10));
+    ///         token.cancel();
+    ///     }
+    /// });
+    /// let res = e.evaluate_with_token(None, &token);
+    /// assert!(matches!(res, Err(Error::Cancelled)));
+    /// ```
+    pub fn evaluate_with_token(
+        self: &Arc<Self>,
+        state: Option<Arc<State>>,
+        token: &CancellationToken,
+    ) -> Result<Solution<R>> {
+        self.do_evaluate(state, Some(token.clone()))
+    }
+
+    /// Evaluate the function with an optional state on the blocking thread pool of [`tokio`],
+    /// so the asynchronous runtime can keep serving other tasks.
+    /// The evaluation is cancelled when the returned future is dropped.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new("return 1+1", empty()).build();
+    /// let res = e.evaluate_async(None).await?;
+    /// assert_eq!(&json!(2), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn evaluate_async(
+        self: &Arc<Self>,
+        state: Option<Arc<State>>,
+    ) -> Result<Solution<R>> {
+        self.evaluate_async_holding(state, ()).await
+    }
+
+    /// Evaluate on the blocking thread pool like [`Evaluation::evaluate_async`],
+    /// and drop the held value there after the evaluation ends, even when cancelled.
+    pub(crate) async fn evaluate_async_holding<T>(
+        self: &Arc<Self>,
+        state: Option<Arc<State>>,
+        held: T,
+    ) -> Result<Solution<R>>
+    where
+        T: Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let _guard = CancelOnDrop(token.clone());
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let res = this.do_evaluate(state, Some(token));
+            drop(held);
+            res
+        })
+        .await?
+    }
+
+    /// Create an evaluation of another script on the same virtual machine,
+    /// sharing globals, input, and store, e.g. to evaluate statements one by one in a REPL.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = EvaluationBuilder::new("x = 1", empty()).build();
+    /// e.evaluate()?;
+    /// let res = e.with_script("return x + 1").evaluate()?;
+    /// assert_eq!(&json!(2), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_script<S>(&self, script: S) -> Arc<Self>
+    where
+        S: Display,
+    {
+        let script = script.to_string();
+        Arc::new(Self {
+            compiled: compile(&script),
+            context: self.context.clone(),
+            input: self.input.clone(),
+            memory_limit: self.memory_limit,
+            metrics: self.metrics.clone(),
+            module_loader: self.module_loader.clone(),
+            name: self.name.clone(),
+            output: self.output.clone(),
+            script,
+            state: self.state.clone(),
+            store: self.store.clone(),
+            timeout: self.timeout,
+            vm: self.vm.clone(),
+        })
+    }
+
+    /// Get paths of modules required by the script so far, e.g. to watch them for changes.
+    pub fn module_files(&self) -> Vec<PathBuf> {
+        self.module_loader.files()
+    }
+
+    /// Get source of a module required by the script.
+    pub(crate) fn module_source(&self, name: &str) -> Option<String> {
+        self.module_loader.source(name)
     }
 
     /// Get name.
@@ -299,6 +851,7 @@ where
     /// Schedule the script.
     pub fn schedule(self: Arc<Self>, options: &ScheduleOptions) {
         let bail = options.bail();
+        let token = options.cancellation_token();
         debug!(bail, "script scheduled");
         let mut error_count = 0usize;
         loop {
@@ -306,8 +859,16 @@ where
             if let Some(next) = options.schedule().upcoming(Utc).take(1).next() {
                 debug!(%next, "next run");
                 let elapsed = next - now;
-                thread::sleep(elapsed.to_std().expect("failed to fetch next schedule"));
-                if let Err(err) = self.evaluate() {
+                if token.wait_timeout(elapsed.to_std().expect("failed to fetch next schedule")) {
+                    debug!("schedule cancelled");
+                    break;
+                }
+                let res = self.evaluate_with_token(None, token);
+                if token.is_cancelled() {
+                    debug!("schedule cancelled");
+                    break;
+                }
+                if let Err(err) = res {
                     warn!(?err, "failed to evaluate");
                     if bail > 0 {
                         debug!(bail, error_count, "check bail threshold");
@@ -322,11 +883,27 @@ where
         }
     }
 
+    /// Reset the virtual machine, so the evaluation can be reused without
+    /// leaking globals or request state from the previous run.
+    pub(crate) fn reset(&self) -> Result<()> {
+        self.state.set(None);
+        let vm = self.vm.lock();
+        vm.sandbox(false)?;
+        vm.sandbox(true)?;
+        LuaModuleLoader::unload(&vm)?;
+        Ok(())
+    }
+
     /// Get script.
     pub fn script(&self) -> &str {
         &self.script
     }
 
+    /// Get execution timeout.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
     /// Replace the function input after the container is built.
     ///
     /// ```rust
@@ -348,10 +925,34 @@ where
     /// # Ok(())
     /// # }
     /// ```
-    pub fn set_input(self: &Arc<Self>, input: R) {
+    pub fn set_input(&self, input: R) {
         *self.input.lock() = BufReader::new(input);
     }
 
+    /// Set or unset the output, where the script streams the response body
+    /// with `response:write(chunk)`.
+    ///
+    /// ```rust
+    /// # use std::{io::empty, sync::Arc};
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let script = "require('@lmb').response:write('hello')";
+    /// let e = EvaluationBuilder::new(script, empty()).build();
+    /// e.set_output(Some(Box::new(Vec::new())));
+    /// e.evaluate_with_state(Arc::new(State::new()))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_output(&self, output: Option<Box<dyn io::Write + Send>>) {
+        self.output.set_writer(output);
+    }
+
+    /// Set or unset the WebSocket connection exposed to the script as `require('@lmb').websocket`.
+    pub fn set_websocket(&self, websocket: Option<WebSocket>) {
+        self.output.set_websocket(websocket);
+    }
+
     /// Render the script.
     ///
     /// ```rust
@@ -397,25 +998,35 @@ where
         Ok(controller.run(inputs, Some(&mut f))?)
     }
 
-    fn do_evaluate(self: &Arc<Self>, state: Option<Arc<State>>) -> Result<Solution<R>> {
-        let vm = &self.vm;
-        if state.is_some() {
-            LuaBinding::register(vm, self.input.clone(), self.store.clone(), state)?;
-        }
+    fn do_evaluate(
+        self: &Arc<Self>,
+        state: Option<Arc<State>>,
+        token: Option<CancellationToken>,
+    ) -> Result<Solution<R>> {
+        let vm = self.vm.lock();
+        self.state.set(state);
 
         let max_memory = Arc::new(AtomicUsize::new(0));
+        let timed_out = Arc::new(AtomicBool::new(false));
         let timeout = self.timeout;
 
         let start = Instant::now();
-        self.vm.set_interrupt({
+        vm.set_interrupt({
             let max_memory = Arc::clone(&max_memory);
+            let timed_out = Arc::clone(&timed_out);
+            let token = token.clone();
             move |vm| {
                 let used_memory = vm.used_memory();
                 max_memory.fetch_max(used_memory, Ordering::Relaxed);
                 if start.elapsed() > timeout {
+                    timed_out.store(true, Ordering::Release);
                     vm.remove_interrupt();
                     return Err(mlua::Error::runtime("timeout"));
                 }
+                if token.as_ref().is_some_and(|t| t.is_cancelled()) {
+                    vm.remove_interrupt();
+                    return Err(mlua::Error::runtime("cancelled"));
+                }
                 Ok(LuaVmState::Continue)
             }
         });
@@ -423,17 +1034,74 @@ where
         let script_name = &self.name;
         let chunk = vm.load(&self.compiled).set_name(script_name);
 
+        // the limit only applies to the script, so registering the binding never fails on it
+        if let Some(limit) = self.memory_limit {
+            vm.set_memory_limit(limit)?;
+        }
         let _s = trace_span!("evaluate").entered();
-        let result = vm.from_value(chunk.eval()?)?;
-
+        // the interrupt is not called while the script awaits e.g. an HTTP request
+        let res = block_on(chunk.eval_async::<LuaMultiValue<'_>>(), || {
+            if start.elapsed() > timeout {
+                timed_out.store(true, Ordering::Release);
+                return Some(mlua::Error::runtime("timeout"));
+            }
+            if token.as_ref().is_some_and(|t| t.is_cancelled()) {
+                return Some(mlua::Error::runtime("cancelled"));
+            }
+            None
+        });
+        if self.memory_limit.is_some() {
+            vm.set_memory_limit(0)?;
+        }
         let duration = start.elapsed();
         let max_memory = max_memory.load(Ordering::Acquire);
+        let metrics = &self.metrics;
+        metrics.observe(
+            EVALUATION_DURATION,
+            DURATION_BUCKETS,
+            duration.as_secs_f64(),
+        );
+        metrics.observe(EVALUATION_MEMORY, MEMORY_BUCKETS, max_memory as f64);
+        if res.is_err() && !token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            metrics.increment(EVALUATION_ERRORS, &[], 1.0);
+            if timed_out.load(Ordering::Acquire) {
+                metrics.increment(EVALUATION_TIMEOUTS, &[], 1.0);
+            }
+        }
+        let values = match (res, self.memory_limit) {
+            (Err(_), _) if token.as_ref().is_some_and(|t| t.is_cancelled()) => {
+                return Err(Error::Cancelled)
+            }
+            (Err(err), Some(limit)) if is_memory_error(&err) => {
+                return Err(Error::MemoryLimitExceeded(limit))
+            }
+            (res, _) => res?,
+        };
+        let bytes = values
+            .iter()
+            .map(|v| match v {
+                LuaValue::String(s) => Some(s.as_bytes().to_vec()),
+                _ => None,
+            })
+            .collect();
+        let payloads = values
+            .into_iter()
+            .map(|v| match v {
+                // the raw bytes are kept, see `Solution::payload_bytes`
+                LuaValue::String(s) if s.to_str().is_err() => {
+                    Ok(Value::String(s.to_string_lossy().into_owned()))
+                }
+                v => vm.from_value(v),
+            })
+            .collect::<LuaResult<Vec<Value>>>()?;
+
         debug!(?duration, %script_name, ?max_memory, "script evaluated");
         Ok(Solution {
+            bytes,
             duration,
             evaluation: self.clone(),
             max_memory_usage: max_memory,
-            payload: result,
+            payloads,
         })
     }
 }
@@ -446,11 +1114,13 @@ mod tests {
         fs,
         io::{empty, BufReader},
         sync::Arc,
+        thread,
         time::{Duration, Instant},
     };
     use test_case::test_case;
 
-    use crate::{EvaluationBuilder, State, StateKey};
+    use super::is_memory_error;
+    use crate::{CancellationToken, Error, EvaluationBuilder, Metrics, State, StateKey, Store};
 
     #[test_case("./lua-examples/error.lua")]
     fn error_in_script(path: &str) {
@@ -472,7 +1142,7 @@ mod tests {
             .default_store()
             .build();
         let res = e.evaluate().unwrap();
-        assert_eq!(expected, res.payload);
+        assert_eq!(&expected, res.payload());
     }
 
     #[test]
@@ -490,13 +1160,166 @@ mod tests {
         assert!(elapsed < 500, "actual elapsed {elapsed:?}"); // 500% error
     }
 
+    #[test]
+    fn evaluate_metrics() {
+        let metrics = Metrics::new();
+        let e = EvaluationBuilder::new(r#"while true do end"#, empty())
+            .metrics(metrics.clone())
+            .name("a")
+            .timeout(Some(Duration::from_millis(10)))
+            .build();
+        assert!(e.evaluate().is_err());
+        let rendered = metrics.render();
+        assert!(rendered.contains("lmb_evaluation_errors_total{script=\"a\"} 1\n"));
+        assert!(rendered.contains("lmb_evaluation_timeouts_total{script=\"a\"} 1\n"));
+        assert!(rendered.contains("lmb_evaluation_duration_seconds_count{script=\"a\"} 1\n"));
+        assert!(rendered.contains("lmb_evaluation_memory_bytes_count{script=\"a\"} 1\n"));
+    }
+
+    #[test]
+    fn evaluate_cancelled() {
+        let timer = Instant::now();
+        let e = EvaluationBuilder::new(r#"while true do end"#, empty()).build();
+        let token = CancellationToken::new();
+        let t = thread::spawn({
+            let token = token.clone();
+            move || {
+                thread::sleep(Duration::from_millis(100));
+                token.cancel();
+            }
+        });
+        let res = e.evaluate_with_token(None, &token);
+        assert!(matches!(res, Err(Error::Cancelled)));
+        t.join().unwrap();
+
+        let elapsed = timer.elapsed().as_millis();
+        assert!(elapsed < 500, "actual elapsed {elapsed:?}"); // 500% error
+    }
+
+    #[tokio::test]
+    async fn evaluate_async_cancel_on_drop() {
+        let script = r#"
+        local m = require('@lmb')
+        if m:get('started') then
+          return true
+        end
+        m:put('started', true)
+        while true do end
+        "#;
+        let timer = Instant::now();
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty())
+            .store(store.clone())
+            .build();
+        {
+            let mut f = Box::pin(e.evaluate_async(None));
+            tokio::select! {
+                biased;
+                _ = &mut f => panic!("expect evaluation to be running"),
+                () = std::future::ready(()) => {}
+            }
+            while store.get("started").unwrap() != json!(true) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        // the virtual machine is released once the evaluation is cancelled
+        let e = Arc::clone(&e);
+        let res = tokio::task::spawn_blocking(move || e.evaluate().map(|s| s.payload().clone()))
+            .await
+            .unwrap();
+        assert_eq!(json!(true), res.unwrap());
+
+        let elapsed = timer.elapsed().as_millis();
+        assert!(elapsed < 500, "actual elapsed {elapsed:?}"); // 500% error
+    }
+
+    #[test]
+    fn evaluate_memory_limit() {
+        let script = "local t = {}; for i = 1, 1e7 do t[i] = i end; return #t";
+        let e = EvaluationBuilder::new(script, empty())
+            .memory_limit(Some(1024 * 1024))
+            .build();
+        let res = e.evaluate();
+        assert!(matches!(res, Err(Error::MemoryLimitExceeded(_))));
+
+        let e = EvaluationBuilder::new("return 1", empty())
+            .memory_limit(Some(1024 * 1024))
+            .build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!(1), res.payload());
+
+        // the limit is lower than the memory used by the binding
+        let e = EvaluationBuilder::new(script, empty())
+            .memory_limit(Some(200_000))
+            .build();
+        let res = e.evaluate();
+        assert!(matches!(res, Err(Error::MemoryLimitExceeded(200_000))));
+    }
+
+    #[test]
+    fn evaluate_memory_error_in_callback() {
+        let err = mlua::Error::CallbackError {
+            traceback: String::new(),
+            cause: Arc::new(mlua::Error::MemoryError("not enough memory".to_string())),
+        };
+        assert!(is_memory_error(&err));
+        assert!(!is_memory_error(&mlua::Error::runtime("error")));
+    }
+
     #[test_case("return 1+1", json!(2))]
     #[test_case("return 'a'..1", json!("a1"))]
     #[test_case("return require('@lmb')._VERSION", json!(env!("APP_VERSION")))]
     fn evaluate_scripts(script: &str, expected: Value) {
         let e = EvaluationBuilder::new(script, empty()).build();
         let res = e.evaluate().unwrap();
-        assert_eq!(expected, res.payload);
+        assert_eq!(&expected, res.payload());
+    }
+
+    #[test]
+    fn evaluate_binary_string() {
+        let e = EvaluationBuilder::new(r#"return "\xff\xfe""#, empty()).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(Some(&[0xff, 0xfe][..]), res.payload_bytes());
+        assert_eq!(&json!("\u{fffd}\u{fffd}"), res.payload());
+
+        let e = EvaluationBuilder::new("return 1", empty()).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(None, res.payload_bytes());
+    }
+
+    #[test]
+    fn evaluate_deserialize() {
+        let e = EvaluationBuilder::new("return { 1, 2 }", empty()).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(vec![1, 2], res.deserialize::<Vec<i32>>().unwrap());
+        assert!(matches!(
+            res.deserialize::<String>(),
+            Err(Error::Deserialize(_))
+        ));
+    }
+
+    #[test_case("return", vec![])]
+    #[test_case("return 1", vec![json!(1)])]
+    #[test_case("return 1, 'a', nil, true", vec![json!(1), json!("a"), json!(null), json!(true)])]
+    fn evaluate_multiple_values(script: &str, expected: Vec<Value>) {
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(expected, res.payloads());
+    }
+
+    #[test]
+    fn evaluate_with_script() {
+        let store = Store::default();
+        let e = EvaluationBuilder::new("x = 1", empty())
+            .store(store.clone())
+            .build();
+        e.evaluate().unwrap();
+
+        let script = "require('@lmb'):put('x', x + 1); return x + 1";
+        let res = e.with_script(script).evaluate().unwrap();
+        assert_eq!(&json!(2), res.payload());
+        assert_eq!(json!(2), store.get("x").unwrap());
     }
 
     #[test]
@@ -506,10 +1329,10 @@ mod tests {
         let e = EvaluationBuilder::new(script, input.as_bytes()).build();
 
         let res = e.evaluate().unwrap();
-        assert_eq!(json!("foo"), res.payload);
+        assert_eq!(&json!("foo"), res.payload());
 
         let res = e.evaluate().unwrap();
-        assert_eq!(json!("bar"), res.payload);
+        assert_eq!(&json!("bar"), res.payload());
     }
 
     #[test]
@@ -518,12 +1341,12 @@ mod tests {
         let e = EvaluationBuilder::new(script, &b"0"[..]).build();
 
         let res = e.evaluate().unwrap();
-        assert_eq!(json!("0"), res.payload);
+        assert_eq!(&json!("0"), res.payload());
 
         e.set_input(&b"1"[..]);
 
         let res = e.evaluate().unwrap();
-        assert_eq!(json!("1"), res.payload);
+        assert_eq!(&json!("1"), res.payload());
     }
 
     #[test]
@@ -538,7 +1361,7 @@ mod tests {
         let input = Arc::new(Mutex::new(BufReader::new(empty())));
         let e = EvaluationBuilder::with_reader("return nil", input.clone()).build();
         let res = e.evaluate().unwrap();
-        assert_eq!(json!(null), res.payload);
+        assert_eq!(&json!(null), res.payload());
         let _input = input;
     }
 
@@ -549,12 +1372,12 @@ mod tests {
         state.insert(StateKey::Request, 1.into());
         {
             let res = e.evaluate_with_state(state.clone()).unwrap();
-            assert_eq!(json!(1), res.payload);
+            assert_eq!(&json!(1), res.payload());
         }
         state.insert(StateKey::Request, 2.into());
         {
             let res = e.evaluate_with_state(state.clone()).unwrap();
-            assert_eq!(json!(2), res.payload);
+            assert_eq!(&json!(2), res.payload());
         }
     }
 
@@ -567,4 +1390,34 @@ mod tests {
         solution.write(&mut buf, false).unwrap();
         assert_eq!("2", buf);
     }
+
+    #[test_case("return", false, "null")]
+    #[test_case("return 1, 'a', nil", false, "1\na\nnull")]
+    #[test_case("return 1, 'a', nil", true, "1\n\"a\"\nnull")]
+    fn write_multiple_values(script: &str, json: bool, expected: &str) {
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let solution = e.evaluate().unwrap();
+        let mut buf = String::new();
+        solution.write(&mut buf, json).unwrap();
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn write_payload() {
+        let e = EvaluationBuilder::new("return 1, 'a'", empty()).build();
+        let solution = e.evaluate().unwrap();
+        let mut buf = String::new();
+        solution.write_payload(&mut buf, false).unwrap();
+        assert_eq!("1", buf);
+    }
+
+    #[test]
+    fn write_binary_values() {
+        let script = r#"return "\xff", 1, "\xfe""#;
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let solution = e.evaluate().unwrap();
+        let mut buf = vec![];
+        solution.write_bytes(&mut buf, false).unwrap();
+        assert_eq!(b"\xff\n1\n\xfe", buf.as_slice());
+    }
 }
@@ -7,7 +7,10 @@ use include_dir::{include_dir, Dir};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use rusqlite_migration::Migrations;
-use std::{fmt::Display, io::BufReader, result::Result as StdResult, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap, fmt::Display, io::BufReader, result::Result as StdResult, sync::Arc,
+    time::Duration,
+};
 
 pub use check::*;
 pub use error::*;
@@ -15,6 +18,8 @@ pub use eval::*;
 pub use example::*;
 pub use guide::*;
 pub use lua_binding::*;
+pub use metrics::Metrics;
+pub use pool::*;
 pub use schedule::*;
 pub use store::*;
 
@@ -24,9 +29,14 @@ mod eval;
 mod example;
 mod guide;
 mod lua_binding;
+mod metrics;
+mod pool;
 mod schedule;
 mod store;
 
+/// Default number of evaluations in a pool.
+pub const DEFAULT_POOL_SIZE: usize = 8;
+
 /// Default timeout for evaluation in seconds.
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
@@ -48,6 +58,10 @@ pub type Result<T> = StdResult<T, Error>;
 /// Enum representing different state keys.
 #[derive(Debug, Eq, Hash, PartialEq)]
 pub enum StateKey {
+    /// Error passed to the error handler of HTTP requests
+    Error,
+    /// Record of batch evaluation, e.g. a line or a parsed JSON value
+    Record,
     /// HTTP request object
     Request,
     /// HTTP response object
@@ -69,6 +83,43 @@ where
 /// State of each evaluation, using a [`dashmap::DashMap`].
 pub type State = DashMap<StateKey, serde_json::Value>;
 
+/// Command-line arguments and environment variables exposed to the script
+/// as `require('@lmb').args` and `require('@lmb').env`.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    args: Vec<String>,
+    env: HashMap<String, String>,
+}
+
+impl Context {
+    /// Create an empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get arguments.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Get environment variables.
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    /// Set arguments.
+    pub fn set_args(&mut self, args: Vec<String>) -> &mut Self {
+        self.args = args;
+        self
+    }
+
+    /// Set environment variables. Only these variables are visible to the script.
+    pub fn set_env(&mut self, env: HashMap<String, String>) -> &mut Self {
+        self.env = env;
+        self
+    }
+}
+
 /// Options for printing scripts.
 #[derive(Debug, Default)]
 pub struct PrintOptions {
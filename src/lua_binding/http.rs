@@ -2,21 +2,38 @@ use std::{
     collections::HashMap,
     io::{BufReader, Cursor, Read},
     sync::Arc,
+    time::Duration,
 };
 
 use http::{Method, StatusCode};
 use mlua::prelude::*;
 use parking_lot::Mutex;
 use serde_json::Value;
+use tokio::runtime::Handle;
 use tracing::{trace, trace_span, warn};
-use ureq::Request;
+use ureq::{Agent, AgentBuilder, Request};
 use url::Url;
 
 use super::{lua_lmb_read, lua_lmb_read_unicode};
-use crate::Input;
+use crate::{metrics::HTTP_REQUESTS, Input, Metrics};
 
 /// HTTP module
-pub struct LuaModHTTP {}
+pub struct LuaModHTTP {
+    agent: Agent,
+    metrics: Metrics,
+}
+
+impl LuaModHTTP {
+    /// Create the module. Requests time out after the timeout, so a server that never answers
+    /// doesn't hold the blocking thread after the evaluation is cancelled or timed out.
+    pub fn new(metrics: Metrics, timeout: Duration) -> Self {
+        let agent = AgentBuilder::new()
+            .timeout_connect(timeout)
+            .timeout(timeout)
+            .build();
+        Self { agent, metrics }
+    }
+}
 
 /// HTTP response
 pub struct LuaModHTTPResponse {
@@ -70,10 +87,29 @@ fn set_headers(req: Request, headers: &Value) -> Request {
     new_req
 }
 
-fn lua_lmb_fetch(
-    vm: &Lua,
-    _: &LuaModHTTP,
-    (uri, options): (String, Option<LuaTable<'_>>),
+fn send_request(
+    agent: &Agent,
+    method: &Method,
+    url: &Url,
+    headers: &Value,
+    body: Option<String>,
+) -> LuaResult<ureq::Response> {
+    let req = agent.request_url(method.as_str(), url);
+    let req = set_headers(req, headers);
+    let res = match body {
+        Some(body) => req.send(Cursor::new(body)),
+        None => req.call(),
+    };
+    match res {
+        Ok(res) | Err(ureq::Error::Status(_, res)) => Ok(res),
+        Err(e) => Err(e.into_lua_err()),
+    }
+}
+
+async fn lua_lmb_fetch<'lua>(
+    vm: &'lua Lua,
+    this: &LuaModHTTP,
+    (uri, options): (String, Option<LuaTable<'lua>>),
 ) -> LuaResult<LuaModHTTPResponse> {
     let options = options.as_ref();
     let url: Url = uri.parse().into_lua_err()?;
@@ -85,23 +121,40 @@ fn lua_lmb_fetch(
         .and_then(|t| t.get("headers").ok())
         .and_then(|m| vm.from_value(m).ok())
         .unwrap_or(Value::Null);
-    let _s = trace_span!("send_http_request", %method, %url, ?headers).entered();
-    let res = if method.is_safe() {
-        let req = ureq::request_url(method.as_str(), &url);
-        let req = set_headers(req, &headers);
-        req.call()
+    let body: Option<String> = if method.is_safe() {
+        None
     } else {
-        let body: String = options
-            .map(|t| t.get("body").unwrap_or_default())
-            .unwrap_or_default();
-        let req = ureq::request_url(method.as_str(), &url);
-        let req = set_headers(req, &headers);
-        req.send(Cursor::new(body))
+        Some(
+            options
+                .map(|t| t.get("body").unwrap_or_default())
+                .unwrap_or_default(),
+        )
     };
-    let res = match res {
-        Ok(res) | Err(ureq::Error::Status(_, res)) => res,
-        Err(e) => return Err(e.into_lua_err()),
+    let span = trace_span!("send_http_request", %method, %url, ?headers);
+    // the request is sent on the blocking thread pool, so the evaluation can be
+    // cancelled or timed out while waiting for the response
+    let res = if let Ok(handle) = Handle::try_current() {
+        let agent = this.agent.clone();
+        let method = method.clone();
+        handle
+            .spawn_blocking(move || {
+                let _s = span.entered();
+                send_request(&agent, &method, &url, &headers, body)
+            })
+            .await
+            .into_lua_err()?
+    } else {
+        let _s = span.entered();
+        send_request(&this.agent, &method, &url, &headers, body)
     };
+    let res = res.map_err(|e| {
+        let labels = [("method", method.as_str()), ("status", "error")];
+        this.metrics.increment(HTTP_REQUESTS, &labels, 1.0);
+        e
+    })?;
+    let status = res.status().to_string();
+    let labels = [("method", method.as_str()), ("status", status.as_str())];
+    this.metrics.increment(HTTP_REQUESTS, &labels, 1.0);
     let charset = res.charset().to_string();
     let content_type = res.content_type().to_string();
     let headers = {
@@ -130,18 +183,23 @@ fn lua_lmb_fetch(
 
 impl LuaUserData for LuaModHTTP {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("fetch", lua_lmb_fetch);
+        methods.add_async_method("fetch", lua_lmb_fetch);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::empty;
+    use std::{
+        io::empty,
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
 
     use mockito::Server;
     use serde_json::json;
 
-    use crate::EvaluationBuilder;
+    use crate::{Error, EvaluationBuilder};
 
     #[test]
     fn http_get() {
@@ -276,4 +334,57 @@ mod tests {
 
         post_mock.assert();
     }
+
+    #[tokio::test]
+    async fn http_cancel_pending() {
+        let mut server = Server::new_async().await;
+
+        let get_mock = server
+            .mock("GET", "/slow")
+            .with_body_from_request(|_| {
+                thread::sleep(Duration::from_secs(1));
+                b"a".to_vec()
+            })
+            .create_async()
+            .await;
+
+        let url = server.url();
+        let script = format!(
+            r#"
+            local m = require('@lmb/http')
+            local res = m:fetch('{url}/slow')
+            return res:read('*a')
+            "#
+        );
+        let e = EvaluationBuilder::new(script, empty())
+            .timeout(Some(Duration::from_millis(100)))
+            .build();
+        let start = Instant::now();
+        let res = e.evaluate_async(None).await;
+        assert!(matches!(res, Err(Error::Lua(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        get_mock.assert_async().await;
+    }
+
+    #[test]
+    fn http_request_timeout() {
+        // the connection is accepted by the backlog, but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = format!(
+            r#"
+            local m = require('@lmb/http')
+            return m:fetch('http://{addr}/')
+            "#
+        );
+        let e = EvaluationBuilder::new(script, empty())
+            .timeout(Some(Duration::from_millis(100)))
+            .build();
+        let start = Instant::now();
+        let res = e.evaluate();
+        assert!(matches!(res, Err(Error::Lua(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(listener);
+    }
 }
@@ -1,34 +1,112 @@
+use lazy_regex::regex_is_match;
 use mlua::prelude::*;
+use parking_lot::Mutex;
 use serde_json::Value;
 use std::{
+    collections::HashMap,
     io::{stderr, stdout, Read, Write as _},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use crate::{Input, Result, State, StateKey, Store};
+use crate::{
+    metrics::{RESERVED_PREFIX, STORE_OPERATIONS},
+    Context, Error, HttpError, Input, Metrics, Result, State, StateKey, Store, StoreTransaction,
+};
 
 use crypto::*;
 use http::*;
 use json::*;
 use read::*;
+use request::*;
+use response::*;
+use websocket::*;
+
+pub use request::decode_form;
+pub(crate) use require::*;
+pub use response::Output;
+pub use websocket::WebSocket;
 
 mod crypto;
 mod http;
 mod json;
 mod read;
+mod request;
+mod require;
+mod response;
+mod websocket;
 
 // ref: https://www.lua.org/pil/8.1.html
 const K_LOADED: &str = "_LOADED";
 
+const LOCKED_BY_TRANSACTION: &str =
+    "store is locked by the transaction, use the transaction instead";
+
+const LOCKED_BY_UPDATE: &str = "store is locked by the update, return the new value instead";
+
+/// Number of values listed by `list` when the limit is omitted.
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// State of the running evaluation, which is exposed to the script.
+/// It's replaced before every evaluation, so the binding is only registered once.
+#[derive(Clone, Debug, Default)]
+pub struct StateSlot(Arc<Mutex<Option<SlotState>>>);
+
+// the request body is cached along with the state of the request
+type SlotState = (Arc<State>, RequestBody);
+
+impl StateSlot {
+    /// Create a slot with the state.
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// use lmb::*;
+    /// let state = Arc::new(State::new());
+    /// let slot = StateSlot::new(Some(state));
+    /// slot.set(None);
+    /// ```
+    pub fn new(state: Option<Arc<State>>) -> Self {
+        let slot = Self::default();
+        slot.set(state);
+        slot
+    }
+
+    /// Set or unset the state. The request body read by the previous evaluation is discarded.
+    pub fn set(&self, state: Option<Arc<State>>) {
+        *self.0.lock() = state.map(|state| (state, RequestBody::default()));
+    }
+
+    fn state(&self) -> Option<Arc<State>> {
+        self.0.lock().as_ref().map(|(state, _)| state.clone())
+    }
+
+    fn body(&self) -> RequestBody {
+        self.0
+            .lock()
+            .as_ref()
+            .map(|(_, body)| body.clone())
+            .unwrap_or_default()
+    }
+}
+
 /// Interface between Lua and Rust.
 #[derive(Debug)]
 pub struct LuaBinding<R>
 where
     R: Read,
 {
+    context: Arc<Context>,
     input: Input<R>,
-    state: Option<Arc<State>>,
+    metrics: Metrics,
+    output: Output,
+    state: StateSlot,
     store: Option<Store>,
+    // the store is locked while the function of `update` or `transaction` is running
+    locked_by: Mutex<Option<&'static str>>,
+    locked_store_called: AtomicBool,
 }
 
 impl<R> LuaBinding<R>
@@ -49,9 +127,14 @@ where
     /// ```
     pub fn new(input: Input<R>, store: Option<Store>, state: Option<Arc<State>>) -> Self {
         Self {
+            context: Arc::default(),
             input,
-            state,
+            metrics: Metrics::default(),
+            output: Output::default(),
+            state: StateSlot::new(state),
             store,
+            locked_by: Mutex::new(None),
+            locked_store_called: AtomicBool::new(false),
         }
     }
 
@@ -65,13 +148,23 @@ where
     /// let vm = Lua::new();
     /// let input = Arc::new(Mutex::new(BufReader::new(Cursor::new("0"))));
     /// let store = Store::default();
-    /// let _ = LuaBinding::register(&vm, input, Some(store), None);
+    /// let context = Arc::new(Context::new());
+    /// let output = Output::default();
+    /// let metrics = Metrics::default();
+    /// let state = StateSlot::default();
+    /// let timeout = DEFAULT_TIMEOUT;
+    /// let _ = LuaBinding::register(&vm, input, Some(store), state, context, output, metrics, timeout);
     /// ```
+    #[allow(clippy::too_many_arguments)]
     pub fn register(
         vm: &Lua,
         input: Input<R>,
         store: Option<Store>,
-        state: Option<Arc<State>>,
+        state: StateSlot,
+        context: Arc<Context>,
+        output: Output,
+        metrics: Metrics,
+        timeout: Duration,
     ) -> Result<()> {
         let io_table = vm.create_table()?;
 
@@ -96,9 +189,18 @@ where
         globals.set("io", io_table)?;
 
         let loaded = vm.named_registry_value::<LuaTable<'_>>(K_LOADED)?;
-        loaded.set("@lmb", Self::new(input, store, state))?;
+        loaded.set(
+            "@lmb",
+            Self {
+                context,
+                metrics: metrics.clone(),
+                output,
+                state,
+                ..Self::new(input, store, None)
+            },
+        )?;
         loaded.set("@lmb/crypto", LuaModCrypto {})?;
-        loaded.set("@lmb/http", LuaModHTTP {})?;
+        loaded.set("@lmb/http", LuaModHTTP::new(metrics, timeout))?;
         loaded.set("@lmb/json", LuaModJSON {})?;
         vm.set_named_registry_value(K_LOADED, loaded)?;
 
@@ -132,9 +234,10 @@ fn lua_lmb_get<'lua, R>(
 where
     R: Read,
 {
-    let Some(store) = &lmb.store else {
+    let Some(store) = lua_store(lmb)? else {
         return Ok(LuaNil);
     };
+    count_store_operation(lmb, "get");
     let value = store.get(key.as_str()).into_lua_err()?;
     match value {
         Value::Null => Ok(LuaNil),
@@ -145,33 +248,140 @@ where
 fn lua_lmb_put<'lua, R>(
     vm: &'lua Lua,
     lmb: &LuaBinding<R>,
-    (key, value): (String, LuaValue<'lua>),
+    (key, value, options): (String, LuaValue<'lua>, Option<LuaTable<'lua>>),
 ) -> LuaResult<LuaValue<'lua>>
 where
     R: Read,
 {
-    let Some(store) = &lmb.store else {
+    let Some(store) = lua_store(lmb)? else {
         return Ok(LuaNil);
     };
+    count_store_operation(lmb, "put");
+    let ttl = store_ttl(options)?;
     let serialized = serde_json::to_value(&value).into_lua_err()?;
-    store.put(key, &serialized).into_lua_err()?;
+    match ttl {
+        Some(ttl) => store.put_with_ttl(key, &serialized, ttl),
+        None => store.put(key, &serialized),
+    }
+    .into_lua_err()?;
     vm.to_value(&value)
 }
 
+fn lua_lmb_get_many<'lua, R>(
+    vm: &'lua Lua,
+    lmb: &LuaBinding<R>,
+    keys: Vec<String>,
+) -> LuaResult<LuaValue<'lua>>
+where
+    R: Read,
+{
+    let Some(store) = lua_store(lmb)? else {
+        return Ok(LuaNil);
+    };
+    count_store_operation(lmb, "get_many");
+    let values = store.get_many(&keys).into_lua_err()?;
+    let table = vm.create_table()?;
+    for (key, value) in values {
+        table.set(key, vm.to_value(&value)?)?;
+    }
+    Ok(LuaValue::Table(table))
+}
+
+fn lua_lmb_put_many<'lua, R>(
+    _vm: &'lua Lua,
+    lmb: &LuaBinding<R>,
+    (values, options): (LuaTable<'lua>, Option<LuaTable<'lua>>),
+) -> LuaResult<LuaValue<'lua>>
+where
+    R: Read,
+{
+    let Some(store) = lua_store(lmb)? else {
+        return Ok(LuaNil);
+    };
+    count_store_operation(lmb, "put_many");
+    let ttl = store_ttl(options)?;
+    let mut serialized = vec![];
+    for pair in values.clone().pairs::<String, LuaValue<'_>>() {
+        let (key, value) = pair?;
+        serialized.push((key, serde_json::to_value(&value).into_lua_err()?));
+    }
+    store.put_many(serialized, ttl).into_lua_err()?;
+    Ok(LuaValue::Table(values))
+}
+
+fn lua_lmb_list<'lua, R>(
+    vm: &'lua Lua,
+    lmb: &LuaBinding<R>,
+    (prefix, options): (Option<String>, Option<LuaTable<'lua>>),
+) -> LuaResult<LuaValue<'lua>>
+where
+    R: Read,
+{
+    let Some(store) = lua_store(lmb)? else {
+        return Ok(LuaNil);
+    };
+    count_store_operation(lmb, "list");
+    let (cursor, limit): (Option<String>, Option<usize>) = match options {
+        Some(o) => (o.get("cursor")?, o.get("limit")?),
+        None => (None, None),
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    let values = store
+        .list_prefix(&prefix.unwrap_or_default(), cursor.as_deref(), limit)
+        .into_lua_err()?;
+    let table = vm.create_table()?;
+    for value in values {
+        let row = vm.create_table()?;
+        row.set("name", value.name())?;
+        row.set("type_hint", value.type_hint())?;
+        row.set("size", value.size())?;
+        row.set("created_at", value.created_at().to_rfc3339())?;
+        row.set("updated_at", value.updated_at().to_rfc3339())?;
+        row.set("expires_at", value.expires_at().map(|t| t.to_rfc3339()))?;
+        table.push(row)?;
+    }
+    Ok(LuaValue::Table(table))
+}
+
+fn lua_lmb_delete_prefix<R>(_vm: &Lua, lmb: &LuaBinding<R>, prefix: String) -> LuaResult<usize>
+where
+    R: Read,
+{
+    let Some(store) = lua_store(lmb)? else {
+        return Ok(0);
+    };
+    count_store_operation(lmb, "delete_prefix");
+    if prefix.is_empty() {
+        return Err(LuaError::runtime("prefix must not be empty"));
+    }
+    store.delete_prefix(&prefix).into_lua_err()
+}
+
 fn lua_lmb_update<'lua, R>(
     vm: &'lua Lua,
     lmb: &LuaBinding<R>,
-    (key, f, default_v): (String, LuaFunction<'lua>, Option<LuaValue<'lua>>),
+    (key, f, default_v, options): (
+        String,
+        LuaFunction<'lua>,
+        Option<LuaValue<'lua>>,
+        Option<LuaTable<'lua>>,
+    ),
 ) -> LuaResult<LuaValue<'lua>>
 where
     R: Read,
 {
-    let Some(store) = &lmb.store else {
+    let Some(store) = lua_store(lmb)? else {
         return Ok(LuaNil);
     };
+    count_store_operation(lmb, "update");
+    let ttl = store_ttl(options)?;
+    let mut failed = false;
     let update_fn = |old: &mut Value| -> LuaResult<()> {
         let old_v = vm.to_value(old)?;
-        let new = f.call::<_, LuaValue<'_>>(old_v)?;
+        let new = f.call::<_, LuaValue<'_>>(old_v).map_err(|e| {
+            failed = true;
+            e
+        })?;
         *old = vm.from_value(new)?;
         Ok(())
     };
@@ -179,30 +389,227 @@ where
         Some(v) => Some(vm.from_value(v)?),
         None => None,
     };
-    let value = store.update(key, update_fn, default_v).into_lua_err()?;
-    vm.to_value(&value)
+    let res = lock_store(lmb, LOCKED_BY_UPDATE, || match ttl {
+        Some(ttl) => store.update_with_ttl(key, update_fn, default_v, ttl),
+        None => store.update(key, update_fn, default_v),
+    });
+    // the error of the function is swallowed by the update, except calling the store
+    if lmb.locked_store_called.swap(false, Ordering::Relaxed) && failed {
+        return Err(LuaError::runtime(LOCKED_BY_UPDATE));
+    }
+    vm.to_value(&res.into_lua_err()?)
+}
+
+fn lua_lmb_transaction<'lua, R>(
+    vm: &'lua Lua,
+    lmb: &LuaBinding<R>,
+    f: LuaFunction<'lua>,
+) -> LuaResult<LuaValue<'lua>>
+where
+    R: Read,
+{
+    let Some(store) = lua_store(lmb)? else {
+        return Ok(LuaNil);
+    };
+    count_store_operation(lmb, "transaction");
+    let res = lock_store(lmb, LOCKED_BY_TRANSACTION, || {
+        store.transaction(|tx| {
+            let value = vm.scope(|scope| {
+                let tx = scope.create_nonstatic_userdata(LuaStoreTransaction(tx))?;
+                f.call::<_, LuaValue<'_>>(tx)
+            })?;
+            Ok(value)
+        })
+    });
+    lmb.locked_store_called.store(false, Ordering::Relaxed);
+    match res {
+        Ok(value) => Ok(value),
+        Err(Error::Lua(e)) => Err(e),
+        Err(e) => Err(e.into_lua_err()),
+    }
+}
+
+/// Handle of the transaction passed to the function of `transaction`.
+struct LuaStoreTransaction<'a>(&'a StoreTransaction<'a>);
+
+impl LuaUserData for LuaStoreTransaction<'_> {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("delete", |_, this, key: String| {
+            Ok(this.0.delete(key).into_lua_err()? > 0)
+        });
+        methods.add_method("get", |vm, this, key: String| {
+            let value = this.0.get(key).into_lua_err()?;
+            match value {
+                Value::Null => Ok(LuaNil),
+                _ => vm.to_value(&value),
+            }
+        });
+        methods.add_method(
+            "put",
+            |vm, this, (key, value, options): (String, LuaValue<'lua>, Option<LuaTable<'lua>>)| {
+                let ttl = store_ttl(options)?;
+                let serialized = serde_json::to_value(&value).into_lua_err()?;
+                match ttl {
+                    Some(ttl) => this.0.put_with_ttl(key, &serialized, ttl),
+                    None => this.0.put(key, &serialized),
+                }
+                .into_lua_err()?;
+                vm.to_value(&value)
+            },
+        );
+    }
+}
+
+/// Get the store, which is locked by the function of `update` or `transaction`, if any.
+fn lua_store<R>(lmb: &LuaBinding<R>) -> LuaResult<Option<&Store>>
+where
+    R: Read,
+{
+    if let Some(message) = *lmb.locked_by.lock() {
+        lmb.locked_store_called.store(true, Ordering::Relaxed);
+        return Err(LuaError::runtime(message));
+    }
+    Ok(lmb.store.as_ref())
+}
+
+/// Lock the store while the function is running, so calling the store from the function
+/// raises an error with the message instead of waiting for the lock forever.
+fn lock_store<R, T>(lmb: &LuaBinding<R>, message: &'static str, f: impl FnOnce() -> T) -> T
+where
+    R: Read,
+{
+    *lmb.locked_by.lock() = Some(message);
+    let res = f();
+    *lmb.locked_by.lock() = None;
+    res
+}
+
+/// Get the time to live in seconds from the options of `put` and `update`.
+fn store_ttl(options: Option<LuaTable<'_>>) -> LuaResult<Option<Duration>> {
+    let Some(ttl) = options
+        .map(|o| o.get::<_, Option<f64>>("ttl"))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+    match Duration::try_from_secs_f64(ttl) {
+        Ok(ttl) if !ttl.is_zero() => Ok(Some(ttl)),
+        _ => Err(LuaError::runtime(format!("invalid ttl: {ttl}"))),
+    }
+}
+
+fn count_store_operation<R>(lmb: &LuaBinding<R>, operation: &str)
+where
+    R: Read,
+{
+    lmb.metrics
+        .increment(STORE_OPERATIONS, &[("operation", operation)], 1.0);
+}
+
+/// Increment a custom counter. Names and labels follow the Prometheus data model,
+/// and names starting with `lmb_` are reserved for built-in metrics.
+///
+/// ref: <https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels>
+fn lua_lmb_increment<R>(
+    _: &Lua,
+    lmb: &LuaBinding<R>,
+    (name, value, labels): (String, Option<f64>, Option<HashMap<String, String>>),
+) -> LuaResult<()>
+where
+    R: Read,
+{
+    if !regex_is_match!("^[a-zA-Z_:][a-zA-Z0-9_:]*$", &name) || name.starts_with(RESERVED_PREFIX) {
+        return Err(LuaError::runtime(format!("invalid metric name: {name}")));
+    }
+    let value = value.unwrap_or(1.0);
+    if !(value >= 0.0 && value.is_finite()) {
+        return Err(LuaError::runtime("counter can only be increased"));
+    }
+    let labels = labels.unwrap_or_default();
+    if let Some(label) = labels
+        .keys()
+        .find(|l| !regex_is_match!("^[a-zA-Z_][a-zA-Z0-9_]*$", l) || l.starts_with("__"))
+    {
+        return Err(LuaError::runtime(format!("invalid label name: {label}")));
+    }
+    let labels = labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Vec<_>>();
+    lmb.metrics.increment(&name, &labels, value);
+    Ok(())
+}
+
+/// Create an HTTP error to be raised with `error()`. The message defaults to the reason phrase.
+fn lua_lmb_http_error<'lua, R>(
+    _: &'lua Lua,
+    _: &LuaBinding<R>,
+    (status, message): (u16, Option<String>),
+) -> LuaResult<LuaValue<'lua>>
+where
+    R: Read,
+{
+    let Some(reason) = ::http::StatusCode::from_u16(status)
+        .ok()
+        .filter(|s| s.is_client_error() || s.is_server_error())
+    else {
+        return Err(LuaError::runtime(format!(
+            "invalid HTTP error status: {status}"
+        )));
+    };
+    let message =
+        message.unwrap_or_else(|| reason.canonical_reason().unwrap_or_default().to_string());
+    Ok(LuaValue::Error(LuaError::external(HttpError {
+        status,
+        message,
+    })))
 }
 
 impl<R> LuaUserData for LuaBinding<R>
 where
-    for<'lua> R: 'lua + Read,
+    for<'lua> R: 'lua + Read + Send,
 {
     fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
         fields.add_field("_VERSION", env!("APP_VERSION"));
-        fields.add_field_method_get("request", |vm, this| {
-            let Some(v) = this.state.as_ref().and_then(|m| m.get(&StateKey::Request)) else {
+        fields.add_field_method_get("args", |vm, this| vm.to_value(this.context.args()));
+        fields.add_field_method_get("env", |vm, this| vm.to_value(this.context.env()));
+        fields.add_field_method_get("error", |vm, this| {
+            let state = this.state.state();
+            let Some(v) = state.as_ref().and_then(|m| m.get(&StateKey::Error)) else {
                 return Ok(LuaNil);
             };
             vm.to_value(&*v)
         });
-        fields.add_field_method_get("response", |vm, this| {
-            let Some(v) = this.state.as_ref().and_then(|m| m.get(&StateKey::Response)) else {
+        fields.add_field_method_get("record", |vm, this| {
+            let state = this.state.state();
+            let Some(v) = state.as_ref().and_then(|m| m.get(&StateKey::Record)) else {
                 return Ok(LuaNil);
             };
             vm.to_value(&*v)
         });
+        fields.add_field_method_get("request", |vm, this| {
+            let state = this.state.state();
+            let Some(v) = state.as_ref().and_then(|m| m.get(&StateKey::Request)) else {
+                return Ok(LuaNil);
+            };
+            lua_lmb_request(vm, &v, &this.input, &this.state.body())
+        });
+        fields.add_field_method_get("response", |vm, this| {
+            let Some(state) = this.state.state() else {
+                return Ok(LuaNil);
+            };
+            lua_lmb_response(vm, &state, &this.output)
+        });
+        fields.add_field_method_get("websocket", |_, this| {
+            let websocket = this.output.websocket();
+            if websocket.lock().is_none() {
+                return Ok(None);
+            }
+            Ok(Some(LuaWebSocket(websocket.clone())))
+        });
         fields.add_field_method_set("response", |vm, this, value: LuaValue<'lua>| {
-            if let Some(v) = this.state.as_ref() {
+            if let Some(v) = this.state.state() {
                 v.insert(StateKey::Response, vm.from_value(value)?);
             }
             Ok(())
@@ -210,11 +617,18 @@ where
     }
 
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("delete_prefix", lua_lmb_delete_prefix);
         methods.add_method("get", lua_lmb_get);
+        methods.add_method("get_many", lua_lmb_get_many);
+        methods.add_method("http_error", lua_lmb_http_error);
+        methods.add_method("increment", lua_lmb_increment);
         methods.add_method("read_unicode", |vm, this, f| {
             lua_lmb_read_unicode(vm, &this.input, f)
         });
+        methods.add_method("list", lua_lmb_list);
         methods.add_method("put", lua_lmb_put);
+        methods.add_method("put_many", lua_lmb_put_many);
+        methods.add_method("transaction", lua_lmb_transaction);
         methods.add_method("update", lua_lmb_update);
     }
 }
@@ -222,10 +636,62 @@ where
 #[cfg(test)]
 mod tests {
     use serde_json::{json, Value};
-    use std::io::empty;
+    use std::{collections::HashMap, io::empty, sync::Arc};
     use test_case::test_case;
 
-    use crate::EvaluationBuilder;
+    use crate::{Context, EvaluationBuilder, Metrics, State, StateKey};
+
+    #[test]
+    fn args_and_env() {
+        let mut context = Context::new();
+        context
+            .set_args(vec!["a".to_string(), "b".to_string()])
+            .set_env(HashMap::from([("NAME".to_string(), "lmb".to_string())]));
+        let script = "local m = require('@lmb'); return { m.args, m.env.NAME, m.env.HOME }";
+        let e = EvaluationBuilder::new(script, empty())
+            .context(context)
+            .build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!([["a", "b"], "lmb"]), res.payload());
+    }
+
+    #[test]
+    fn increment() {
+        let script = r#"
+        local m = require('@lmb')
+        m:increment('jobs_total')
+        m:increment('jobs_total', 2, { queue = 'a' })
+        m:put('a', 1)
+        m:get('a')
+        assert(not pcall(function() m:increment('lmb_jobs_total') end))
+        assert(not pcall(function() m:increment('jobs-total') end))
+        assert(not pcall(function() m:increment('jobs_total', -1) end))
+        "#;
+        let metrics = Metrics::new();
+        let e = EvaluationBuilder::new(script, empty())
+            .default_store()
+            .metrics(metrics.clone())
+            .name("a")
+            .build();
+        e.evaluate().unwrap();
+        let rendered = metrics.render();
+        assert!(rendered.contains("jobs_total{script=\"a\"} 1\n"));
+        assert!(rendered.contains("jobs_total{queue=\"a\",script=\"a\"} 2\n"));
+        let expected = "lmb_store_operations_total{operation=\"get\",script=\"a\"} 1\n";
+        assert!(rendered.contains(expected));
+        let expected = "lmb_store_operations_total{operation=\"put\",script=\"a\"} 1\n";
+        assert!(rendered.contains(expected));
+    }
+
+    #[test]
+    fn record() {
+        let script = "return require('@lmb').record";
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let state = Arc::new(State::new());
+        state.insert(StateKey::Record, json!({ "a": 1 }));
+        let res = e.evaluate_with_state(state).unwrap();
+        assert_eq!(&json!({ "a": 1 }), res.payload());
+    }
 
     #[test]
     fn read_binary() {
@@ -0,0 +1,236 @@
+use mlua::prelude::*;
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+use url::form_urlencoded;
+
+use crate::Input;
+
+/// Request body, read from the input when a parsed field is accessed for the first time.
+pub(crate) type RequestBody = Arc<OnceCell<Vec<u8>>>;
+
+/// Decode an URL-encoded string e.g. a query string or a form body into a map.
+/// The value of a repeated key is a list of values in order.
+///
+/// ```rust
+/// # use serde_json::json;
+/// use lmb::*;
+/// let decoded = decode_form(b"a=1&b=2&b=3&c=%E4%BD%A0");
+/// assert_eq!(json!({ "a": "1", "b": ["2", "3"], "c": "你" }), decoded);
+/// ```
+pub fn decode_form(input: &[u8]) -> Value {
+    let mut m = Map::new();
+    for (key, value) in form_urlencoded::parse(input) {
+        let value = Value::String(value.into_owned());
+        match m.get_mut(key.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                m.insert(key.into_owned(), value);
+            }
+        }
+    }
+    Value::Object(m)
+}
+
+/// Convert the request to a table with the parsed fields `json`, `form`, and `multipart`.
+/// The body is parsed on first access, so the fields aren't serialized with the request.
+pub(crate) fn lua_lmb_request<'lua, R>(
+    vm: &'lua Lua,
+    request: &Value,
+    input: &Input<R>,
+    body: &RequestBody,
+) -> LuaResult<LuaValue<'lua>>
+where
+    for<'a> R: 'a + Read + Send,
+{
+    let LuaValue::Table(table) = vm.to_value(request)? else {
+        return vm.to_value(request);
+    };
+    let content_type = request
+        .pointer("/headers/content-type")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let index_fn = vm.create_function({
+        let body = body.clone();
+        let input = input.clone();
+        move |vm, (table, key): (LuaTable<'_>, String)| {
+            let parse: for<'l> fn(&'l Lua, &str, &[u8]) -> LuaResult<LuaValue<'l>> =
+                match key.as_str() {
+                    "form" => |vm, _, body| vm.to_value(&decode_form(body)),
+                    "json" => parse_json,
+                    "multipart" => parse_multipart,
+                    _ => return Ok(LuaNil),
+                };
+            let body = body.get_or_try_init(|| {
+                let mut buf = Vec::new();
+                input.lock().read_to_end(&mut buf)?;
+                Ok::<_, io::Error>(buf)
+            })?;
+            let value = parse(vm, &content_type, body)?;
+            table.raw_set(key, value.clone())?;
+            Ok(value)
+        }
+    })?;
+    let metatable = vm.create_table()?;
+    metatable.set("__index", index_fn)?;
+    table.set_metatable(Some(metatable));
+    Ok(LuaValue::Table(table))
+}
+
+fn parse_json<'lua>(vm: &'lua Lua, _: &str, body: &[u8]) -> LuaResult<LuaValue<'lua>> {
+    if body.is_empty() {
+        return Ok(LuaNil);
+    }
+    let value: Value = serde_json::from_slice(body).into_lua_err()?;
+    vm.to_value(&value)
+}
+
+/// Parse a `multipart/form-data` body into a list of parts.
+/// The data of a part is a Lua string, which may contain arbitrary bytes e.g. an uploaded file.
+fn parse_multipart<'lua>(
+    vm: &'lua Lua,
+    content_type: &str,
+    body: &[u8],
+) -> LuaResult<LuaValue<'lua>> {
+    let Some(boundary) = parameter(content_type, "boundary") else {
+        return Ok(LuaNil);
+    };
+    let delimiter = format!("--{boundary}");
+    let invalid = || LuaError::runtime("invalid multipart body");
+
+    let parts = vm.create_table()?;
+    let start = find(body, delimiter.as_bytes()).ok_or_else(invalid)?;
+    let mut rest = &body[start + delimiter.len()..];
+    let next_delimiter = format!("\r\n{delimiter}");
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n").ok_or_else(invalid)?;
+        let end = find(rest, next_delimiter.as_bytes()).ok_or_else(invalid)?;
+        let part = &rest[..end];
+        rest = &rest[end + next_delimiter.len()..];
+
+        let (head, data) = match find(part, b"\r\n\r\n") {
+            Some(idx) => (&part[..idx], &part[idx + 4..]),
+            // part without headers
+            None => (&[][..], part.strip_prefix(b"\r\n").ok_or_else(invalid)?),
+        };
+        let t = vm.create_table()?;
+        let headers = vm.create_table()?;
+        for line in String::from_utf8_lossy(head).split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (name, value) = (name.trim().to_lowercase(), value.trim());
+            match name.as_str() {
+                "content-disposition" => {
+                    t.set("name", parameter(value, "name"))?;
+                    t.set("filename", parameter(value, "filename"))?;
+                }
+                "content-type" => t.set("content_type", value)?,
+                _ => {}
+            }
+            headers.set(name, value)?;
+        }
+        t.set("headers", headers)?;
+        t.set("data", vm.create_string(data)?)?;
+        parts.push(t)?;
+    }
+    Ok(LuaValue::Table(parts))
+}
+
+/// Get a parameter of a header value e.g. `boundary` of `multipart/form-data; boundary=x`.
+fn parameter<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| v.trim().trim_matches('"'))
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::{io::Cursor, sync::Arc};
+
+    use crate::{EvaluationBuilder, State, StateKey};
+
+    fn evaluate(script: &str, content_type: &str, body: &'static str) -> serde_json::Value {
+        let e = EvaluationBuilder::new(script, Cursor::new(body)).build();
+        let state = Arc::new(State::new());
+        let request = json!({ "headers": { "content-type": content_type } });
+        state.insert(StateKey::Request, request);
+        let res = e.evaluate_with_state(state).unwrap();
+        res.payload().clone()
+    }
+
+    #[test]
+    fn request_form() {
+        let script = "return require('@lmb').request.form";
+        let body = "a=1&b=2&b=3";
+        let actual = evaluate(script, "application/x-www-form-urlencoded", body);
+        assert_eq!(json!({ "a": "1", "b": ["2", "3"] }), actual);
+    }
+
+    #[test]
+    fn request_json() {
+        let script = r#"
+        local m = require('@lmb')
+        local a = m.request.json.a
+        -- the body is read once
+        return { a, m.request.json.a, m.request }
+        "#;
+        let actual = evaluate(script, "application/json", r#"{"a":1}"#);
+        let expected = json!([1, 1, { "headers": { "content-type": "application/json" } }]);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn request_json_invalid() {
+        let script = "return (pcall(function() return require('@lmb').request.json end))";
+        let actual = evaluate(script, "application/json", "{");
+        assert_eq!(json!(false), actual);
+    }
+
+    #[test]
+    fn request_multipart() {
+        let script = r#"
+        local parts = require('@lmb').request.multipart
+        for _, part in ipairs(parts) do
+          part.headers = nil
+        end
+        return parts
+        "#;
+        let body = concat!(
+            "--X\r\n",
+            "Content-Disposition: form-data; name=\"a\"\r\n",
+            "\r\n",
+            "1\r\n",
+            "--X\r\n",
+            "Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "hello\r\nworld\r\n",
+            "--X--\r\n",
+        );
+        let actual = evaluate(script, "multipart/form-data; boundary=X", body);
+        let expected = json!([
+            { "data": "1", "name": "a" },
+            {
+                "content_type": "text/plain",
+                "data": "hello\r\nworld",
+                "filename": "a.txt",
+                "name": "file",
+            },
+        ]);
+        assert_eq!(expected, actual);
+    }
+}
@@ -0,0 +1,233 @@
+use dashmap::DashMap;
+use mlua::{prelude::*, Compiler};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{debug, trace_span};
+
+use super::K_LOADED;
+use crate::Result;
+
+// ref: https://www.lua.org/manual/5.1/manual.html#pdf-package.loaders
+const K_LOADERS: &str = "_LOADERS";
+
+const EXTENSIONS: [&str; 2] = ["luau", "lua"];
+
+/// Module compiled from a file in the module search path.
+#[derive(Debug)]
+struct LuaModule {
+    compiled: Vec<u8>,
+    name: String,
+    source: String,
+}
+
+/// Loader of modules required from the module search path.
+/// Compiled modules are cached and shared by every virtual machine using the loader.
+#[derive(Debug, Default)]
+pub(crate) struct LuaModuleLoader {
+    cache: DashMap<PathBuf, Arc<LuaModule>>,
+    paths: Vec<PathBuf>,
+}
+
+impl LuaModuleLoader {
+    pub(crate) fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            cache: DashMap::new(),
+            paths,
+        }
+    }
+
+    /// Replace the default loaders of the virtual machine,
+    /// so modules can only be required from the module search path.
+    pub(crate) fn register(self: &Arc<Self>, vm: &Lua) -> Result<()> {
+        let loader = vm.create_function({
+            let this = self.clone();
+            move |vm, name: String| this.load(vm, &name)
+        })?;
+        let loaders = vm.create_sequence_from([loader])?;
+        vm.set_named_registry_value(K_LOADERS, loaders)?;
+        Ok(())
+    }
+
+    /// Unload required modules, so module state doesn't leak into the next evaluation.
+    /// Built-in modules are kept.
+    pub(crate) fn unload(vm: &Lua) -> Result<()> {
+        let loaded = vm.named_registry_value::<LuaTable<'_>>(K_LOADED)?;
+        let names = loaded
+            .clone()
+            .pairs::<String, LuaValue<'_>>()
+            .filter_map(|pair| pair.ok().map(|(name, _)| name))
+            .filter(|name| !name.starts_with('@'))
+            .collect::<Vec<_>>();
+        for name in names {
+            loaded.raw_set(name, LuaNil)?;
+        }
+        Ok(())
+    }
+
+    /// Get paths of loaded modules.
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        self.cache.iter().map(|m| m.key().clone()).collect()
+    }
+
+    /// Get the source of a loaded module by its chunk name.
+    pub(crate) fn source(&self, name: &str) -> Option<String> {
+        self.cache
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| m.source.clone())
+    }
+
+    fn load<'lua>(&self, vm: &'lua Lua, name: &str) -> LuaResult<LuaValue<'lua>> {
+        let relative = Path::new(name)
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect::<PathBuf>();
+        let mut messages = vec![];
+        for dir in &self.paths {
+            for candidate in candidates(&dir.join(&relative)) {
+                if !candidate.is_file() {
+                    messages.push(format!("no file '{}'", candidate.display()));
+                    continue;
+                }
+                if !is_within(dir, &candidate)? {
+                    messages.push(format!(
+                        "file '{}' is outside of the module path",
+                        candidate.display()
+                    ));
+                    continue;
+                }
+                let module = self.compile(&candidate)?;
+                let f = vm
+                    .load(&module.compiled)
+                    .set_name(&module.name)
+                    .into_function()?;
+                return Ok(LuaValue::Function(f));
+            }
+        }
+        if messages.is_empty() {
+            return Ok(LuaNil);
+        }
+        messages.join("\n\t").into_lua(vm)
+    }
+
+    fn compile(&self, path: &Path) -> LuaResult<Arc<LuaModule>> {
+        if let Some(module) = self.cache.get(path) {
+            return Ok(module.clone());
+        }
+        let source = fs::read_to_string(path)?;
+        let compiled = {
+            let compiler = Compiler::new();
+            let _s = trace_span!("compile_module").entered();
+            compiler.compile(&source)
+        };
+        let name = path.display().to_string();
+        debug!(%name, "module compiled");
+        let module = Arc::new(LuaModule {
+            compiled,
+            name,
+            source,
+        });
+        self.cache.insert(path.to_path_buf(), module.clone());
+        Ok(module)
+    }
+}
+
+fn candidates(path: &Path) -> Vec<PathBuf> {
+    let with_extension = |path: &Path, ext: &str| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".");
+        path.push(ext);
+        PathBuf::from(path)
+    };
+    let mut candidates = vec![];
+    for ext in EXTENSIONS {
+        candidates.push(with_extension(path, ext));
+    }
+    for ext in EXTENSIONS {
+        candidates.push(with_extension(&path.join("init"), ext));
+    }
+    candidates
+}
+
+fn is_within(dir: &Path, path: &Path) -> LuaResult<bool> {
+    Ok(path.canonicalize()?.starts_with(dir.canonicalize()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use mlua::prelude::*;
+    use serde_json::json;
+    use std::io::empty;
+
+    use crate::{Error, EvaluationBuilder};
+
+    #[test]
+    fn require_module() {
+        let dir = TempDir::new().unwrap();
+        dir.child("lib/util.lua")
+            .write_str("return { add = function(a, b) return a + b end }")
+            .unwrap();
+        let script = "return require('./lib/util').add(1, 2)";
+        let e = EvaluationBuilder::new(script, empty())
+            .module_paths(vec![dir.to_path_buf()])
+            .build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!(3), res.payload());
+    }
+
+    #[test]
+    fn require_module_error() {
+        let dir = TempDir::new().unwrap();
+        let module = dir.child("util.lua");
+        module.write_str("\nreturn nil+1").unwrap();
+        let script = "return require('util')";
+        let e = EvaluationBuilder::new(script, empty())
+            .module_paths(vec![dir.to_path_buf()])
+            .build();
+        let err = e.evaluate().unwrap_err();
+        let mut buf = String::new();
+        err.write_lua_error(&mut buf, &e, true).unwrap();
+        let expected = format!("{}:2:", module.display());
+        assert!(buf.contains(&expected));
+        assert!(buf.contains("attempt to perform arithmetic (add) on nil and number"));
+    }
+
+    #[test]
+    fn require_module_not_found() {
+        let dir = TempDir::new().unwrap();
+        let script = "return require('util')";
+        let e = EvaluationBuilder::new(script, empty())
+            .module_paths(vec![dir.to_path_buf()])
+            .build();
+        let err = e.evaluate().unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Lua(LuaError::RuntimeError(message)) if message.contains("module 'util' not found")
+        ));
+    }
+
+    #[test]
+    fn require_module_outside() {
+        let dir = TempDir::new().unwrap();
+        dir.child("lib").create_dir_all().unwrap();
+        dir.child("secret.lua").write_str("return 1").unwrap();
+        let script = "return require('../secret')";
+        let e = EvaluationBuilder::new(script, empty())
+            .module_paths(vec![dir.child("lib").to_path_buf()])
+            .build();
+        let err = e.evaluate().unwrap_err();
+        assert!(err.to_string().contains("outside of the module path"));
+    }
+
+    #[test]
+    fn require_without_module_path() {
+        let script = "return require('./lib/util')";
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let err = e.evaluate().unwrap_err();
+        assert!(err.to_string().contains("module './lib/util' not found"));
+    }
+}
@@ -0,0 +1,230 @@
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write},
+    sync::Arc,
+};
+
+use crate::{State, StateKey, WebSocket};
+
+/// Output to the client, e.g. a chunked HTTP body or a WebSocket connection.
+/// When the writer is set, the script can stream the body with `response:write(chunk)`.
+/// When the WebSocket connection is set, it's exposed as `require('@lmb').websocket`.
+#[derive(Clone, Default)]
+pub struct Output {
+    websocket: Arc<Mutex<Option<WebSocket>>>,
+    writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+impl Output {
+    /// Set or unset the writer.
+    pub fn set_writer(&self, writer: Option<Box<dyn Write + Send>>) {
+        *self.writer.lock() = writer;
+    }
+
+    /// Set or unset the WebSocket connection.
+    pub fn set_websocket(&self, websocket: Option<WebSocket>) {
+        *self.websocket.lock() = websocket;
+    }
+
+    pub(crate) fn websocket(&self) -> &Arc<Mutex<Option<WebSocket>>> {
+        &self.websocket
+    }
+
+    fn write(&self, chunk: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock();
+        let Some(writer) = writer.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "response is not streamable",
+            ));
+        };
+        writer.write_all(chunk)?;
+        writer.flush()
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Output")
+            .field("websocket", &self.websocket.lock().is_some())
+            .field("writer", &self.writer.lock().is_some())
+            .finish()
+    }
+}
+
+/// Convert the response to a table with the methods `write` and `event`.
+/// Status code and headers are saved before the first chunk is written,
+/// so they must be set before then.
+pub(crate) fn lua_lmb_response<'lua>(
+    vm: &'lua Lua,
+    state: &Arc<State>,
+    output: &Output,
+) -> LuaResult<LuaValue<'lua>> {
+    let value = state
+        .get(&StateKey::Response)
+        .map_or_else(|| json!({}), |v| v.clone());
+    let LuaValue::Table(table) = vm.to_value(&value)? else {
+        return vm.to_value(&value);
+    };
+    let write_fn = vm.create_function({
+        let output = output.clone();
+        let state = state.clone();
+        move |vm, (this, chunk): (LuaTable<'_>, LuaString<'_>)| {
+            write_chunk(vm, &state, &output, this, chunk.as_bytes())
+        }
+    })?;
+    let event_fn = vm.create_function({
+        let output = output.clone();
+        let state = state.clone();
+        move |vm, (this, event): (LuaTable<'_>, LuaValue<'_>)| {
+            let headers = if let Some(headers) = this.raw_get("headers")? {
+                headers
+            } else {
+                let headers: LuaTable<'_> = vm.create_table()?;
+                this.raw_set("headers", headers.clone())?;
+                headers
+            };
+            if headers.raw_get::<_, LuaValue<'_>>("content-type")?.is_nil() {
+                headers.raw_set("content-type", "text/event-stream")?;
+                headers.raw_set("cache-control", "no-cache")?;
+            }
+            let chunk = format_event(event)?;
+            write_chunk(vm, &state, &output, this, chunk.as_bytes())
+        }
+    })?;
+    let index = vm.create_table()?;
+    index.set("event", event_fn)?;
+    index.set("write", write_fn)?;
+    let metatable = vm.create_table()?;
+    metatable.set("__index", index)?;
+    table.set_metatable(Some(metatable));
+    Ok(LuaValue::Table(table))
+}
+
+fn write_chunk(
+    vm: &Lua,
+    state: &State,
+    output: &Output,
+    response: LuaTable<'_>,
+    chunk: &[u8],
+) -> LuaResult<()> {
+    let value: Value = vm.from_value(LuaValue::Table(response))?;
+    state.insert(StateKey::Response, value);
+    output.write(chunk)?;
+    Ok(())
+}
+
+/// Format a server-sent event. The event is either the data,
+/// or a table with the fields `data`, `event`, `id`, and `retry`.
+/// Data other than a string is encoded as JSON.
+///
+/// ref: <https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation>
+fn format_event(event: LuaValue<'_>) -> LuaResult<String> {
+    let mut buf = String::new();
+    let data = match event {
+        LuaValue::Table(t) => {
+            for field in ["event", "id", "retry"] {
+                if let Some(value) = t.get::<_, Option<String>>(field)? {
+                    writeln!(buf, "{field}: {value}").into_lua_err()?;
+                }
+            }
+            t.get::<_, LuaValue<'_>>("data")?
+        }
+        event => event,
+    };
+    let data = match data {
+        LuaNil => String::new(),
+        LuaValue::String(s) => s.to_str()?.to_string(),
+        data => serde_json::to_string(&data).into_lua_err()?,
+    };
+    for line in data.split('\n') {
+        writeln!(buf, "data: {line}").into_lua_err()?;
+    }
+    buf.push('\n');
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+    use serde_json::json;
+    use std::{
+        io::{self, empty, Write},
+        sync::Arc,
+    };
+
+    use crate::{EvaluationBuilder, State, StateKey};
+
+    #[derive(Clone, Default)]
+    struct Chunks(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Chunks {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn response_write() {
+        let script = r#"
+        local res = require('@lmb').response
+        res.status_code = 201
+        res:write('a')
+        res:write('\0\255')
+        return 1
+        "#;
+        let chunks = Chunks::default();
+        let e = EvaluationBuilder::new(script, empty()).build();
+        e.set_output(Some(Box::new(chunks.clone())));
+        let state = Arc::new(State::new());
+        let res = e.evaluate_with_state(state.clone()).unwrap();
+        assert_eq!(&json!(1), res.payload());
+        assert_eq!(vec![b"a".to_vec(), vec![0, 255]], *chunks.0.lock());
+        let response = state.get(&StateKey::Response).unwrap();
+        assert_eq!(json!({ "status_code": 201 }), *response);
+    }
+
+    #[test]
+    fn response_event() {
+        let script = r#"
+        local res = require('@lmb').response
+        res:event('a')
+        res:event({ event = 'update', id = 1, data = { b = 1 } })
+        res:event({ data = 'c\nd' })
+        "#;
+        let chunks = Chunks::default();
+        let e = EvaluationBuilder::new(script, empty()).build();
+        e.set_output(Some(Box::new(chunks.clone())));
+        let state = Arc::new(State::new());
+        e.evaluate_with_state(state.clone()).unwrap();
+        let expected = [
+            "data: a\n\n",
+            "event: update\nid: 1\ndata: {\"b\":1}\n\n",
+            "data: c\ndata: d\n\n",
+        ]
+        .map(|s| s.as_bytes().to_vec());
+        assert_eq!(expected.to_vec(), *chunks.0.lock());
+        let response = state.get(&StateKey::Response).unwrap();
+        let expected = json!({
+            "headers": { "cache-control": "no-cache", "content-type": "text/event-stream" },
+        });
+        assert_eq!(expected, *response);
+    }
+
+    #[test]
+    fn response_write_without_output() {
+        let script = "require('@lmb').response:write('a')";
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let state = Arc::new(State::new());
+        let err = e.evaluate_with_state(state).unwrap_err();
+        assert!(err.to_string().contains("response is not streamable"));
+    }
+}
@@ -0,0 +1,101 @@
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// WebSocket connection exposed to the script as `require('@lmb').websocket`.
+/// Messages are exchanged through channels, so the script can block on them
+/// while the server handles the connection asynchronously.
+#[derive(Debug)]
+pub struct WebSocket {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+}
+
+impl WebSocket {
+    /// Create a connection with channels of incoming and outgoing messages.
+    /// Text and binary messages are both passed as bytes.
+    pub fn new(incoming: Receiver<Vec<u8>>, outgoing: Sender<Vec<u8>>) -> Self {
+        Self { incoming, outgoing }
+    }
+}
+
+/// Handle of the WebSocket connection in Lua.
+pub(crate) struct LuaWebSocket(pub(crate) Arc<Mutex<Option<WebSocket>>>);
+
+impl LuaUserData for LuaWebSocket {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        // block until a message is received, or return nil when the connection is closed
+        methods.add_method("receive", |vm, this, ()| {
+            let mut websocket = this.0.lock();
+            let Some(websocket) = websocket.as_mut() else {
+                return Ok(LuaNil);
+            };
+            match websocket.incoming.blocking_recv() {
+                Some(message) => Ok(LuaValue::String(vm.create_string(message)?)),
+                None => Ok(LuaNil),
+            }
+        });
+        methods.add_method("send", |_, this, message: LuaString<'lua>| {
+            let websocket = this.0.lock();
+            let Some(websocket) = websocket.as_ref() else {
+                return Err(LuaError::runtime("websocket is closed"));
+            };
+            let message = message.as_bytes().to_vec();
+            if websocket.outgoing.blocking_send(message).is_err() {
+                return Err(LuaError::runtime("websocket is closed"));
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::{io::empty, sync::Arc, thread};
+    use tokio::sync::mpsc;
+
+    use crate::{EvaluationBuilder, State, WebSocket};
+
+    #[test]
+    fn websocket_echo() {
+        let script = r#"
+        local ws = require('@lmb').websocket
+        local count = 0
+        while true do
+          local message = ws:receive()
+          if not message then
+            break
+          end
+          ws:send('echo ' .. message)
+          count = count + 1
+        end
+        return count
+        "#;
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(1);
+        let e = EvaluationBuilder::new(script, empty()).build();
+        e.set_websocket(Some(WebSocket::new(incoming_rx, outgoing_tx)));
+        let client = thread::spawn(move || {
+            let mut received = vec![];
+            for message in ["a", "b"] {
+                incoming_tx.blocking_send(message.into()).unwrap();
+                received.push(outgoing_rx.blocking_recv().unwrap());
+            }
+            received
+        });
+        let res = e.evaluate_with_state(Arc::new(State::new())).unwrap();
+        assert_eq!(&json!(2), res.payload());
+        let expected = vec![b"echo a".to_vec(), b"echo b".to_vec()];
+        assert_eq!(expected, client.join().unwrap());
+    }
+
+    #[test]
+    fn websocket_absent() {
+        let script = "return require('@lmb').websocket";
+        let e = EvaluationBuilder::new(script, empty()).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!(null), res.payload());
+    }
+}
@@ -1,31 +1,51 @@
 use anyhow::bail;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clio::*;
 use comfy_table::{presets, Table};
 use cron::Schedule;
 use lmb::{
-    Error, EvaluationBuilder, LuaCheck, PrintOptions, ScheduleOptions, Store, StoreOptions,
-    DEFAULT_TIMEOUT, EXAMPLES, GUIDES,
+    CancellationToken, Context, Error, Evaluation, EvaluationBuilder, LuaCheck, Metrics,
+    PrintOptions, ScheduleOptions, Solution, SqliteBackend, State, StateKey, Store, StoreOptions,
+    StoreRecord, DEFAULT_POOL_SIZE, DEFAULT_TIMEOUT, EXAMPLES, GUIDES,
 };
+use middleware::Middleware;
 use mlua::prelude::*;
-use serde_json::json;
-use serve::ServeOptions;
+use routes::load_routes;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serde_json::{json, Value};
+use serve::{ErrorFormat, ServeOptions, DEFAULT_DRAIN_TIMEOUT};
 use std::{
+    collections::HashMap,
+    env,
     fmt::Display,
-    io::{self, Read},
+    fs,
+    io::{self, BufRead, Cursor, Empty, Read, Stdin, Write as _},
+    iter,
     path::PathBuf,
     process::ExitCode,
     str::FromStr,
+    sync::Arc,
+    thread,
     time::Duration,
 };
 use termimad::MadSkin;
-use tracing::Level;
+use tls::TlsFiles;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use watch::{Snapshot, POLL_INTERVAL};
 
+mod cache;
+mod middleware;
+mod routes;
 mod serve;
+mod tls;
+mod watch;
 
 static VERSION: &str = env!("APP_VERSION");
 
+/// Interval of purging expired values from the store.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
 /// lmb is a Lua function runner.
 #[derive(Parser)]
 #[command(about, author, version=VERSION)]
@@ -39,12 +59,28 @@ struct Cli {
     #[arg(long, short = 'd', env = "DEBUG")]
     debug: bool,
 
+    /// Expose an environment variable to the script via `require('@lmb').env`.
+    /// Specify `NAME` to pass the variable from the current environment, or `NAME=VALUE`.
+    /// Other environment variables are not visible to the script
+    #[arg(long = "env", value_name = "NAME")]
+    envs: Vec<String>,
+
+    /// Expose environment variables in a dotenv file to the script
+    #[arg(long)]
+    env_file: Option<PathBuf>,
+
     /// Enable JSON mode.
     /// When evaluating, output the solution in JSON format.
     /// When serving, always respond with the solution as a JSON value
     #[arg(long)]
     json: bool,
 
+    /// Directories to search for modules required by the script,
+    /// e.g. `require('./lib/util')` loads `lib/util.luau` or `lib/util.lua`.
+    /// Can be specified multiple times or separated by colons
+    #[arg(long, env = "LMB_MODULE_PATH", value_delimiter = ':')]
+    module_path: Vec<PathBuf>,
+
     /// No color <https://no-color.org/>
     #[arg(long, env = "NO_COLOR")]
     no_color: bool,
@@ -69,6 +105,8 @@ struct Cli {
     command: Commands,
 }
 
+// parsed once, so the size of the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Check syntax of script
@@ -80,12 +118,32 @@ enum Commands {
     /// Evaluate a script file
     #[command(alias = "eval")]
     Evaluate {
+        /// Arguments passed to the script via `require('@lmb').args`
+        #[arg(last = true)]
+        args: Vec<String>,
+        /// Evaluate the script once per JSON value of each line from standard input.
+        /// The value is available via `require('@lmb').record`
+        #[arg(long, conflicts_with = "each_line")]
+        each_json: bool,
+        /// Evaluate the script once per line from standard input.
+        /// The line is available via `require('@lmb').record` and `io.read`
+        #[arg(long)]
+        each_line: bool,
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long, value_parser, default_value = "-")]
         file: Input,
+        /// Memory limit in bytes. The script is aborted when the limit is exceeded
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// What to do when a record fails in batch mode
+        #[arg(long, value_enum, default_value_t = OnError::Stop)]
+        on_error: OnError,
         /// Timeout in seconds
         #[arg(long, default_value_t = DEFAULT_TIMEOUT.as_secs())]
         timeout: u64,
+        /// Watch the script and required modules, and evaluate again on change
+        #[arg(long, conflicts_with_all = ["each_json", "each_line"])]
+        watch: bool,
     },
     /// Check out examples and evaluate or serve them
     #[command(subcommand)]
@@ -95,6 +153,15 @@ enum Commands {
     Guide(GuideCommands),
     /// List available themes
     ListThemes,
+    /// Evaluate statements interactively on one virtual machine
+    Repl {
+        /// Memory limit in bytes. The statement is aborted when the limit is exceeded
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Timeout of each statement in seconds
+        #[arg(long, default_value_t = DEFAULT_TIMEOUT.as_secs())]
+        timeout: u64,
+    },
     /// Schedule the script as a cron job
     Schedule {
         /// Exit immediately upon N number of errors. 0 to disable.
@@ -109,18 +176,69 @@ enum Commands {
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long, value_parser, default_value = "-")]
         file: Input,
+        /// Memory limit in bytes. The script is aborted when the limit is exceeded
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Serve metrics at "/metrics" on a specific host and port in Prometheus text format
+        #[arg(long)]
+        metrics_bind: Option<String>,
+        /// Watch the script and required modules, and reschedule on change
+        #[arg(long)]
+        watch: bool,
     },
     /// Handle HTTP requests with the script
     Serve {
         /// Bind the server to a specific host and port
         #[arg(long, default_value = "127.0.0.1:3000")]
         bind: String,
+        /// Compress response bodies with gzip or brotli when the client accepts them
+        #[arg(long)]
+        compression: bool,
+        /// Seconds to wait for in-flight requests on shutdown
+        #[arg(long, default_value_t = DEFAULT_DRAIN_TIMEOUT.as_secs())]
+        drain_timeout: u64,
+        /// Format of error responses when the script fails
+        #[arg(long, value_enum, default_value_t = ErrorFormat::Problem)]
+        error_format: ErrorFormat,
+        /// Script building the response when a request fails.
+        /// The error is exposed as "require('@lmb').error"
+        #[arg(long)]
+        error_script: Option<PathBuf>,
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long, value_parser, default_value = "-")]
         file: Input,
+        /// Memory limit in bytes. The script is aborted when the limit is exceeded
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Expose metrics at "/metrics" in Prometheus text format
+        #[arg(long)]
+        metrics: bool,
+        #[command(flatten)]
+        middleware: Middleware,
+        /// Number of pre-built Lua virtual machines to handle requests concurrently
+        #[arg(long, default_value_t = DEFAULT_POOL_SIZE)]
+        pool_size: usize,
+        /// Route table mapping methods and paths to scripts, either a TOML file
+        /// or a directory of scripts e.g. "routes/users/[id].lua" is served at "/users/:id".
+        /// The script from "--file" is ignored
+        #[arg(long)]
+        routes: Option<PathBuf>,
         /// Timeout in seconds
         #[arg(long)]
         timeout: Option<u64>,
+        /// Certificate chain in PEM format to serve HTTPS. It's reloaded on change
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// Private key in PEM format to serve HTTPS. It's reloaded on change
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Listen on a Unix domain socket instead of the TCP address
+        #[arg(long, conflicts_with = "bind")]
+        unix_socket: Option<PathBuf>,
+        /// Watch the script and required modules, and reload on change without dropping connections.
+        /// The last good version is kept when the script is broken
+        #[arg(long)]
+        watch: bool,
     },
     /// Store commands
     #[command(subcommand)]
@@ -171,22 +289,85 @@ enum GuideCommands {
     List,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum OnError {
+    /// Output an error record as a JSON line and continue
+    Emit,
+    /// Log a warning and continue
+    Skip,
+    /// Print the error and exit
+    Stop,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StoreFormat {
+    /// A JSON object per line
+    Json,
+    /// A `MessagePack` map per value
+    Msgpack,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportMode {
+    /// Keep existing values with the same names
+    Merge,
+    /// Overwrite existing values with the same names
+    Overwrite,
+}
+
 #[derive(Parser)]
 enum StoreCommands {
+    /// Back up the store, which is safe while the store is in use e.g. by `serve`
+    Backup {
+        /// Path of the backup, which must not exist
+        #[arg(long)]
+        to: PathBuf,
+    },
     /// Delete a value
     Delete {
         /// Name
         #[arg(long)]
         name: String,
     },
+    /// Export values with type hints and timestamps, except expired ones
+    Export {
+        /// Format of the output
+        #[arg(long, value_enum, default_value_t = StoreFormat::Json)]
+        format: StoreFormat,
+        /// Output file
+        #[arg(long, value_parser, default_value = "-")]
+        output: Output,
+    },
     /// Get a value
     Get {
         /// Name
         #[arg(long)]
         name: String,
     },
-    /// List values
-    List,
+    /// Import values exported by `store export`, except expired ones
+    Import {
+        /// Format of the input
+        #[arg(long, value_enum, default_value_t = StoreFormat::Json)]
+        format: StoreFormat,
+        /// How to handle existing values with the same names
+        #[arg(long, value_enum, default_value_t = ImportMode::Merge)]
+        mode: ImportMode,
+        /// Input file
+        #[arg(long, value_parser, default_value = "-")]
+        input: Input,
+    },
+    /// List values in order of name
+    List {
+        /// Only list values whose names start with the prefix e.g. "user:42:"
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Only list values after the name, which is the last name of the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Maximum number of values
+        #[arg(long)]
+        limit: Option<usize>,
+    },
     /// Migrate the store
     Migrate {
         /// Target version. Specify 0 to revert ALL migrations. Omit to migrate to the latest
@@ -201,6 +382,9 @@ enum StoreCommands {
         /// Consider value as plain string instead of JSON value
         #[arg(long)]
         plain: bool,
+        /// Seconds before the value expires. Omit to keep the value forever
+        #[arg(long)]
+        ttl: Option<u64>,
         /// Value, the content should be a valid JSON value e.g. true or "string" or 1
         #[arg(long, value_parser, default_value = "-")]
         value: Input,
@@ -209,6 +393,41 @@ enum StoreCommands {
     Version,
 }
 
+fn export_store(store: &Store, format: StoreFormat, mut output: Output) -> anyhow::Result<()> {
+    for record in store.export()? {
+        match format {
+            StoreFormat::Json => {
+                serde_json::to_writer(&mut output, &record)?;
+                writeln!(output)?;
+            }
+            StoreFormat::Msgpack => rmp_serde::encode::write_named(&mut output, &record)?,
+        }
+    }
+    output.finish()?;
+    Ok(())
+}
+
+fn read_store_records(format: StoreFormat, input: Input) -> anyhow::Result<Vec<StoreRecord>> {
+    let mut reader = io::BufReader::new(input);
+    let mut records = vec![];
+    match format {
+        StoreFormat::Json => {
+            for line in reader.lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    records.push(serde_json::from_str(&line)?);
+                }
+            }
+        }
+        StoreFormat::Msgpack => {
+            while !reader.fill_buf()?.is_empty() {
+                records.push(rmp_serde::from_read(&mut reader)?);
+            }
+        }
+    }
+    Ok(records)
+}
+
 fn do_check_syntax<S>(no_color: bool, name: S, script: S) -> anyhow::Result<()>
 where
     S: Display,
@@ -222,6 +441,151 @@ where
     Ok(())
 }
 
+fn do_evaluate_record(
+    e: &Arc<Evaluation<Cursor<String>>>,
+    line: &str,
+    each_json: bool,
+) -> lmb::Result<Solution<Cursor<String>>> {
+    let record = if each_json {
+        serde_json::from_str(line)?
+    } else {
+        Value::String(line.to_string())
+    };
+    e.set_input(Cursor::new(line.to_string()));
+    let state = Arc::new(State::new());
+    state.insert(StateKey::Record, record);
+    e.evaluate_with_state(state)
+}
+
+fn do_evaluate_each<B>(
+    e: &Arc<Evaluation<Cursor<String>>>,
+    reader: B,
+    each_json: bool,
+    on_error: OnError,
+    json: bool,
+    no_color: bool,
+) -> anyhow::Result<()>
+where
+    B: BufRead,
+{
+    let mut stdout = io::stdout().lock();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = idx + 1;
+        match do_evaluate_record(e, &line, each_json) {
+            Ok(s) => {
+                // one line per record, so only the first returned value is written
+                let mut buf = String::new();
+                s.write_payload(&mut buf, json)?;
+                writeln!(stdout, "{buf}")?;
+            }
+            Err(err) => match on_error {
+                OnError::Emit => {
+                    // the first line of the error, without the stack traceback
+                    let message = err.to_string();
+                    let message = message.lines().next().unwrap_or_default();
+                    let record = json!({ "error": message, "line": line_number });
+                    writeln!(stdout, "{record}")?;
+                }
+                OnError::Skip => warn!(line = line_number, %err, "skip record"),
+                OnError::Stop => {
+                    error!(line = line_number, "failed to evaluate record");
+                    let mut buf = String::new();
+                    err.write_lua_error(&mut buf, e, no_color)?;
+                    eprint!("{buf}");
+                    return Err(err.into());
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn do_evaluate(e: &Arc<Evaluation<Stdin>>, json: bool, no_color: bool) -> anyhow::Result<()> {
+    let mut buf = String::new();
+    match e.evaluate() {
+        Ok(s) => {
+            // binary string is written as it is
+            s.write_bytes(io::stdout().lock(), json)?;
+            Ok(())
+        }
+        Err(err) => {
+            err.write_lua_error(&mut buf, e, no_color)?;
+            eprint!("{buf}");
+            Err(err.into())
+        }
+    }
+}
+
+fn do_repl(e: &Arc<Evaluation<Empty>>, json: bool, no_color: bool) -> anyhow::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let mut statement = String::new();
+    loop {
+        let prompt = if statement.is_empty() { "> " } else { ">> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                statement.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        statement.push_str(&line);
+        statement.push('\n');
+
+        // evaluate the statement as an expression first, so the value is printed
+        let expression = e.with_script(format!("return {statement}"));
+        let (evaluation, res) = match expression.evaluate() {
+            Err(Error::Lua(LuaError::SyntaxError { .. })) => {
+                let evaluation = e.with_script(&statement);
+                let res = evaluation.evaluate();
+                (evaluation, res)
+            }
+            res => (expression, res),
+        };
+        if let Err(Error::Lua(LuaError::SyntaxError {
+            incomplete_input: true,
+            ..
+        })) = res
+        {
+            continue;
+        }
+
+        editor.add_history_entry(statement.trim_end())?;
+        statement.clear();
+        let mut buf = String::new();
+        match res {
+            Ok(s) if s.payloads().is_empty() => {}
+            Ok(s) => {
+                s.write(&mut buf, json)?;
+                println!("{buf}");
+            }
+            Err(err @ Error::Lua(_)) => {
+                err.write_lua_error(&mut buf, &evaluation, no_color)?;
+                eprint!("{buf}");
+            }
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+}
+
+/// Check whether the error is already rendered with [`Error::write_lua_error`].
+fn is_handled(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<Error>() {
+        Some(&Error::Lua(LuaError::RuntimeError(_) | LuaError::SyntaxError { .. })) => true,
+        // e.g. syntax error in a required module
+        Some(Error::Lua(LuaError::CallbackError { cause, .. })) => matches!(
+            cause.as_ref(),
+            LuaError::RuntimeError(_) | LuaError::SyntaxError { .. }
+        ),
+        _ => false,
+    }
+}
+
 fn read_script(input: &mut Input) -> anyhow::Result<(String, String)> {
     let name = input.path().to_string_lossy().to_string();
     let mut script = String::new();
@@ -229,6 +593,30 @@ fn read_script(input: &mut Input) -> anyhow::Result<(String, String)> {
     Ok((name, script))
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+}
+
 fn prepare_store(options: &StoreOptions) -> anyhow::Result<Store> {
     let store = if let Some(store_path) = options.store_path() {
         let store = Store::new(store_path)?;
@@ -242,6 +630,44 @@ fn prepare_store(options: &StoreOptions) -> anyhow::Result<Store> {
     Ok(store)
 }
 
+/// Purge expired values from the store periodically.
+async fn purge_expired(store: Store) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let store = store.clone();
+        match tokio::task::spawn_blocking(move || store.purge_expired()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => warn!(%err, "failed to purge expired values"),
+            Err(err) => error!(%err, "failed to purge expired values"),
+        }
+    }
+}
+
+fn prepare_context(
+    envs: &[String],
+    env_file: Option<&PathBuf>,
+    args: Vec<String>,
+) -> anyhow::Result<Context> {
+    let mut vars = HashMap::new();
+    if let Some(env_file) = env_file {
+        for item in dotenvy::from_path_iter(env_file)? {
+            let (name, value) = item?;
+            vars.insert(name, value);
+        }
+    }
+    for env in envs {
+        if let Some((name, value)) = env.split_once('=') {
+            vars.insert(name.to_string(), value.to_string());
+        } else if let Ok(value) = env::var(env) {
+            vars.insert(env.clone(), value);
+        }
+    }
+    let mut context = Context::new();
+    context.set_args(args).set_env(vars);
+    Ok(context)
+}
+
 async fn try_main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
@@ -280,29 +706,75 @@ async fn try_main() -> anyhow::Result<()> {
             let (name, script) = read_script(&mut file)?;
             do_check_syntax(cli.no_color, &name, &script)
         }
-        Commands::Evaluate { mut file, timeout } => {
+        Commands::Evaluate {
+            args,
+            each_json,
+            each_line,
+            mut file,
+            memory_limit,
+            on_error,
+            timeout,
+            watch,
+        } => {
+            if (each_json || each_line) && file.is_std() {
+                bail!("the script must be loaded from a file in batch mode");
+            }
+            if watch && file.is_std() {
+                bail!("the script must be loaded from a file to watch");
+            }
             let (name, script) = read_script(&mut file)?;
             if cli.check_syntax {
                 do_check_syntax(cli.no_color, &name, &script)?;
             }
+            let context = prepare_context(&cli.envs, cli.env_file.as_ref(), args)?;
             let store = prepare_store(&store_options)?;
-            let e = EvaluationBuilder::new(&script, io::stdin())
-                .name(&name)
-                .store(store)
-                .timeout(Some(Duration::from_secs(timeout)))
-                .build();
-            let mut buf = String::new();
-            match e.evaluate() {
-                Ok(s) => {
-                    s.write(&mut buf, cli.json)?;
-                    print!("{buf}");
-                    Ok(())
-                }
-                Err(err) => {
-                    err.write_lua_error(&mut buf, &e, cli.no_color)?;
-                    eprint!("{buf}");
-                    Err(err.into())
+            if each_json || each_line {
+                let e = EvaluationBuilder::new(&script, Cursor::new(String::new()))
+                    .context(context)
+                    .memory_limit(memory_limit)
+                    .module_paths(cli.module_path)
+                    .name(&name)
+                    .store(store)
+                    .timeout(Some(Duration::from_secs(timeout)))
+                    .build();
+                let stdin = io::stdin().lock();
+                return do_evaluate_each(&e, stdin, each_json, on_error, cli.json, cli.no_color);
+            }
+            let build = |script: &str| {
+                EvaluationBuilder::new(script, io::stdin())
+                    .context(context.clone())
+                    .memory_limit(memory_limit)
+                    .module_paths(cli.module_path.clone())
+                    .name(&name)
+                    .store(store.clone())
+                    .timeout(Some(Duration::from_secs(timeout)))
+                    .build()
+            };
+            if !watch {
+                return do_evaluate(&build(&script), cli.json, cli.no_color);
+            }
+            let path = file.path().to_path_buf();
+            let mut script = script;
+            loop {
+                let e = build(&script);
+                if let Err(err) = do_evaluate(&e, cli.json, cli.no_color) {
+                    if !is_handled(&err) {
+                        eprintln!("{err}");
+                    }
                 }
+                let watched = || iter::once(path.clone()).chain(e.module_files());
+                let mut snapshot = Snapshot::new(watched());
+                info!(?path, "watching for changes");
+                script = loop {
+                    thread::sleep(POLL_INTERVAL);
+                    if !snapshot.is_changed() {
+                        continue;
+                    }
+                    if let Some(script) = watch::reload(&path, &snapshot, cli.no_color) {
+                        break script;
+                    }
+                    snapshot = Snapshot::new(watched());
+                };
             }
         }
         Commands::Example(ExampleCommands::Cat { name }) => {
@@ -365,7 +837,7 @@ async fn try_main() -> anyhow::Result<()> {
             let mut options = ServeOptions::new(name.as_str(), found.script(), bind, store_options);
             options.set_json(cli.json);
             options.set_timeout(timeout);
-            serve::serve_file(&options).await?;
+            serve::serve_file(&options, shutdown_signal()).await?;
             Ok(())
         }
         Commands::Guide(GuideCommands::List) => {
@@ -393,40 +865,176 @@ async fn try_main() -> anyhow::Result<()> {
             }
             Ok(())
         }
+        Commands::Repl {
+            memory_limit,
+            timeout,
+        } => {
+            let context = prepare_context(&cli.envs, cli.env_file.as_ref(), vec![])?;
+            let store = prepare_store(&store_options)?;
+            let e = EvaluationBuilder::new("", io::empty())
+                .context(context)
+                .memory_limit(memory_limit)
+                .module_paths(cli.module_path)
+                .name("repl")
+                .store(store)
+                .timeout(Some(Duration::from_secs(timeout)))
+                .build();
+            do_repl(&e, cli.json, cli.no_color)
+        }
         Commands::Schedule {
             bail,
             cron,
             mut file,
             initial_run,
+            memory_limit,
+            metrics_bind,
+            watch,
         } => {
+            if watch && file.is_std() {
+                bail!("the script must be loaded from a file to watch");
+            }
             let (name, script) = read_script(&mut file)?;
             let schedule = Schedule::from_str(&cron)?;
+            let context = prepare_context(&cli.envs, cli.env_file.as_ref(), vec![])?;
             let store = prepare_store(&store_options)?;
+            tokio::spawn(purge_expired(store.clone()));
+
+            let metrics = Metrics::new();
+            if let Some(bind) = metrics_bind {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve::serve_metrics(bind, metrics).await {
+                        error!(%err, "failed to serve metrics");
+                    }
+                });
+            }
 
-            let mut options = ScheduleOptions::new(schedule);
-            options.set_bail(bail);
-            options.set_initial_run(initial_run);
+            let shutdown = CancellationToken::new();
+            tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown_signal().await;
+                    info!("shutting down, stop the schedule");
+                    shutdown.cancel();
+                }
+            });
 
-            let e = EvaluationBuilder::new(script, io::stdin())
-                .name(name)
-                .store(store)
-                .build();
-            e.schedule(&options);
-            Ok(())
+            let path = file.path().to_path_buf();
+            let mut script = script;
+            loop {
+                let e = EvaluationBuilder::new(&script, io::stdin())
+                    .context(context.clone())
+                    .memory_limit(memory_limit)
+                    .metrics(metrics.clone())
+                    .module_paths(cli.module_path.clone())
+                    .name(&name)
+                    .store(store.clone())
+                    .build();
+
+                // the schedule is stopped on shutdown, or when the script is changed
+                let token = CancellationToken::new();
+                let watcher = thread::spawn({
+                    let e = e.clone();
+                    let path = path.clone();
+                    let token = token.clone();
+                    let shutdown = shutdown.clone();
+                    let no_color = cli.no_color;
+                    move || {
+                        let watched = || iter::once(path.clone()).chain(e.module_files());
+                        let mut snapshot = Snapshot::new(watched());
+                        loop {
+                            if shutdown.wait_timeout(POLL_INTERVAL) || token.is_cancelled() {
+                                token.cancel();
+                                return None;
+                            }
+                            snapshot.extend(e.module_files());
+                            if !watch || !snapshot.is_changed() {
+                                continue;
+                            }
+                            if let Some(script) = watch::reload(&path, &snapshot, no_color) {
+                                token.cancel();
+                                return Some(script);
+                            }
+                            snapshot = Snapshot::new(watched());
+                        }
+                    }
+                });
+
+                let mut options = ScheduleOptions::new(schedule.clone());
+                options.set_bail(bail);
+                options.set_cancellation_token(token.clone());
+                options.set_initial_run(initial_run);
+                e.schedule(&options);
+
+                // stop the watcher when the schedule bails
+                token.cancel();
+                match watcher.join() {
+                    Ok(Some(reloaded)) => script = reloaded,
+                    _ => return Ok(()),
+                }
+            }
         }
         Commands::Serve {
             bind,
+            compression,
+            drain_timeout,
+            error_format,
+            error_script,
             mut file,
+            memory_limit,
+            metrics,
+            middleware,
+            pool_size,
+            routes,
             timeout,
+            tls_cert,
+            tls_key,
+            unix_socket,
+            watch,
         } => {
-            let (name, script) = read_script(&mut file)?;
-            if cli.check_syntax {
-                do_check_syntax(cli.no_color, &name, &script)?;
-            }
+            let (name, script, routes) = if let Some(path) = routes {
+                let routes = load_routes(&path)?;
+                if cli.check_syntax {
+                    for route in &routes {
+                        let script = fs::read_to_string(&route.file)?;
+                        let name = route.file.to_string_lossy();
+                        do_check_syntax(cli.no_color, name.as_ref(), script.as_str())?;
+                    }
+                }
+                (path.to_string_lossy().to_string(), String::new(), routes)
+            } else {
+                if watch && file.is_std() {
+                    bail!("the script must be loaded from a file to watch");
+                }
+                let (name, script) = read_script(&mut file)?;
+                if cli.check_syntax {
+                    do_check_syntax(cli.no_color, &name, &script)?;
+                }
+                (name, script, vec![])
+            };
+            let context = prepare_context(&cli.envs, cli.env_file.as_ref(), vec![])?;
             let timeout = timeout.map(Duration::from_secs);
             let mut options = ServeOptions::new(name, script, bind, store_options);
+            options.set_compression(compression);
+            options.set_context(context);
+            options.set_drain_timeout(Duration::from_secs(drain_timeout));
+            options.set_error_format(error_format);
+            options.set_error_script(error_script);
+            options.set_memory_limit(memory_limit);
+            options.set_metrics(metrics);
+            options.set_middleware(middleware);
+            options.set_module_paths(cli.module_path);
+            options.set_pool_size(pool_size);
+            options.set_routes(routes);
             options.set_timeout(timeout);
-            serve::serve_file(&options).await?;
+            options.set_tls(
+                tls_cert
+                    .zip(tls_key)
+                    .map(|(cert, key)| TlsFiles { cert, key }),
+            );
+            options.set_unix_socket(unix_socket);
+            options.set_watch(watch.then(|| file.path().to_path_buf()));
+            serve::serve_file(&options, shutdown_signal()).await?;
             Ok(())
         }
         Commands::Store(c) => {
@@ -438,22 +1046,53 @@ async fn try_main() -> anyhow::Result<()> {
                 store.migrate(None)?;
             }
             match c {
+                StoreCommands::Backup { to } => {
+                    if to.exists() {
+                        bail!("backup already exists: {}", to.display());
+                    }
+                    SqliteBackend::new(store_path)?.backup(&to)?;
+                    Ok(())
+                }
                 StoreCommands::Delete { name } => {
                     let affected = store.delete(name)?;
                     print!("{affected}");
                     Ok(())
                 }
+                StoreCommands::Export { format, output } => export_store(&store, format, output),
                 StoreCommands::Get { name } => {
                     let value = store.get(name)?;
                     let value = serde_json::to_string(&value)?;
                     print!("{value}");
                     Ok(())
                 }
-                StoreCommands::List => {
-                    let metadata_rows = store.list()?;
+                StoreCommands::Import {
+                    format,
+                    mode,
+                    input,
+                } => {
+                    let records = read_store_records(format, input)?;
+                    let overwrite = matches!(mode, ImportMode::Overwrite);
+                    let affected = store.import(&records, overwrite)?;
+                    print!("{affected}");
+                    Ok(())
+                }
+                StoreCommands::List {
+                    prefix,
+                    cursor,
+                    limit,
+                } => {
+                    let limit = limit.unwrap_or(usize::MAX);
+                    let metadata_rows = store.list_prefix(&prefix, cursor.as_deref(), limit)?;
                     let mut table = Table::new();
                     table.load_preset(presets::NOTHING);
-                    table.set_header(["name", "type", "size", "created at", "updated at"]);
+                    table.set_header([
+                        "name",
+                        "type",
+                        "size",
+                        "created at",
+                        "updated at",
+                        "expires at",
+                    ]);
                     for m in metadata_rows.iter() {
                         table.add_row([
                             m.name(),
@@ -461,6 +1100,7 @@ async fn try_main() -> anyhow::Result<()> {
                             &m.size().to_string(),
                             &m.created_at().to_rfc3339(),
                             &m.updated_at().to_rfc3339(),
+                            &m.expires_at().map(|t| t.to_rfc3339()).unwrap_or_default(),
                         ]);
                     }
                     println!("{table}");
@@ -473,6 +1113,7 @@ async fn try_main() -> anyhow::Result<()> {
                 StoreCommands::Put {
                     name,
                     plain,
+                    ttl,
                     mut value,
                 } => {
                     let mut buf = String::new();
@@ -482,7 +1123,10 @@ async fn try_main() -> anyhow::Result<()> {
                     } else {
                         serde_json::from_str(&buf)?
                     };
-                    let affected = store.put(name, &value)?;
+                    let affected = match ttl {
+                        Some(ttl) => store.put_with_ttl(name, &value, Duration::from_secs(ttl))?,
+                        None => store.put(name, &value)?,
+                    };
                     print!("{affected}");
                     Ok(())
                 }
@@ -499,10 +1143,8 @@ async fn try_main() -> anyhow::Result<()> {
 #[tokio::main]
 async fn main() -> ExitCode {
     if let Err(e) = try_main().await {
-        match e.downcast_ref::<Error>() {
-            // the following errors are handled, do nothing
-            Some(&Error::Lua(LuaError::RuntimeError(_) | LuaError::SyntaxError { .. })) => {}
-            _ => eprintln!("{e}"),
+        if !is_handled(&e) {
+            eprintln!("{e}");
         }
         return ExitCode::FAILURE;
     }
@@ -0,0 +1,283 @@
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Write as _},
+    sync::Arc,
+};
+
+/// Counter of failed evaluations.
+pub(crate) const EVALUATION_ERRORS: &str = "lmb_evaluation_errors_total";
+/// Histogram of evaluation durations in seconds.
+pub(crate) const EVALUATION_DURATION: &str = "lmb_evaluation_duration_seconds";
+/// Histogram of the maximum memory usage of evaluations in bytes.
+pub(crate) const EVALUATION_MEMORY: &str = "lmb_evaluation_memory_bytes";
+/// Counter of evaluations exceeding the timeout.
+pub(crate) const EVALUATION_TIMEOUTS: &str = "lmb_evaluation_timeouts_total";
+/// Counter of HTTP requests sent with `@lmb/http`.
+pub(crate) const HTTP_REQUESTS: &str = "lmb_http_requests_total";
+/// Counter of store operations.
+pub(crate) const STORE_OPERATIONS: &str = "lmb_store_operations_total";
+
+/// Prefix of built-in metrics, which custom counters can't use.
+pub(crate) const RESERVED_PREFIX: &str = "lmb_";
+
+/// Upper bounds of duration buckets in seconds.
+pub(crate) const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// Upper bounds of memory buckets in bytes, from 64 KiB to 256 MiB.
+pub(crate) const MEMORY_BUCKETS: &[f64] = &[
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+    67_108_864.0,
+    268_435_456.0,
+];
+
+const HELP: [(&str, &str); 6] = [
+    (EVALUATION_DURATION, "Duration of evaluations in seconds."),
+    (EVALUATION_ERRORS, "Number of failed evaluations."),
+    (
+        EVALUATION_MEMORY,
+        "Maximum memory usage of evaluations in bytes.",
+    ),
+    (
+        EVALUATION_TIMEOUTS,
+        "Number of evaluations exceeding the timeout.",
+    ),
+    (HTTP_REQUESTS, "Number of HTTP requests sent by scripts."),
+    (STORE_OPERATIONS, "Number of store operations by scripts."),
+];
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    count: u64,
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<String, BTreeMap<Labels, f64>>,
+    histograms: BTreeMap<String, BTreeMap<Labels, Histogram>>,
+}
+
+/// Metrics of evaluations, rendered in Prometheus text format.
+/// Clones share the same registry.
+///
+/// ```rust
+/// # use std::io::empty;
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let metrics = Metrics::new();
+/// let e = EvaluationBuilder::new("return 1", empty())
+///     .metrics(metrics.clone())
+///     .name("a")
+///     .build();
+/// e.evaluate()?;
+/// assert!(metrics.render().contains(r#"lmb_evaluation_duration_seconds_count{script="a"} 1"#));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    labels: Labels,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create metrics sharing the registry, with a label added to every metric.
+    pub fn with_label<K, V>(&self, name: K, value: V) -> Self
+    where
+        K: Display,
+        V: Display,
+    {
+        let mut labels = self.labels.clone();
+        labels.push((name.to_string(), value.to_string()));
+        Self {
+            labels,
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Increment a counter.
+    ///
+    /// ```rust
+    /// use lmb::*;
+    /// let metrics = Metrics::new();
+    /// metrics.increment("jobs_total", &[("queue", "a")], 2.0);
+    /// assert!(metrics.render().contains(r#"jobs_total{queue="a"} 2"#));
+    /// ```
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels = self.labels(labels);
+        let mut registry = self.registry.lock();
+        let counter = registry
+            .counters
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels)
+            .or_default();
+        *counter += value;
+    }
+
+    pub(crate) fn observe(&self, name: &str, buckets: &'static [f64], value: f64) {
+        let labels = self.labels(&[]);
+        let mut registry = self.registry.lock();
+        let histogram = registry
+            .histograms
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels)
+            .or_insert_with(|| Histogram {
+                buckets,
+                count: 0,
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+            });
+        for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += value;
+    }
+
+    /// Render metrics in Prometheus text format.
+    ///
+    /// ref: <https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format>
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock();
+        let mut buf = String::new();
+        // writing to a string never fails
+        let _ = registry.write(&mut buf);
+        buf
+    }
+
+    fn labels(&self, labels: &[(&str, &str)]) -> Labels {
+        let mut merged = self.labels.clone();
+        for (name, value) in labels {
+            if !merged.iter().any(|(n, _)| n == name) {
+                merged.push(((*name).to_string(), (*value).to_string()));
+            }
+        }
+        merged.sort();
+        merged
+    }
+}
+
+impl Registry {
+    fn write(&self, f: &mut String) -> fmt::Result {
+        for (name, series) in &self.counters {
+            write_header(f, name, "counter")?;
+            for (labels, value) in series {
+                writeln!(f, "{name}{} {value}", LabelSet(labels, None))?;
+            }
+        }
+        for (name, series) in &self.histograms {
+            write_header(f, name, "histogram")?;
+            for (labels, h) in series {
+                for (bound, count) in h.buckets.iter().zip(&h.counts) {
+                    let le = bound.to_string();
+                    writeln!(f, "{name}_bucket{} {count}", LabelSet(labels, Some(&le)))?;
+                }
+                let count = h.count;
+                writeln!(f, "{name}_bucket{} {count}", LabelSet(labels, Some("+Inf")))?;
+                writeln!(f, "{name}_sum{} {}", LabelSet(labels, None), h.sum)?;
+                writeln!(f, "{name}_count{} {count}", LabelSet(labels, None))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_header(f: &mut String, name: &str, kind: &str) -> fmt::Result {
+    if let Some((_, help)) = HELP.iter().find(|(n, _)| *n == name) {
+        writeln!(f, "# HELP {name} {help}")?;
+    }
+    writeln!(f, "# TYPE {name} {kind}")
+}
+
+/// Labels with an optional `le` label of a histogram bucket.
+struct LabelSet<'a>(&'a Labels, Option<&'a str>);
+
+impl Display for LabelSet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let le = self.1.map(|le| ("le", le));
+        let labels = self
+            .0
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .chain(le)
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            return Ok(());
+        }
+        f.write_char('{')?;
+        for (idx, (name, value)) in labels.into_iter().enumerate() {
+            if idx > 0 {
+                f.write_char(',')?;
+            }
+            write!(f, "{name}=\"")?;
+            for c in value.chars() {
+                match c {
+                    '\\' => f.write_str("\\\\")?,
+                    '"' => f.write_str("\\\"")?,
+                    '\n' => f.write_str("\\n")?,
+                    c => f.write_char(c)?,
+                }
+            }
+            f.write_char('"')?;
+        }
+        f.write_char('}')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, DURATION_BUCKETS};
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new().with_label("script", "a\"b");
+        metrics.increment("jobs_total", &[], 1.0);
+        metrics.increment("jobs_total", &[], 2.5);
+        metrics.observe("duration_seconds", &DURATION_BUCKETS[..2], 0.003);
+        let expected = concat!(
+            "# TYPE jobs_total counter\n",
+            "jobs_total{script=\"a\\\"b\"} 3.5\n",
+            "# TYPE duration_seconds histogram\n",
+            "duration_seconds_bucket{script=\"a\\\"b\",le=\"0.001\"} 0\n",
+            "duration_seconds_bucket{script=\"a\\\"b\",le=\"0.005\"} 1\n",
+            "duration_seconds_bucket{script=\"a\\\"b\",le=\"+Inf\"} 1\n",
+            "duration_seconds_sum{script=\"a\\\"b\"} 0.003\n",
+            "duration_seconds_count{script=\"a\\\"b\"} 1\n",
+        );
+        assert_eq!(expected, metrics.render());
+    }
+
+    #[test]
+    fn shared_registry() {
+        let metrics = Metrics::new();
+        metrics
+            .with_label("script", "a")
+            .increment("jobs_total", &[], 1.0);
+        metrics
+            .with_label("script", "b")
+            .increment("jobs_total", &[], 1.0);
+        let rendered = metrics.render();
+        assert!(rendered.contains("jobs_total{script=\"a\"} 1\n"));
+        assert!(rendered.contains("jobs_total{script=\"b\"} 1\n"));
+    }
+}
@@ -0,0 +1,403 @@
+use anyhow::Context as _;
+use axum::{
+    extract::{ConnectInfo, DefaultBodyLimit, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use clap::Args;
+use dashmap::DashMap;
+use http::{header, HeaderValue, StatusCode};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+/// Number of clients tracked by the rate limiter before idle clients are forgotten.
+const RATE_LIMIT_PRUNE_SIZE: usize = 10_000;
+
+/// Middleware rejecting requests before they are handled by the script.
+#[derive(Args, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Middleware {
+    /// Secrets file of bearer tokens and basic auth users.
+    /// Requests without valid credentials are rejected
+    #[arg(long)]
+    pub auth: Option<PathBuf>,
+    /// Maximum size of the request body in bytes
+    #[arg(long)]
+    pub body_limit: Option<usize>,
+    /// Allowed origin of cross-origin requests.
+    /// Repeat for multiple origins, or specify "*" to allow any origin
+    #[arg(long = "cors-origin")]
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Maximum number of requests handled at the same time. Requests over the limit are rejected
+    #[arg(long)]
+    pub max_concurrency: Option<usize>,
+    /// Maximum number of requests per second from each client IP address.
+    /// Not supported on a Unix domain socket, where the address is unknown
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+}
+
+impl Middleware {
+    /// Fill the fields absent from this middleware with those of the other.
+    pub fn or(self, other: &Self) -> Self {
+        Self {
+            auth: self.auth.or_else(|| other.auth.clone()),
+            body_limit: self.body_limit.or(other.body_limit),
+            cors_origins: if self.cors_origins.is_empty() {
+                other.cors_origins.clone()
+            } else {
+                self.cors_origins
+            },
+            max_concurrency: self.max_concurrency.or(other.max_concurrency),
+            rate_limit: self.rate_limit.or(other.rate_limit),
+        }
+    }
+
+    /// Build the layers, reading the secrets file.
+    /// Routes sharing the layers share the concurrency and rate limits.
+    pub fn build(&self) -> anyhow::Result<Layers> {
+        let auth = match &self.auth {
+            Some(path) => Some(Arc::new(Secrets::load(path)?)),
+            None => None,
+        };
+        let cors = if self.cors_origins.is_empty() {
+            None
+        } else {
+            let origin = if self.cors_origins.iter().any(|o| o == "*") {
+                AllowOrigin::any()
+            } else {
+                let origins = self
+                    .cors_origins
+                    .iter()
+                    .map(|o| HeaderValue::from_str(o))
+                    .collect::<Result<Vec<_>, _>>()?;
+                AllowOrigin::list(origins)
+            };
+            let layer = CorsLayer::new()
+                .allow_origin(origin)
+                .allow_methods(AllowMethods::mirror_request())
+                .allow_headers(AllowHeaders::mirror_request());
+            Some(layer)
+        };
+        Ok(Layers {
+            auth,
+            body_limit: self.body_limit,
+            concurrency: self.max_concurrency.map(|n| Arc::new(Semaphore::new(n))),
+            cors,
+            rate_limit: self.rate_limit.map(|rate| {
+                Arc::new(RateLimiter {
+                    buckets: DashMap::new(),
+                    pruned: Mutex::new(Instant::now()),
+                    rate: f64::from(rate),
+                })
+            }),
+        })
+    }
+}
+
+/// Layers built from [`Middleware`].
+#[derive(Clone, Default)]
+pub struct Layers {
+    auth: Option<Arc<Secrets>>,
+    body_limit: Option<usize>,
+    concurrency: Option<Arc<Semaphore>>,
+    cors: Option<CorsLayer>,
+    rate_limit: Option<Arc<RateLimiter>>,
+}
+
+impl Layers {
+    /// Wrap the handlers. From the outermost, CORS preflight requests are answered,
+    /// then rate limit, auth, concurrency limit, and body limit are checked in order.
+    pub fn apply<S>(&self, mut router: MethodRouter<S>) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        if let Some(limit) = self.body_limit {
+            router = router.layer(DefaultBodyLimit::max(limit));
+        }
+        if let Some(semaphore) = &self.concurrency {
+            router = router.layer(middleware::from_fn_with_state(
+                semaphore.clone(),
+                limit_concurrency,
+            ));
+        }
+        if let Some(secrets) = &self.auth {
+            router = router.layer(middleware::from_fn_with_state(secrets.clone(), authorize));
+        }
+        if let Some(limiter) = &self.rate_limit {
+            router = router.layer(middleware::from_fn_with_state(limiter.clone(), limit_rate));
+        }
+        if let Some(cors) = &self.cors {
+            router = router.layer(cors.clone());
+        }
+        router
+    }
+}
+
+/// Credentials accepted by the auth middleware.
+///
+/// ```toml
+/// tokens = ["secret-token"]
+///
+/// [users]
+/// alice = "password"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Secrets {
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    users: HashMap<String, String>,
+}
+
+impl Secrets {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    fn verify(&self, authorization: &[u8]) -> bool {
+        let Some((scheme, credentials)) = authorization
+            .iter()
+            .position(|b| *b == b' ')
+            .map(|idx| (&authorization[..idx], &authorization[idx + 1..]))
+        else {
+            return false;
+        };
+        if scheme.eq_ignore_ascii_case(b"bearer") {
+            return self
+                .tokens
+                .iter()
+                .any(|token| secure_eq(token.as_bytes(), credentials));
+        }
+        if scheme.eq_ignore_ascii_case(b"basic") {
+            let Some(decoded) = STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())
+            else {
+                return false;
+            };
+            let Some((user, password)) = decoded.split_once(':') else {
+                return false;
+            };
+            return self
+                .users
+                .get(user)
+                .is_some_and(|expected| secure_eq(expected.as_bytes(), password.as_bytes()));
+        }
+        false
+    }
+
+    fn challenge(&self) -> Vec<HeaderValue> {
+        let mut challenges = vec![];
+        if !self.tokens.is_empty() {
+            challenges.push(HeaderValue::from_static("Bearer"));
+        }
+        if !self.users.is_empty() {
+            challenges.push(HeaderValue::from_static("Basic realm=\"lmb\""));
+        }
+        challenges
+    }
+}
+
+/// Compare secrets in time independent of where they differ.
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn authorize(State(secrets): State<Arc<Secrets>>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .is_some_and(|value| secrets.verify(value.as_bytes()));
+    if authorized {
+        return next.run(req).await;
+    }
+    let mut res = StatusCode::UNAUTHORIZED.into_response();
+    for challenge in secrets.challenge() {
+        res.headers_mut()
+            .append(header::WWW_AUTHENTICATE, challenge);
+    }
+    res
+}
+
+async fn limit_concurrency(
+    State(semaphore): State<Arc<Semaphore>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // held until the response head is returned
+    let Ok(_permit) = semaphore.try_acquire_owned() else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    next.run(req).await
+}
+
+/// Token bucket per client, refilled at the rate per second up to the rate.
+struct RateLimiter {
+    buckets: DashMap<IpAddr, Bucket>,
+    pruned: Mutex<Instant>,
+    rate: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn acquire(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() > RATE_LIMIT_PRUNE_SIZE {
+            self.prune(now);
+        }
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.rate,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forget idle clients, at most once per second.
+    fn prune(&self, now: Instant) {
+        let Some(mut pruned) = self.pruned.try_lock() else {
+            return;
+        };
+        if now.duration_since(*pruned) < Duration::from_secs(1) {
+            return;
+        }
+        *pruned = now;
+        // a bucket idle for a second is full, which is the same as an absent one
+        self.buckets
+            .retain(|_, b| now.duration_since(b.updated) < Duration::from_secs(1));
+    }
+}
+
+async fn limit_rate(State(limiter): State<Arc<RateLimiter>>, req: Request, next: Next) -> Response {
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ConnectInfo(addr)| {
+            addr.ip()
+        });
+    if !limiter.acquire(ip) {
+        let retry_after = [(header::RETRY_AFTER, "1")];
+        return (StatusCode::TOO_MANY_REQUESTS, retry_after).into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Middleware, RateLimiter, Secrets};
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use dashmap::DashMap;
+    use maplit::hashmap;
+    use parking_lot::Mutex;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn middleware_or() {
+        let route = Middleware {
+            body_limit: Some(1),
+            ..Default::default()
+        };
+        let global = Middleware {
+            body_limit: Some(2),
+            cors_origins: vec!["*".to_string()],
+            rate_limit: Some(3),
+            ..Default::default()
+        };
+        let expected = Middleware {
+            body_limit: Some(1),
+            cors_origins: vec!["*".to_string()],
+            rate_limit: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(expected, route.or(&global));
+    }
+
+    #[test]
+    fn secrets_verify() {
+        let secrets = Secrets {
+            tokens: vec!["token".to_string()],
+            users: hashmap! { "alice".to_string() => "password".to_string() },
+        };
+        assert!(secrets.verify(b"Bearer token"));
+        assert!(secrets.verify(b"bearer token"));
+        assert!(!secrets.verify(b"Bearer tokens"));
+        assert!(!secrets.verify(b"token"));
+
+        let basic = format!("Basic {}", STANDARD.encode("alice:password"));
+        assert!(secrets.verify(basic.as_bytes()));
+        let basic = format!("Basic {}", STANDARD.encode("alice:wrong"));
+        assert!(!secrets.verify(basic.as_bytes()));
+        let basic = format!("Basic {}", STANDARD.encode("bob:password"));
+        assert!(!secrets.verify(basic.as_bytes()));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter {
+            buckets: DashMap::new(),
+            pruned: Mutex::new(Instant::now()),
+            rate: 2.0,
+        };
+        let a = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        assert!(limiter.acquire(a));
+        assert!(limiter.acquire(a));
+        assert!(!limiter.acquire(a));
+        assert!(limiter.acquire(b));
+    }
+
+    #[test]
+    fn rate_limiter_prune() {
+        let now = Instant::now();
+        let limiter = RateLimiter {
+            buckets: DashMap::new(),
+            pruned: Mutex::new(now),
+            rate: 1.0,
+        };
+        let a = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        assert!(limiter.acquire(a));
+        let later = now + Duration::from_secs(2);
+
+        // pruned at most once per second
+        limiter.prune(now + Duration::from_millis(500));
+        assert_eq!(1, limiter.buckets.len());
+        limiter.prune(later);
+        assert!(limiter.buckets.is_empty());
+
+        assert!(limiter.acquire(a));
+        limiter.buckets.get_mut(&a).unwrap().updated = now;
+        limiter.prune(later + Duration::from_millis(500));
+        assert_eq!(1, limiter.buckets.len());
+    }
+}
@@ -0,0 +1,313 @@
+use parking_lot::{Condvar, Mutex};
+use std::{io::Read, ops::Deref, path::PathBuf, sync::Arc};
+use tokio::task;
+use tracing::{trace, trace_span, warn};
+
+use crate::{Evaluation, EvaluationBuilder, Result, Solution, State};
+
+/// Bounded pool of pre-built [`Evaluation`]s.
+///
+/// Building an evaluation creates a virtual machine, registers the binding,
+/// enables the sandbox and compiles the script. The pool does it once per
+/// evaluation, and resets the sandbox and unloads required modules when the
+/// evaluation is returned.
+#[derive(Debug)]
+pub struct EvaluationPool<R>
+where
+    for<'lua> R: 'lua + Read,
+{
+    builder: EvaluationBuilder<R>,
+    condvar: Condvar,
+    slots: Mutex<Slots<R>>,
+    size: usize,
+}
+
+#[derive(Debug)]
+struct Slots<R>
+where
+    for<'lua> R: 'lua + Read,
+{
+    evaluations: Vec<Arc<Evaluation<R>>>,
+    // evaluations failed to reset, which are rebuilt when checked out
+    missing: usize,
+}
+
+impl<R> EvaluationPool<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    pub(crate) fn new(builder: EvaluationBuilder<R>, size: usize) -> Arc<Self> {
+        let size = size.max(1);
+        let evaluations = {
+            let _s = trace_span!("warm_up_pool", size).entered();
+            (0..size)
+                .map(|_| Arc::new(builder.build_pooled()))
+                .collect()
+        };
+        Arc::new(Self {
+            builder,
+            condvar: Condvar::new(),
+            slots: Mutex::new(Slots {
+                evaluations,
+                missing: 0,
+            }),
+            size,
+        })
+    }
+
+    /// Check out an evaluation. Block until one is available when all evaluations are in use.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let pool = EvaluationBuilder::new("return 1+1", empty()).build_pool(1);
+    /// let e = pool.get();
+    /// let res = e.evaluate()?;
+    /// assert_eq!(&json!(2), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get(self: &Arc<Self>) -> PooledEvaluation<R> {
+        let mut slots = self.slots.lock();
+        let evaluation = loop {
+            if let Some(e) = slots.evaluations.pop() {
+                trace!(available = slots.evaluations.len(), "check out evaluation");
+                break e;
+            }
+            if slots.missing > 0 {
+                slots.missing -= 1;
+                drop(slots);
+                trace!("rebuild evaluation");
+                break Arc::new(self.builder.build_pooled());
+            }
+            self.condvar.wait(&mut slots);
+        };
+        PooledEvaluation {
+            checkout: Arc::new(Checkout {
+                evaluation: Some(evaluation),
+                pool: self.clone(),
+            }),
+        }
+    }
+
+    /// Check out an evaluation on the blocking thread pool of [`tokio`],
+    /// so waiting for an available evaluation doesn't block the asynchronous runtime.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<()> {
+    /// let pool = EvaluationBuilder::new("return 1+1", empty()).build_pool(1);
+    /// let e = pool.get_async().await?;
+    /// let res = e.evaluate_async(None).await?;
+    /// assert_eq!(&json!(2), res.payload());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_async(self: &Arc<Self>) -> Result<PooledEvaluation<R>> {
+        let this = self.clone();
+        Ok(task::spawn_blocking(move || this.get()).await?)
+    }
+
+    /// Get paths of modules required by evaluations in the pool so far.
+    pub fn module_files(&self) -> Vec<PathBuf> {
+        self.builder.module_files()
+    }
+
+    /// Get the number of evaluations in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn release(&self, e: Arc<Evaluation<R>>) {
+        e.set_input(R::default());
+        e.set_output(None);
+        e.set_websocket(None);
+        let res = e.reset();
+        let mut slots = self.slots.lock();
+        match res {
+            Ok(()) => {
+                slots.evaluations.push(e);
+                trace!(available = slots.evaluations.len(), "check in evaluation");
+            }
+            Err(err) => {
+                warn!(?err, "failed to reset evaluation, rebuild it on checkout");
+                slots.missing += 1;
+            }
+        }
+        self.condvar.notify_one();
+    }
+}
+
+/// Evaluation checked out from an [`EvaluationPool`]. It is returned to the pool when dropped,
+/// or when the evaluation on the blocking thread pool ends if it's still running.
+#[derive(Debug)]
+pub struct PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    checkout: Arc<Checkout<R>>,
+}
+
+#[derive(Debug)]
+struct Checkout<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    evaluation: Option<Arc<Evaluation<R>>>,
+    pool: Arc<EvaluationPool<R>>,
+}
+
+impl<R> PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    /// Evaluate the function, see [`Evaluation::evaluate`].
+    pub fn evaluate(&self) -> Result<Solution<R>> {
+        self.evaluation().evaluate()
+    }
+
+    /// Evaluate the function with a state, see [`Evaluation::evaluate_with_state`].
+    pub fn evaluate_with_state(&self, state: Arc<State>) -> Result<Solution<R>> {
+        self.evaluation().evaluate_with_state(state)
+    }
+
+    /// Evaluate the function on the blocking thread pool, see [`Evaluation::evaluate_async`].
+    /// The evaluation is returned to the pool after it ends, even when the future is dropped.
+    pub async fn evaluate_async(&self, state: Option<Arc<State>>) -> Result<Solution<R>> {
+        self.evaluation()
+            .evaluate_async_holding(state, self.checkout.clone())
+            .await
+    }
+
+    fn evaluation(&self) -> &Arc<Evaluation<R>> {
+        self.checkout
+            .evaluation
+            .as_ref()
+            .expect("evaluation has been returned to the pool")
+    }
+}
+
+impl<R> Deref for PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    type Target = Evaluation<R>;
+
+    fn deref(&self) -> &Self::Target {
+        self.evaluation()
+    }
+}
+
+impl<R> Drop for Checkout<R>
+where
+    for<'lua> R: 'lua + Default + Read + Send,
+{
+    fn drop(&mut self) {
+        if let Some(e) = self.evaluation.take() {
+            self.pool.release(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::{io::Cursor, sync::Arc, thread, time::Duration};
+
+    use crate::{EvaluationBuilder, State, StateKey, Store};
+
+    #[test]
+    fn bounded() {
+        let pool = EvaluationBuilder::new("return 1", Cursor::new("")).build_pool(2);
+        assert_eq!(2, pool.size());
+
+        let mut threads = vec![];
+        for _ in 0..10 {
+            let pool = pool.clone();
+            threads.push(thread::spawn(move || {
+                let e = pool.get();
+                let res = e.evaluate().unwrap();
+                assert_eq!(&json!(1), res.payload());
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn reset_globals() {
+        let script = "x = (x or 0) + 1; return x";
+        let pool = EvaluationBuilder::new(script, Cursor::new("")).build_pool(1);
+        for _ in 0..3 {
+            let e = pool.get();
+            let res = e.evaluate().unwrap();
+            assert_eq!(&json!(1), res.payload());
+        }
+    }
+
+    #[test]
+    fn reset_input() {
+        let script = "return io.read('*a')";
+        let pool = EvaluationBuilder::new(script, Cursor::new("")).build_pool(1);
+        {
+            let e = pool.get();
+            e.set_input(Cursor::new("1"));
+            let res = e.evaluate().unwrap();
+            assert_eq!(&json!("1"), res.payload());
+        }
+        let e = pool.get();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!(null), res.payload());
+    }
+
+    #[test]
+    fn reset_state() {
+        let script = "return require('@lmb').request";
+        let pool = EvaluationBuilder::new(script, Cursor::new("")).build_pool(1);
+        {
+            let state = Arc::new(State::new());
+            state.insert(StateKey::Request, 1.into());
+            let e = pool.get();
+            let res = e.evaluate_with_state(state).unwrap();
+            assert_eq!(&json!(1), res.payload());
+        }
+        let e = pool.get();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!(null), res.payload());
+    }
+
+    #[tokio::test]
+    async fn release_after_cancelled() {
+        let script = "require('@lmb'):put('started', true); while true do end";
+        let store = Store::default();
+        let pool = EvaluationBuilder::new(script, Cursor::new(""))
+            .store(store.clone())
+            .build_pool(1);
+        let e = pool.get_async().await.unwrap();
+        let evaluation = Arc::as_ptr(e.evaluation());
+        {
+            let mut f = Box::pin(e.evaluate_async(None));
+            tokio::select! {
+                biased;
+                _ = &mut f => panic!("expect evaluation to be running"),
+                () = std::future::ready(()) => {}
+            }
+            while store.get("started").unwrap() != json!(true) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        drop(e);
+
+        // the same evaluation is returned to the pool once the cancelled evaluation ends
+        let e = pool.get_async().await.unwrap();
+        assert_eq!(evaluation, Arc::as_ptr(e.evaluation()));
+    }
+}
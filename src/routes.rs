@@ -0,0 +1,402 @@
+use crate::middleware::Middleware;
+use anyhow::{bail, Context as _};
+use http::Method;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr as _,
+    time::Duration,
+};
+
+const EXTENSIONS: [&str; 2] = ["lua", "luau"];
+
+/// Path prefix of built-in endpoints e.g. health checks, which routes can't use.
+pub const RESERVED_PREFIX: &str = "/_lmb";
+
+/// Path of the built-in health check.
+pub const HEALTH_PATH: &str = "/_lmb/health";
+
+/// Path of the built-in readiness check.
+pub const READY_PATH: &str = "/_lmb/ready";
+
+/// Route mapping a method and path pattern to a script.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Script path
+    pub file: PathBuf,
+    /// HTTP method. Any method is accepted when omitted
+    #[serde(default, with = "method")]
+    pub method: Option<Method>,
+    /// Middleware of the route, overriding the middleware of the server
+    #[serde(default)]
+    pub middleware: Middleware,
+    /// Path pattern e.g. `/users/:id` or `/files/*path`
+    pub path: String,
+    /// Timeout in seconds, overriding the timeout of the server
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+impl Route {
+    /// Get timeout.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoutesConfig {
+    routes: Vec<Route>,
+}
+
+/// Load routes from a TOML file, or a directory of scripts.
+pub fn load_routes(path: &Path) -> anyhow::Result<Vec<Route>> {
+    if path.is_dir() {
+        load_routes_dir(path)
+    } else {
+        load_routes_toml(path)
+    }
+}
+
+/// Load routes from a TOML file. Script and secrets paths are relative to the file.
+///
+/// ```toml
+/// [[routes]]
+/// method = "GET"
+/// path = "/users/:id"
+/// file = "users/show.lua"
+/// timeout = 5
+///
+/// [routes.middleware]
+/// auth = "secrets.toml"
+/// rate_limit = 10
+/// ```
+fn load_routes_toml(path: &Path) -> anyhow::Result<Vec<Route>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let config: RoutesConfig =
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let routes = config
+        .routes
+        .into_iter()
+        .map(|route| Route {
+            file: base.join(&route.file),
+            middleware: Middleware {
+                auth: route.middleware.auth.as_ref().map(|p| base.join(p)),
+                ..route.middleware
+            },
+            ..route
+        })
+        .collect();
+    validate(routes, path)
+}
+
+/// Load routes from a directory by convention e.g.
+/// `index.lua` is served at `/`, `users/[id].lua` at `/users/:id`,
+/// `files/[...path].lua` at `/files/*path`, and `users/index.post.lua` at `POST /users`.
+fn load_routes_dir(dir: &Path) -> anyhow::Result<Vec<Route>> {
+    let mut files = vec![];
+    collect_files(dir, &mut files)?;
+    files.sort();
+    let mut routes = vec![];
+    for file in files {
+        let relative = file.strip_prefix(dir)?;
+        let Some(stem) = relative.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        // the dots of a catch-all parameter e.g. `[...path]` are not a method suffix
+        let (stem, method) = match stem.rsplit_once('.').filter(|(_, m)| !m.ends_with(']')) {
+            Some((stem, method)) => (stem, Some(parse_method(method)?)),
+            None => (stem, None),
+        };
+        let mut segments = vec![];
+        if let Some(parent) = relative.parent() {
+            for component in parent.components() {
+                if let Component::Normal(s) = component {
+                    segments.push(segment(&s.to_string_lossy()));
+                }
+            }
+        }
+        if stem != "index" {
+            segments.push(segment(stem));
+        }
+        routes.push(Route {
+            file,
+            method,
+            middleware: Middleware::default(),
+            path: format!("/{}", segments.join("/")),
+            timeout: None,
+        });
+    }
+    validate(routes, dir)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXTENSIONS.contains(&e))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn parse_method(method: &str) -> anyhow::Result<Method> {
+    Ok(Method::from_str(&method.to_uppercase())?)
+}
+
+fn segment(name: &str) -> String {
+    match name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+        Some(param) => match param.strip_prefix("...") {
+            Some(param) => format!("*{param}"),
+            None => format!(":{param}"),
+        },
+        None => name.to_string(),
+    }
+}
+
+/// Reject routes that would overlap, which the router can't tell apart,
+/// routes under the reserved prefix, and paths the router can't add.
+fn validate(routes: Vec<Route>, source: &Path) -> anyhow::Result<Vec<Route>> {
+    for (idx, route) in routes.iter().enumerate() {
+        if !route.path.starts_with('/') {
+            bail!(
+                "path must start with `/` in {}: {} of {}",
+                source.display(),
+                route.path,
+                route.file.display()
+            );
+        }
+        let reserved = route
+            .path
+            .strip_prefix(RESERVED_PREFIX)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        if reserved {
+            bail!(
+                "{RESERVED_PREFIX} is reserved for built-in endpoints in {}: {}",
+                source.display(),
+                route.file.display()
+            );
+        }
+        for other in &routes[idx + 1..] {
+            if route.path != other.path {
+                continue;
+            }
+            if route.method.is_none() || other.method.is_none() || route.method == other.method {
+                bail!(
+                    "overlapping routes for {} in {}: {} and {}",
+                    route.path,
+                    source.display(),
+                    route.file.display(),
+                    other.file.display()
+                );
+            }
+        }
+    }
+    if let Err((route, err)) = check_paths(&[HEALTH_PATH, READY_PATH], &routes) {
+        bail!(
+            "conflicting route {} in {}: {err}, {}",
+            route.path,
+            source.display(),
+            route.file.display()
+        );
+    }
+    Ok(routes)
+}
+
+/// Add the paths to a router after the built-in paths, the way the server does, and return
+/// the first route the router rejects, e.g. `/users/:name` after `/users/:id`.
+pub fn check_paths<'a>(
+    builtins: &[&str],
+    routes: &'a [Route],
+) -> Result<(), (&'a Route, matchit::InsertError)> {
+    let mut router = matchit::Router::new();
+    for path in builtins {
+        // the built-in paths don't conflict with each other
+        let _ = router.insert(*path, ());
+    }
+    // routes of the same path with different methods share the path
+    let mut added = HashSet::new();
+    for route in routes {
+        if !added.insert(route.path.as_str()) {
+            continue;
+        }
+        router
+            .insert(route.path.as_str(), ())
+            .map_err(|err| (route, err))?;
+    }
+    Ok(())
+}
+
+mod method {
+    use http::Method;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Method>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let Some(method) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        super::parse_method(&method)
+            .map(Some)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use http::Method;
+
+    use super::load_routes;
+    use crate::middleware::Middleware;
+
+    #[test]
+    fn routes_dir() {
+        let dir = TempDir::new().unwrap();
+        for file in [
+            "index.lua",
+            "users/index.post.lua",
+            "users/[id].lua",
+            "files/[...path].luau",
+            "README.md",
+        ] {
+            dir.child(file).write_str("return 1").unwrap();
+        }
+        let routes = load_routes(dir.path()).unwrap();
+        let actual = routes
+            .iter()
+            .map(|r| (r.method.clone(), r.path.as_str()))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (None, "/files/*path"),
+            (None, "/"),
+            (None, "/users/:id"),
+            (Some(Method::POST), "/users"),
+        ];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn routes_toml() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.child("routes.toml");
+        config
+            .write_str(
+                r#"
+                [[routes]]
+                method = "get"
+                path = "/users/:id"
+                file = "users/show.lua"
+                timeout = 5
+
+                [routes.middleware]
+                auth = "secrets.toml"
+                rate_limit = 10
+
+                [[routes]]
+                method = "DELETE"
+                path = "/users/:id"
+                file = "users/delete.lua"
+                "#,
+            )
+            .unwrap();
+        let routes = load_routes(config.path()).unwrap();
+        assert_eq!(2, routes.len());
+        assert_eq!(Some(Method::GET), routes[0].method);
+        assert_eq!(dir.child("users/show.lua").path(), routes[0].file);
+        assert_eq!(Some(5), routes[0].timeout);
+        let middleware = &routes[0].middleware;
+        assert_eq!(
+            Some(dir.child("secrets.toml").to_path_buf()),
+            middleware.auth
+        );
+        assert_eq!(Some(10), middleware.rate_limit);
+        assert_eq!(Some(Method::DELETE), routes[1].method);
+        assert_eq!(Middleware::default(), routes[1].middleware);
+    }
+
+    #[test]
+    fn routes_overlapping() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.child("routes.toml");
+        config
+            .write_str(
+                r#"
+                [[routes]]
+                path = "/"
+                file = "a.lua"
+
+                [[routes]]
+                method = "GET"
+                path = "/"
+                file = "b.lua"
+                "#,
+            )
+            .unwrap();
+        let err = load_routes(config.path()).unwrap_err();
+        assert!(err.to_string().contains("overlapping routes"));
+    }
+
+    #[test]
+    fn routes_invalid_path() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.child("routes.toml");
+        config
+            .write_str(
+                r#"
+                [[routes]]
+                path = "users"
+                file = "a.lua"
+                "#,
+            )
+            .unwrap();
+        let err = load_routes(config.path()).unwrap_err().to_string();
+        assert!(err.contains("must start with `/`"), "{err}");
+        assert!(err.contains("routes.toml"), "{err}");
+    }
+
+    #[test]
+    fn routes_conflicting() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.child("routes.toml");
+        config
+            .write_str(
+                r#"
+                [[routes]]
+                path = "/users/:id"
+                file = "a.lua"
+
+                [[routes]]
+                method = "POST"
+                path = "/users/:name"
+                file = "b.lua"
+                "#,
+            )
+            .unwrap();
+        let err = load_routes(config.path()).unwrap_err().to_string();
+        assert!(err.contains("conflicting route /users/:name"), "{err}");
+        assert!(err.contains("routes.toml"), "{err}");
+    }
+
+    #[test]
+    fn routes_reserved() {
+        let dir = TempDir::new().unwrap();
+        dir.child("_lmb/health.lua").write_str("return 1").unwrap();
+        let err = load_routes(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("reserved"));
+    }
+}
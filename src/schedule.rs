@@ -1,11 +1,12 @@
 use cron::Schedule;
 
-use crate::Store;
+use crate::{CancellationToken, Store};
 
 /// Schedule options.
 #[derive(Debug)]
 pub struct ScheduleOptions {
     bail: usize,
+    cancellation_token: CancellationToken,
     initial_run: bool,
     schedule: Schedule,
     store: Option<Store>,
@@ -16,6 +17,7 @@ impl ScheduleOptions {
     pub fn new(schedule: Schedule) -> Self {
         Self {
             bail: 0,
+            cancellation_token: CancellationToken::new(),
             initial_run: false,
             schedule,
             store: None,
@@ -27,6 +29,11 @@ impl ScheduleOptions {
         self.bail
     }
 
+    /// Get the token to stop the schedule and the running evaluation.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
     /// Get schedule.
     pub fn schedule(&self) -> &Schedule {
         &self.schedule
@@ -38,6 +45,12 @@ impl ScheduleOptions {
         self
     }
 
+    /// Set the token to stop the schedule and the running evaluation.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancellation_token = token;
+        self
+    }
+
     /// Set initial run.
     pub fn set_initial_run(&mut self, initial_run: bool) -> &mut Self {
         self.initial_run = initial_run;
@@ -1,29 +1,122 @@
-use crate::StoreOptions;
+use crate::{
+    cache::{CachePolicy, ResponseCache},
+    middleware::Middleware,
+    routes::{check_paths, Route, HEALTH_PATH, READY_PATH},
+    tls::{CertResolver, TlsFiles},
+    watch::{self, Snapshot, POLL_INTERVAL},
+    StoreOptions,
+};
+use anyhow::{bail, Context as _};
 use axum::{
-    body::Bytes,
-    extract::{Path, State as AxumState},
-    http::{HeaderMap, Method, StatusCode},
-    response::IntoResponse,
-    routing::any,
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State as AxumState,
+    },
+    http::{
+        header::{CONTENT_TYPE, HOST},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{any, get, on, MethodFilter},
     Router,
 };
+use clap::ValueEnum;
+use futures_util::stream;
 use http::{HeaderName, HeaderValue};
-use lmb::{EvaluationBuilder, State, StateKey, Store};
-use serde_json::{Map, Value};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use lmb::{
+    decode_form, Context, Error, EvaluationBuilder, EvaluationPool, HttpError, Metrics,
+    PooledEvaluation, State, StateKey, Store, DEFAULT_POOL_SIZE,
+};
+use parking_lot::RwLock;
+use rustls::ServerConfig;
+use serde_json::{json, Map, Value};
 use std::{
-    collections::HashMap, fmt::Display, io::Cursor, str::FromStr as _, sync::Arc, time::Duration,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    convert::Infallible,
+    fmt::Display,
+    fs,
+    future::Future,
+    hash::{BuildHasher as _, Hasher as _},
+    io::{self, Cursor, Write},
+    iter,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+    task, time,
 };
-use tokio::net::ToSocketAddrs;
-use tower_http::trace::{self, TraceLayer};
-use tracing::{error, info, warn, Level};
+use tokio_rustls::TlsAcceptor;
+use tower_http::{
+    compression::CompressionLayer,
+    trace::{self, TraceLayer},
+};
+use tower_service::Service as _;
+use tracing::{debug, error, info, warn, Level};
+
+type Pool = Arc<EvaluationPool<Cursor<Bytes>>>;
+
+/// Number of chunks buffered before the script is blocked by a slow client.
+const CHUNK_BUFFER_SIZE: usize = 16;
+
+/// Path of the metrics endpoint.
+const METRICS_PATH: &str = "/metrics";
+
+/// Default time to wait for in-flight requests on shutdown.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time to wait for the TLS handshake of a new connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header of the request ID, which is generated when absent.
+static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Format of error responses when the script fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    /// JSON problem details, without the cause unless it's an HTTP error raised by the script
+    #[default]
+    Problem,
+    /// Rendered diagnostic of the Lua error in plain text, for development only
+    Dev,
+}
+
+/// Respond to failed requests.
+#[derive(Clone)]
+struct ErrorHandler {
+    format: ErrorFormat,
+    // the user-defined error script
+    pool: Option<Pool>,
+}
 
 #[derive(Clone)]
 struct AppState {
+    errors: ErrorHandler,
     json: bool,
-    name: String,
-    script: String,
-    store: Store,
-    timeout: Option<Duration>,
+    // swapped when the script is reloaded, while running requests keep the previous one
+    script: Arc<RwLock<Script>>,
+}
+
+/// Evaluations of the script, and responses cached from them.
+#[derive(Clone)]
+struct Script {
+    cache: Arc<ResponseCache>,
+    pool: Pool,
 }
 
 pub struct ServeOptions<S, T>
@@ -32,11 +125,25 @@ where
     T: Display + ToSocketAddrs,
 {
     bind: T,
+    compression: bool,
+    context: Context,
+    drain_timeout: Duration,
+    error_format: ErrorFormat,
+    error_script: Option<PathBuf>,
     json: bool,
+    memory_limit: Option<usize>,
+    metrics: bool,
+    middleware: Middleware,
+    module_paths: Vec<PathBuf>,
     name: S,
+    pool_size: usize,
+    routes: Vec<Route>,
     script: S,
     store_options: StoreOptions,
     timeout: Option<Duration>,
+    tls: Option<TlsFiles>,
+    unix_socket: Option<PathBuf>,
+    watch: Option<PathBuf>,
 }
 
 impl<S, T> ServeOptions<S, T>
@@ -48,89 +155,517 @@ where
     pub fn new(name: S, script: S, bind: T, store_options: StoreOptions) -> Self {
         Self {
             bind,
+            compression: false,
+            context: Context::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            error_format: ErrorFormat::default(),
+            error_script: None,
             json: false,
+            memory_limit: None,
+            metrics: false,
+            middleware: Middleware::default(),
+            module_paths: Vec::new(),
             name,
+            pool_size: DEFAULT_POOL_SIZE,
+            routes: Vec::new(),
             script,
             store_options,
             timeout: None,
+            tls: None,
+            unix_socket: None,
+            watch: None,
         }
     }
 
+    /// Set whether to compress response bodies with gzip or brotli
+    /// when the client accepts them.
+    pub fn set_compression(&mut self, yes: bool) -> &mut Self {
+        self.compression = yes;
+        self
+    }
+
+    /// Set arguments and environment variables exposed to the script.
+    pub fn set_context(&mut self, context: Context) -> &mut Self {
+        self.context = context;
+        self
+    }
+
+    /// Set time to wait for in-flight requests on shutdown.
+    /// Requests still running after then are dropped.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the format of error responses.
+    pub fn set_error_format(&mut self, format: ErrorFormat) -> &mut Self {
+        self.error_format = format;
+        self
+    }
+
+    /// Set the script building the response when a request fails.
+    /// The error is exposed as `require('@lmb').error`.
+    pub fn set_error_script(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.error_script = path;
+        self
+    }
+
     /// Set JSON mode.
     pub fn set_json(&mut self, yes: bool) -> &mut Self {
         self.json = yes;
         self
     }
 
+    /// Set or unset memory limit in bytes.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) -> &mut Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Set whether to expose metrics at `/metrics` in Prometheus text format.
+    /// The middleware of the server e.g. auth is applied to it.
+    pub fn set_metrics(&mut self, yes: bool) -> &mut Self {
+        self.metrics = yes;
+        self
+    }
+
+    /// Set middleware rejecting requests before they are handled by the script.
+    /// With routes, the middleware of a route overrides it, and each route is limited separately.
+    pub fn set_middleware(&mut self, middleware: Middleware) -> &mut Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Set directories to search for required modules.
+    pub fn set_module_paths(&mut self, module_paths: Vec<PathBuf>) -> &mut Self {
+        self.module_paths = module_paths;
+        self
+    }
+
+    /// Set the number of pre-built evaluations.
+    pub fn set_pool_size(&mut self, pool_size: usize) -> &mut Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Set routes mapping methods and paths to scripts.
+    /// When routes are set, the script is not served.
+    pub fn set_routes(&mut self, routes: Vec<Route>) -> &mut Self {
+        self.routes = routes;
+        self
+    }
+
     /// Set or unset timeout.
     pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
         self.timeout = timeout;
         self
     }
+
+    /// Set or unset the certificate and the private key to serve HTTPS.
+    pub fn set_tls(&mut self, tls: Option<TlsFiles>) -> &mut Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Set or unset the Unix domain socket to listen on instead of the TCP address.
+    /// Serving fails when a rate limit is set, because client addresses are unknown.
+    pub fn set_unix_socket(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.unix_socket = path;
+        self
+    }
+
+    /// Set or unset the script path to watch. The script is reloaded when
+    /// the script or the required modules are changed.
+    /// When routes are set, the script of every route is watched instead.
+    pub fn set_watch(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.watch = path;
+        self
+    }
 }
 
-fn do_handle_request<S>(
+async fn do_handle_request<S>(
     state: AppState,
-    method: Method,
+    ws: Option<WebSocketUpgrade>,
+    parts: Parts,
     path: S,
-    headers: HeaderMap,
+    params: HashMap<String, String>,
     body: Bytes,
-) -> impl IntoResponse
+) -> Response
 where
     S: AsRef<str>,
 {
-    let e = EvaluationBuilder::new(state.script, Cursor::new(body))
-        .name(state.name)
-        .timeout(state.timeout)
-        .store(state.store.clone())
-        .build();
+    let Script { cache, pool } = state.script.read().clone();
+    if ws.is_none() {
+        if let Some(res) = cache.get(&parts) {
+            return res;
+        }
+    }
+    let e = match pool.get_async().await {
+        Ok(e) => e,
+        Err(err) => {
+            error!(%err, "failed to check out evaluation");
+            return state
+                .respond_error(&parts, None, None, err.to_string())
+                .await;
+        }
+    };
+    e.set_input(Cursor::new(body));
 
     let mut headers_map: Map<_, Value> = Map::new();
-    for (name, value) in headers {
-        if let Some(name) = name {
-            let value = value.to_str().unwrap_or("");
-            headers_map.insert(name.to_string(), value.into());
-        }
+    for (name, value) in &parts.headers {
+        let value = value.to_str().unwrap_or("");
+        headers_map.insert(name.to_string(), value.into());
     }
 
+    let params_map = params
+        .into_iter()
+        .map(|(name, value)| (name, value.into()))
+        .collect::<Map<_, Value>>();
+
+    let query = decode_form(parts.uri.query().unwrap_or_default().as_bytes());
+    let remote_addr = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+
     let mut request_map: Map<_, Value> = Map::new();
-    request_map.insert("method".into(), method.as_str().into());
+    request_map.insert("method".into(), parts.method.as_str().into());
     request_map.insert("path".into(), path.as_ref().into());
+    request_map.insert("params".into(), params_map.into());
+    request_map.insert("query".into(), query);
     request_map.insert("headers".into(), headers_map.into());
+    request_map.insert("remote_addr".into(), remote_addr.into());
+    request_map.insert("url".into(), request_url(&parts).into());
+    request_map.insert("version".into(), format!("{:?}", parts.version).into());
 
     let eval_state = Arc::new(State::new());
     eval_state.insert(StateKey::Request, request_map.into());
 
-    let res = e.evaluate_with_state(eval_state.clone());
-    match res {
-        Ok(res) => match build_response(state.json, eval_state, res.payload()) {
-            Ok(t) => t,
-            Err(err) => {
-                error!(?err, "failed to build response");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    HeaderMap::new(),
-                    String::new(),
-                )
+    if let Some(ws) = ws {
+        let json = state.json;
+        return ws.on_upgrade(move |socket| handle_websocket(socket, e, eval_state, json));
+    }
+
+    let (head_tx, mut head_rx) = oneshot::channel();
+    let (chunk_tx, chunk_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+    e.set_output(Some(Box::new(ChunkWriter {
+        head: Some(head_tx),
+        state: eval_state.clone(),
+        tx: chunk_tx,
+    })));
+
+    // the evaluation is cancelled when the client disconnects and the future is dropped
+    let dev = state.errors.format == ErrorFormat::Dev;
+    let mut evaluation = Box::pin({
+        let eval_state = eval_state.clone();
+        async move {
+            match e.evaluate_async(Some(eval_state)).await {
+                Ok(res) => {
+                    let bytes = res.payload_bytes().map(<[u8]>::to_vec);
+                    Ok((res.payload().clone(), bytes))
+                }
+                Err(err) => {
+                    // rendered while the evaluation is checked out, which has the script
+                    let mut diagnostic = String::new();
+                    if dev {
+                        let _ = err.write_lua_error(&mut diagnostic, &e, true);
+                    }
+                    Err((err, diagnostic))
+                }
+            }
+        }
+    });
+    let head = tokio::select! {
+        // the head is sent before the first chunk, so it wins when both are ready
+        biased;
+        Ok(head) = &mut head_rx => {
+            // the response is streamed, so the evaluation is detached from the request
+            tokio::spawn(async move {
+                if let Err((err, _)) = evaluation.await {
+                    error!(%err, "failed to run Lua script");
+                }
+            });
+            head
+        }
+        res = &mut evaluation => {
+            // the script may write and return before the head is polled,
+            // and the chunks are already buffered
+            if let Ok(head) = head_rx.try_recv() {
+                if let Err((err, _)) = res {
+                    error!(%err, "failed to run Lua script");
+                }
+                head
+            } else {
+                let request = eval_state.get(&StateKey::Request).map(|r| r.clone());
+                return match res {
+                    Ok((payload, bytes)) => match build_response(state.json, &eval_state, &payload, bytes) {
+                        Ok((status_code, headers, body)) => cache.put(&parts, status_code, headers, body),
+                        Err(err) => {
+                            error!(?err, "failed to build response");
+                            state.respond_error(&parts, request, None, err.to_string()).await
+                        }
+                    },
+                    Err((Error::Cancelled, _)) => {
+                        warn!("request cancelled");
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                    Err((err, diagnostic)) => {
+                        let http_error = err.http_error();
+                        if http_error.is_none() {
+                            error!(%err, "failed to run Lua script");
+                        }
+                        let detail = if diagnostic.is_empty() { err.to_string() } else { diagnostic };
+                        state.respond_error(&parts, request, http_error, detail).await
+                    }
+                };
             }
         },
+    };
+    match head {
+        Ok((status_code, headers)) => {
+            let body = Body::from_stream(stream::unfold(chunk_rx, |mut rx| async move {
+                let chunk = rx.recv().await?;
+                Some((Ok::<_, Infallible>(chunk), rx))
+            }));
+            (status_code, headers, body).into_response()
+        }
         Err(err) => {
-            error!(%err, "failed to run Lua script");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                HeaderMap::new(),
-                String::new(),
-            )
+            error!(?err, "failed to build response");
+            let request = eval_state.get(&StateKey::Request).map(|r| r.clone());
+            state
+                .respond_error(&parts, request, None, err.to_string())
+                .await
         }
     }
 }
 
-fn build_response(
+impl AppState {
+    /// Respond to the failed request with the error script, or in the error format.
+    /// The detail is only exposed in the development format unless it's an HTTP error.
+    async fn respond_error(
+        &self,
+        parts: &Parts,
+        request: Option<Value>,
+        http_error: Option<&HttpError>,
+        detail: String,
+    ) -> Response {
+        let request_id = request_id(&parts.headers);
+        let (status, message) = match http_error {
+            Some(e) => (
+                StatusCode::from_u16(e.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                Some(e.message.clone()),
+            ),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        };
+        let mut res = None;
+        if let Some(pool) = &self.errors.pool {
+            let error = json!({
+                "message": message.as_ref().unwrap_or(&detail),
+                "request_id": request_id,
+                "status": status.as_u16(),
+            });
+            match evaluate_error_script(self.json, pool, request, error, status).await {
+                Ok(r) => res = Some(r),
+                Err(err) => error!(?err, %request_id, "failed to run error script"),
+            }
+        }
+        let mut res = res.unwrap_or_else(|| match (self.errors.format, message) {
+            (_, Some(message)) => problem(status, Some(message), &request_id),
+            (ErrorFormat::Dev, None) => (status, detail).into_response(),
+            (ErrorFormat::Problem, None) => problem(status, None, &request_id),
+        });
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert(REQUEST_ID.clone(), value);
+        }
+        res
+    }
+}
+
+/// Build the error response with the error script.
+/// The status code is the status of the error unless the script sets the response.
+async fn evaluate_error_script(
     json: bool,
+    pool: &Pool,
+    request: Option<Value>,
+    error: Value,
+    status: StatusCode,
+) -> anyhow::Result<Response> {
+    let e = pool.get_async().await?;
+    let state = Arc::new(State::new());
+    if let Some(request) = request {
+        state.insert(StateKey::Request, request);
+    }
+    state.insert(StateKey::Error, error);
+    state.insert(
+        StateKey::Response,
+        json!({ "status_code": status.as_u16() }),
+    );
+    let res = e.evaluate_async(Some(state.clone())).await?;
+    let bytes = res.payload_bytes().map(<[u8]>::to_vec);
+    Ok(build_response(json, &state, res.payload(), bytes)?.into_response())
+}
+
+/// Build a JSON problem details response.
+///
+/// ref: <https://www.rfc-editor.org/rfc/rfc9457>
+fn problem(status: StatusCode, detail: Option<String>, request_id: &str) -> Response {
+    let mut body = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+        "request_id": request_id,
+    });
+    if let Some(detail) = detail {
+        body["detail"] = detail.into();
+    }
+    let content_type = [(CONTENT_TYPE, "application/problem+json")];
+    (status, content_type, body.to_string()).into_response()
+}
+
+/// Get the request ID from the header, or generate a random one.
+fn request_id(headers: &HeaderMap) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    if let Some(id) = headers
+        .get(&REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty())
+    {
+        return id.to_string();
+    }
+    // each random state is seeded differently
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Relay messages between the WebSocket connection and the script, which holds
+/// the evaluation until it returns. The returned value is sent as the last message
+/// unless the client has closed the connection.
+async fn handle_websocket(
+    mut socket: WebSocket,
+    e: PooledEvaluation<Cursor<Bytes>>,
     state: Arc<State>,
-    value: &Value,
-) -> anyhow::Result<(StatusCode, HeaderMap, String)> {
-    let (status_code, headers) = state
+    json: bool,
+) {
+    // a script waiting for messages isn't interrupted, so stop receiving on timeout
+    let deadline = time::sleep(e.timeout());
+    tokio::pin!(deadline);
+    let (incoming_tx, incoming_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+    e.set_websocket(Some(lmb::WebSocket::new(incoming_rx, outgoing_tx)));
+
+    let mut evaluation = Box::pin(async move {
+        let res = e.evaluate_async(Some(state)).await?;
+        Ok::<_, Error>(res.payload().clone())
+    });
+    // messages from the client are held here while the script is busy, and the client
+    // isn't read when it's full. The sender is dropped when the client closes the
+    // connection and all messages are delivered, so the script receives nil.
+    let mut pending = VecDeque::new();
+    let mut closed = false;
+    let mut incoming_tx = Some(incoming_tx);
+    let payload = loop {
+        if closed && pending.is_empty() {
+            incoming_tx = None;
+        }
+        let reserve = async {
+            match &incoming_tx {
+                Some(tx) if !pending.is_empty() => tx.reserve().await.ok(),
+                _ => None,
+            }
+        };
+        tokio::select! {
+            res = &mut evaluation => break res,
+            () = &mut deadline, if !closed => {
+                pending.clear();
+                closed = true;
+            }
+            Some(message) = outgoing_rx.recv() => {
+                if let Err(err) = socket.send(into_message(message)).await {
+                    warn!(%err, "failed to send websocket message");
+                }
+            }
+            Some(permit) = reserve => {
+                permit.send(pending.pop_front().unwrap_or_default());
+            }
+            message = socket.recv(), if !closed && pending.len() < CHUNK_BUFFER_SIZE => {
+                match message {
+                    Some(Ok(Message::Text(text))) => pending.push_back(text.into_bytes()),
+                    Some(Ok(Message::Binary(bytes))) => pending.push_back(bytes),
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => closed = true,
+                }
+            }
+        }
+    };
+    // send messages left in the channel before the last message
+    while let Ok(message) = outgoing_rx.try_recv() {
+        let _ = socket.send(into_message(message)).await;
+    }
+    match payload {
+        Ok(Value::Null) => {}
+        Ok(value) => {
+            let message = match (json, value) {
+                (false, Value::String(s)) => Message::Text(s),
+                (_, value) => Message::Text(value.to_string()),
+            };
+            let _ = socket.send(message).await;
+        }
+        Err(err) => error!(%err, "failed to run Lua script"),
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// Send a valid UTF-8 string as a text message, otherwise a binary message.
+fn into_message(message: Vec<u8>) -> Message {
+    match String::from_utf8(message) {
+        Ok(text) => Message::Text(text),
+        Err(err) => Message::Binary(err.into_bytes()),
+    }
+}
+
+/// Stream the chunks written by the script with `response:write(chunk)` to the response body.
+struct ChunkWriter {
+    head: Option<oneshot::Sender<anyhow::Result<(StatusCode, HeaderMap)>>>,
+    state: Arc<State>,
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // status code and headers are sent along with the first chunk
+        if let Some(head) = self.head.take() {
+            let _ = head.send(build_head(&self.state));
+        }
+        // the evaluation runs on a blocking thread
+        self.tx
+            .blocking_send(Bytes::copy_from_slice(buf))
+            .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reconstruct the URL requested by the client from the host header.
+fn request_url(parts: &Parts) -> String {
+    if parts.uri.scheme().is_some() {
+        return parts.uri.to_string();
+    }
+    let host = parts
+        .headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| parts.uri.host())
+        .unwrap_or("localhost");
+    let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    format!("http://{host}{path_and_query}")
+}
+
+fn build_head(state: &State) -> anyhow::Result<(StatusCode, HeaderMap)> {
+    let (status_code, headers, cache) = state
         .view(&StateKey::Response, |_k, res| {
             let status_code = res
                 .get("status_code")
@@ -148,44 +683,102 @@ fn build_response(
                     );
                 }
             }
-            (status_code, m)
+            (status_code, m, res.get("cache").cloned())
         })
-        .unwrap_or_else(|| (200u64, HashMap::new()));
+        .unwrap_or_else(|| (200u64, HashMap::new(), None));
 
     let status_code = StatusCode::from_u16(u16::try_from(status_code)?)?;
     let mut header_map = HeaderMap::new();
     for (name, value) in headers.iter() {
         header_map.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
     }
-    let body = if json {
-        serde_json::to_string(&value)?
-    } else {
-        match value {
-            Value::String(s) => s.to_string(),
-            _ => value.to_string(),
-        }
+    if let Some(cache) = cache {
+        let policy: CachePolicy = serde_json::from_value(cache)?;
+        policy.apply(&mut header_map)?;
+    }
+    Ok((status_code, header_map))
+}
+
+fn build_response(
+    json: bool,
+    state: &State,
+    value: &Value,
+    bytes: Option<Vec<u8>>,
+) -> anyhow::Result<(StatusCode, HeaderMap, Bytes)> {
+    let (status_code, header_map) = build_head(state)?;
+    let body = match (json, bytes, value) {
+        (true, _, _) => serde_json::to_string(&value)?.into(),
+        // a string is sent verbatim, even if it's not a valid UTF-8 string
+        (false, Some(bytes), _) => bytes.into(),
+        (false, None, Value::String(s)) => s.to_string().into(),
+        (false, None, _) => value.to_string().into(),
     };
     Ok((status_code, header_map, body))
 }
 
 async fn index_route(
     AxumState(state): AxumState<AppState>,
-    method: Method,
-    headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+    parts: Parts,
     body: Bytes,
 ) -> impl IntoResponse {
-    do_handle_request(state, method, "/", headers, body)
+    do_handle_request(state, ws, parts, "/", HashMap::new(), body).await
 }
 
 async fn match_all_route(
     AxumState(state): AxumState<AppState>,
-    method: Method,
     Path(path): Path<String>,
-    headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+    parts: Parts,
     body: Bytes,
 ) -> impl IntoResponse {
     let path = format!("/{path}");
-    do_handle_request(state, method, path, headers, body)
+    do_handle_request(state, ws, parts, path, HashMap::new(), body).await
+}
+
+async fn table_route(
+    AxumState(state): AxumState<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    ws: Option<WebSocketUpgrade>,
+    parts: Parts,
+    body: Bytes,
+) -> impl IntoResponse {
+    let path = parts.uri.path().to_string();
+    do_handle_request(state, ws, parts, path, params, body).await
+}
+
+/// Reload the script when it or the required modules are changed,
+/// and start with an empty cache, so responses of the previous script are not reused.
+async fn watch_script<F>(path: PathBuf, script: Arc<RwLock<Script>>, build_pool: F)
+where
+    F: Fn(&str) -> Pool + Clone + Send + 'static,
+{
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let watched = |script: &Script| iter::once(path.clone()).chain(script.pool.module_files());
+    let mut snapshot = Snapshot::new(watched(&script.read()));
+    loop {
+        interval.tick().await;
+        snapshot.extend(script.read().pool.module_files());
+        if !snapshot.is_changed() {
+            continue;
+        }
+        // the diagnostic is logged, so it's not colored
+        if let Some(source) = watch::reload(&path, &snapshot, true) {
+            let build_pool = build_pool.clone();
+            match tokio::task::spawn_blocking(move || build_pool(&source)).await {
+                Ok(pool) => {
+                    *script.write() = Script {
+                        cache: Arc::default(),
+                        pool,
+                    };
+                }
+                Err(err) => error!(%err, "failed to build evaluations"),
+            }
+        }
+        // keep watching modules required by the previous pool, which are not required again yet
+        let files = snapshot.files().cloned().collect::<Vec<_>>();
+        snapshot = Snapshot::new(files.into_iter().chain(watched(&script.read())));
+    }
 }
 
 pub fn init_route<S, T>(opts: &ServeOptions<S, T>) -> anyhow::Result<Router>
@@ -205,46 +798,384 @@ where
         warn!("no store path is specified, an in-memory store will be used and values will be lost when process ends");
         store
     };
-    let app_state = AppState {
-        json: opts.json,
-        name: opts.name.to_string(),
-        script: opts.script.to_string(),
-        store,
-        timeout: opts.timeout,
+    tokio::spawn(crate::purge_expired(store.clone()));
+    let metrics = Metrics::new();
+    let pool_builder = |name: String, timeout: Option<Duration>| {
+        let context = opts.context.clone();
+        let memory_limit = opts.memory_limit;
+        let metrics = metrics.clone();
+        let module_paths = opts.module_paths.clone();
+        let pool_size = opts.pool_size;
+        let store = store.clone();
+        move |script: &str| {
+            EvaluationBuilder::new(script, Cursor::new(Bytes::new()))
+                .context(context.clone())
+                .memory_limit(memory_limit)
+                .metrics(metrics.clone())
+                .module_paths(module_paths.clone())
+                .name(&name)
+                .timeout(timeout)
+                .store(store.clone())
+                .build_pool(pool_size)
+        }
+    };
+    let errors = ErrorHandler {
+        format: opts.error_format,
+        pool: match &opts.error_script {
+            Some(path) => {
+                let script = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Some(pool_builder(path.display().to_string(), opts.timeout)(
+                    &script,
+                ))
+            }
+            None => None,
+        },
     };
-    let app = Router::new()
-        .route("/", any(index_route))
-        .route("/*path", any(match_all_route))
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    let serve_script = |name: String, script: &str, timeout: Option<Duration>, watch| {
+        let build_pool = pool_builder(name, timeout);
+        let script = Arc::new(RwLock::new(Script {
+            cache: Arc::default(),
+            pool: build_pool(script),
+        }));
+        if let Some(path) = watch {
+            tokio::spawn(watch_script(path, script.clone(), build_pool));
+        }
+        AppState {
+            errors: errors.clone(),
+            json: opts.json,
+            script,
+        }
+    };
+    let app = if opts.routes.is_empty() {
+        let app_state = serve_script(
+            opts.name.to_string(),
+            &opts.script.to_string(),
+            opts.timeout,
+            opts.watch.clone(),
+        );
+        let layers = opts.middleware.build()?;
+        Router::new()
+            .route("/", layers.apply(any(index_route)))
+            .route("/*path", layers.apply(any(match_all_route)))
+            .with_state(app_state)
+    } else {
+        if opts.metrics {
+            if let Err((route, _)) = check_paths(&[METRICS_PATH], &opts.routes) {
+                bail!(
+                    "{METRICS_PATH} is reserved for metrics: {}",
+                    route.file.display()
+                );
+            }
+        }
+        let mut app = Router::new();
+        for route in &opts.routes {
+            let script = fs::read_to_string(&route.file)
+                .with_context(|| format!("failed to read {}", route.file.display()))?;
+            let app_state = serve_script(
+                route.file.display().to_string(),
+                &script,
+                route.timeout().or(opts.timeout),
+                opts.watch.as_ref().map(|_| route.file.clone()),
+            );
+            let method_router = match &route.method {
+                Some(method) => on(MethodFilter::try_from(method.clone())?, table_route),
+                None => any(table_route),
+            };
+            let layers = route.middleware.clone().or(&opts.middleware).build()?;
+            let method_router = layers.apply(method_router);
+            info!(method = ?route.method, path = route.path, file = ?route.file, "route");
+            app = app.route(&route.path, method_router.with_state(app_state));
+        }
+        app
+    };
+    // static paths take precedence over the catch-all path of the script
+    let app = app
+        .route(HEALTH_PATH, get(health_route))
+        .route(READY_PATH, get(ready_route).with_state(store));
+    let app = if opts.metrics {
+        // protected by the middleware of the server, with limits of its own
+        let layers = opts.middleware.build()?;
+        app.route(
+            METRICS_PATH,
+            layers.apply(get(metrics_route)).with_state(metrics),
         )
-        .with_state(app_state);
+    } else {
+        app
+    };
+    let app = if opts.compression {
+        app.layer(CompressionLayer::new())
+    } else {
+        app
+    };
+    let app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    );
     Ok(app)
 }
 
-pub async fn serve_file<'a, S, T>(opts: &ServeOptions<S, T>) -> anyhow::Result<()>
+/// Always healthy while the server is accepting requests.
+async fn health_route() -> &'static str {
+    "ok"
+}
+
+/// Ready when the store is migrated to the latest version.
+async fn ready_route(AxumState(store): AxumState<Store>) -> impl IntoResponse {
+    // the store is locked while a script is updating a value
+    let version = task::spawn_blocking(move || store.current_version()).await;
+    match version {
+        Ok(Ok(version)) if version == Store::latest_version() => (StatusCode::OK, "ok".to_string()),
+        Ok(Ok(version)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("store is not migrated to the latest version: {version}"),
+        ),
+        Ok(Err(err)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("failed to read the store: {err}"),
+        ),
+        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+    }
+}
+
+async fn metrics_route(AxumState(metrics): AxumState<Metrics>) -> impl IntoResponse {
+    let content_type = [(CONTENT_TYPE, "text/plain; version=0.0.4")];
+    (content_type, metrics.render())
+}
+
+/// Serve metrics at `/metrics` in Prometheus text format, e.g. for scheduled scripts.
+pub async fn serve_metrics<T>(bind: T, metrics: Metrics) -> anyhow::Result<()>
 where
-    S: Display,
     T: Display + ToSocketAddrs,
 {
-    let bind = &opts.bind;
-    let app = init_route(opts)?;
+    let app = Router::new().route(METRICS_PATH, get(metrics_route).with_state(metrics));
     let listener = tokio::net::TcpListener::bind(&bind).await?;
-    info!(%bind, "serving lua script");
+    info!(%bind, "serving metrics");
     axum::serve(listener, app).await?;
     Ok(())
 }
 
+/// Listener of the server.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Listen on the Unix domain socket, replacing the stale socket file.
+    #[cfg(unix)]
+    fn bind_unix(path: &std::path::Path) -> anyhow::Result<Self> {
+        use std::os::unix::fs::FileTypeExt as _;
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("failed to bind {}", path.display()))?;
+        Ok(Self::Unix(listener))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &std::path::Path) -> anyhow::Result<Self> {
+        bail!("Unix domain sockets are not supported on this platform")
+    }
+}
+
+/// Stream of an accepted connection.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Connection with the client address, which is unnamed for a Unix domain socket.
+type Accepted = (Box<dyn Connection>, Option<SocketAddr>);
+
+/// Accept connections until the shutdown future resolves,
+/// then wait for the connections to finish in-flight requests.
+async fn serve_listener<F>(
+    listener: Listener,
+    app: Router,
+    tls: Option<Arc<ServerConfig>>,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    let graceful = GracefulShutdown::new();
+    let acceptor = tls.map(TlsAcceptor::from);
+    // TLS handshakes are done in their own tasks, and the connections are sent back here
+    // to be watched for the graceful shutdown
+    let (handshaked_tx, mut handshaked_rx) = mpsc::unbounded_channel::<Accepted>();
+    tokio::pin!(shutdown);
+    loop {
+        let accept = async {
+            let accepted: Accepted = match &listener {
+                Listener::Tcp(l) => {
+                    let (stream, addr) = l.accept().await?;
+                    stream.set_nodelay(true)?;
+                    (Box::new(stream), Some(addr))
+                }
+                #[cfg(unix)]
+                Listener::Unix(l) => {
+                    let (stream, _) = l.accept().await?;
+                    (Box::new(stream), None)
+                }
+            };
+            Ok::<_, io::Error>(accepted)
+        };
+        tokio::select! {
+            res = accept => match (res, &acceptor) {
+                (Ok((stream, addr)), Some(acceptor)) => {
+                    handshake(acceptor.clone(), stream, addr, handshaked_tx.clone());
+                }
+                (Ok((stream, addr)), None) => spawn_connection(stream, addr, &app, &graceful),
+                (Err(err), _) => {
+                    // e.g. too many open files, so back off
+                    warn!(%err, "failed to accept connection");
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            },
+            Some((stream, addr)) = handshaked_rx.recv() => {
+                spawn_connection(stream, addr, &app, &graceful);
+            }
+            () = &mut shutdown => break,
+        }
+    }
+    graceful.shutdown().await;
+}
+
+fn handshake(
+    acceptor: TlsAcceptor,
+    stream: Box<dyn Connection>,
+    addr: Option<SocketAddr>,
+    handshaked: mpsc::UnboundedSender<Accepted>,
+) {
+    tokio::spawn(async move {
+        match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let _ = handshaked.send((Box::new(stream), addr));
+            }
+            Ok(Err(err)) => debug!(%err, "TLS handshake failed"),
+            Err(_) => debug!("TLS handshake timed out"),
+        }
+    });
+}
+
+fn spawn_connection<IO>(io: IO, addr: Option<SocketAddr>, app: &Router, graceful: &GracefulShutdown)
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let app = app.clone();
+    let service = service_fn(move |mut req: http::Request<Incoming>| {
+        // the client address of a Unix domain socket is unnamed
+        if let Some(addr) = addr {
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+        app.clone().call(req)
+    });
+    let conn = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .into_owned();
+    let conn = graceful.watch(conn);
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!(%err, "connection closed");
+        }
+    });
+}
+
+/// Serve until the signal resolves, then stop accepting connections
+/// and wait for in-flight requests until the drain timeout elapses.
+pub async fn serve_file<'a, S, T, F>(opts: &ServeOptions<S, T>, signal: F) -> anyhow::Result<()>
+where
+    S: Display,
+    T: Display + ToSocketAddrs,
+    F: Future<Output = ()> + Send + 'static,
+{
+    if opts.unix_socket.is_some() {
+        let rate_limited = std::iter::once(&opts.middleware)
+            .chain(opts.routes.iter().map(|r| &r.middleware))
+            .any(|m| m.rate_limit.is_some());
+        if rate_limited {
+            bail!("rate limit is not supported on a Unix domain socket, where client addresses are unknown");
+        }
+    }
+    let app = init_route(opts)?;
+    let tls = match &opts.tls {
+        Some(files) => {
+            let resolver = Arc::new(CertResolver::new(files.clone())?);
+            tokio::spawn(resolver.clone().watch());
+            Some(resolver.server_config())
+        }
+        None => None,
+    };
+    let listener = if let Some(path) = &opts.unix_socket {
+        let listener = Listener::bind_unix(path)?;
+        info!(?path, tls = tls.is_some(), "serving lua script");
+        listener
+    } else {
+        let bind = &opts.bind;
+        let listener = TcpListener::bind(bind).await?;
+        info!(%bind, tls = tls.is_some(), "serving lua script");
+        Listener::Tcp(listener)
+    };
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        signal.await;
+        let _ = shutdown_tx.send(true);
+    });
+    let wait_for_shutdown = |mut rx: tokio::sync::watch::Receiver<bool>| async move {
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    };
+    let server = serve_listener(listener, app, tls, wait_for_shutdown(shutdown_rx.clone()));
+    let drain_timeout = opts.drain_timeout;
+    let drain = async move {
+        wait_for_shutdown(shutdown_rx).await;
+        info!(?drain_timeout, "shutting down, wait for in-flight requests");
+        time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        () = server => {},
+        () = drain => warn!("drain timeout elapsed, drop in-flight requests"),
+    }
+    if let Some(path) = &opts.unix_socket {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::init_route;
-    use crate::{serve::ServeOptions, Cli, StoreOptions};
+    use super::{init_route, serve_listener, Listener, REQUEST_ID};
+    use crate::{
+        middleware::Middleware,
+        routes::load_routes,
+        serve::{ErrorFormat, ServeOptions},
+        tls::{CertResolver, TlsFiles},
+        watch::POLL_INTERVAL,
+        Cli, StoreOptions,
+    };
+    use assert_fs::{prelude::*, NamedTempFile, TempDir};
     use axum_test::TestServer;
     use clap::Parser;
-    use http::HeaderValue;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use http::{
+        header::{
+            ACCEPT_ENCODING, ACCEPT_LANGUAGE, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_REQUEST_METHOD, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING,
+            CONTENT_TYPE, ETAG, IF_NONE_MATCH, ORIGIN, VARY, WWW_AUTHENTICATE,
+        },
+        HeaderValue, Method,
+    };
     use serde_json::{json, Value};
+    use std::{fs, future, path::PathBuf, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+        sync::oneshot,
+        task,
+    };
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
 
     #[tokio::test]
     async fn echo_request() {
@@ -259,7 +1190,13 @@ mod tests {
 
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
-        let res = server.post("/foo/bar/baz").json(&json!({"a":1})).await;
+        let res = server
+            .post("/foo/bar/baz")
+            .add_query_param("a", 1)
+            .add_query_param("b", 2)
+            .add_query_param("b", 3)
+            .json(&json!({"a":1}))
+            .await;
         assert_eq!(200, res.status_code());
 
         let value: Value = serde_json::from_str(&res.text()).unwrap();
@@ -270,7 +1207,12 @@ mod tests {
                     "content-type": "application/json",
                 },
                 "method": "POST",
+                "params": {},
                 "path": "/foo/bar/baz",
+                "query": { "a": "1", "b": ["2", "3"] },
+                "remote_addr": null,
+                "url": "http://localhost/foo/bar/baz?a=1&b=2&b=3",
+                "version": "HTTP/1.1",
             },
         });
         assert_eq!(expected, value);
@@ -312,9 +1254,76 @@ mod tests {
         opts.set_json(cli.json);
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
-        let res = server.post("/").await;
+        let res = server
+            .post("/")
+            .add_header(REQUEST_ID.clone(), HeaderValue::from_static("id"))
+            .await;
         assert_eq!(500, res.status_code());
-        assert_eq!("", res.text());
+        assert_eq!("application/problem+json", res.header(CONTENT_TYPE));
+        assert_eq!("id", res.header(REQUEST_ID.clone()));
+        let expected = json!({
+            "type": "about:blank",
+            "title": "Internal Server Error",
+            "status": 500,
+            "request_id": "id",
+        });
+        assert_eq!(expected, res.json::<Value>());
+    }
+
+    #[tokio::test]
+    async fn http_error() {
+        let script = "error(require('@lmb'):http_error(404, 'user not found'))";
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(404, res.status_code());
+        let value = res.json::<Value>();
+        assert_eq!(json!("Not Found"), value["title"]);
+        assert_eq!(json!("user not found"), value["detail"]);
+        assert_eq!(16, value["request_id"].as_str().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn error_format_dev() {
+        let script = "return nil + 1";
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("a.lua", script, "", store_options);
+        opts.set_error_format(ErrorFormat::Dev);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(500, res.status_code());
+        let text = res.text();
+        assert!(text.contains("a.lua:1"), "{text}");
+        assert!(text.contains("attempt to perform arithmetic (add) on nil and number"));
+    }
+
+    #[tokio::test]
+    async fn error_script() {
+        let error_script = NamedTempFile::new("error.lua").unwrap();
+        error_script
+            .write_str(
+                r#"
+                local m = require('@lmb')
+                m.response = {
+                  status_code = m.error.status,
+                  headers = { ['content-type'] = 'text/plain' },
+                }
+                return m.request.path .. ' ' .. m.error.status .. ' ' .. m.error.message
+                "#,
+            )
+            .unwrap();
+        let script = "error(require('@lmb'):http_error(403))";
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", script, "", store_options);
+        opts.set_error_script(Some(error_script.to_path_buf()));
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/a").await;
+        assert_eq!(403, res.status_code());
+        assert_eq!("/a 403 Forbidden", res.text());
     }
 
     #[tokio::test]
@@ -334,7 +1343,7 @@ mod tests {
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
         assert_eq!(500, res.status_code());
-        assert_eq!("", res.text());
+        assert_eq!(json!(500), res.json::<Value>()["status"]);
     }
 
     #[tokio::test]
@@ -365,6 +1374,405 @@ mod tests {
         assert_eq!("1", res.text());
     }
 
+    #[tokio::test]
+    async fn binary_string() {
+        let script = r#"return '\0\255'"#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(200, res.status_code());
+        assert_eq!(&[0u8, 255][..], res.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn stream_response() {
+        let script = r#"
+        local m = require('@lmb')
+        local res = m.response
+        res.status_code = 201
+        res.headers = { ['content-type'] = 'text/csv' }
+        for i = 1, 3 do
+          res:write(i .. '\n')
+        end
+        return 'ignored'
+        "#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(201, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("text/csv"),
+            res.headers().get("content-type").unwrap()
+        );
+        assert_eq!("1\n2\n3\n", res.text());
+    }
+
+    #[tokio::test]
+    async fn cache_response() {
+        let script = r#"
+        local m = require('@lmb')
+        local n = (m:get('n') or 0) + 1
+        m:put('n', n)
+        m.response = { cache = { max_age = 60, vary = { 'accept-language' } } }
+        return 'response ' .. n
+        "#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        assert_eq!("response 1", res.text());
+        assert_eq!("max-age=60", res.header(CACHE_CONTROL));
+        assert_eq!("accept-language", res.header(VARY));
+        let etag = res.header(ETAG);
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+        let res = server.get("/").await;
+        assert_eq!("response 1", res.text());
+        assert_eq!("0", res.header(AGE));
+
+        let res = server
+            .get("/")
+            .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"))
+            .await;
+        assert_eq!("response 2", res.text());
+        let res = server.get("/").add_query_param("a", 1).await;
+        assert_eq!("response 3", res.text());
+        let res = server.post("/").await;
+        assert_eq!("response 4", res.text());
+
+        let res = server.get("/").add_header(IF_NONE_MATCH, etag).await;
+        assert_eq!(304, res.status_code());
+        assert_eq!("max-age=60", res.header(CACHE_CONTROL));
+        assert_eq!("", res.text());
+    }
+
+    #[tokio::test]
+    async fn not_modified() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response = { cache = { etag = 'v1' } }
+        return 'a'
+        "#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server
+            .get("/")
+            .add_header(IF_NONE_MATCH, HeaderValue::from_static("\"v1\""))
+            .await;
+        assert_eq!(304, res.status_code());
+        assert_eq!("\"v1\"", res.header(ETAG));
+        let res = server
+            .get("/")
+            .add_header(IF_NONE_MATCH, HeaderValue::from_static("\"v0\""))
+            .await;
+        assert_eq!(200, res.status_code());
+        assert_eq!("a", res.text());
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let script = "return ('a'):rep(1024)";
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", script, "", store_options);
+        opts.set_compression(true);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        for encoding in ["br", "gzip"] {
+            let res = server
+                .get("/")
+                .add_header(ACCEPT_ENCODING, HeaderValue::from_static(encoding))
+                .await;
+            assert_eq!(encoding, res.header(CONTENT_ENCODING));
+            assert!(res.as_bytes().len() < 1024);
+        }
+        let res = server.get("/").await;
+        assert_eq!(1024, res.as_bytes().len());
+    }
+
+    #[tokio::test]
+    async fn health_and_ready() {
+        let script = "return 'script'";
+        let store_file = NamedTempFile::new("db.sqlite3").unwrap();
+        let store_options = StoreOptions::new(Some(store_file.to_path_buf()), false);
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/_lmb/health").await;
+        assert_eq!(200, res.status_code());
+        assert_eq!("ok", res.text());
+        let res = server.get("/_lmb/ready").await;
+        assert_eq!(503, res.status_code());
+        assert_eq!("script", server.get("/_lmb").await.text());
+
+        let store_options = StoreOptions::new(Some(store_file.to_path_buf()), true);
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/_lmb/ready").await;
+        assert_eq!(200, res.status_code());
+    }
+
+    #[tokio::test]
+    async fn middleware() {
+        let secrets = NamedTempFile::new("secrets.toml").unwrap();
+        secrets.write_str(r#"tokens = ["token"]"#).unwrap();
+        let script = "return io.read('*a')";
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", script, "", store_options);
+        opts.set_middleware(Middleware {
+            auth: Some(secrets.to_path_buf()),
+            body_limit: Some(4),
+            cors_origins: vec!["https://example.com".to_string()],
+            rate_limit: Some(2),
+            ..Default::default()
+        });
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        // preflight requests are answered without credentials
+        let res = server
+            .method(Method::OPTIONS, "/")
+            .add_header(ORIGIN, HeaderValue::from_static("https://example.com"))
+            .add_header(
+                ACCESS_CONTROL_REQUEST_METHOD,
+                HeaderValue::from_static("POST"),
+            )
+            .await;
+        assert_eq!(200, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("https://example.com"),
+            res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+
+        let res = server.post("/").text("a").await;
+        assert_eq!(401, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("Bearer"),
+            res.headers().get(WWW_AUTHENTICATE).unwrap()
+        );
+
+        let res = server
+            .post("/")
+            .authorization_bearer("token")
+            .text("hello")
+            .await;
+        assert_eq!(413, res.status_code());
+
+        // two requests per second are allowed, and the preflight request doesn't count
+        let res = server
+            .post("/")
+            .authorization_bearer("token")
+            .text("a")
+            .await;
+        assert_eq!(429, res.status_code());
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let script = "require('@lmb'):increment('hits_total'); return 1";
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("a", script, "", store_options);
+        opts.set_metrics(true);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        server.get("/").await.assert_status_ok();
+        let res = server.get("/metrics").await;
+        assert_eq!(200, res.status_code());
+        let text = res.text();
+        assert!(text.contains("hits_total{script=\"a\"} 1\n"));
+        assert!(text.contains("lmb_evaluation_duration_seconds_count{script=\"a\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_auth() {
+        let secrets = NamedTempFile::new("secrets.toml").unwrap();
+        secrets.write_str(r#"tokens = ["token"]"#).unwrap();
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("a", "return 1", "", store_options);
+        opts.set_metrics(true);
+        opts.set_middleware(Middleware {
+            auth: Some(secrets.to_path_buf()),
+            ..Default::default()
+        });
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/metrics").await;
+        assert_eq!(401, res.status_code());
+        let res = server
+            .get("/metrics")
+            .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer token"))
+            .await;
+        assert_eq!(200, res.status_code());
+    }
+
+    #[tokio::test]
+    async fn server_sent_events() {
+        let script = r#"
+        local res = require('@lmb').response
+        res:event('a')
+        res:event({ event = 'b', data = { c = 1 } })
+        "#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(200, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("text/event-stream"),
+            res.headers().get("content-type").unwrap()
+        );
+        assert_eq!("data: a\n\nevent: b\ndata: {\"c\":1}\n\n", res.text());
+    }
+
+    #[tokio::test]
+    async fn websocket() {
+        let script = r#"
+        local m = require('@lmb')
+        local ws = m.websocket
+        for _ = 1, 2 do
+          ws:send(m.request.path .. ' ' .. ws:receive())
+        end
+        return 'bye'
+        "#;
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Listener::Tcp(listener);
+        tokio::spawn(serve_listener(listener, router, None, future::pending()));
+
+        let (mut socket, _) = connect_async(format!("ws://{addr}/chat")).await.unwrap();
+        for message in ["a", "b"] {
+            socket.send(Message::Text(message.into())).await.unwrap();
+            let received = socket.next().await.unwrap().unwrap();
+            assert_eq!(Message::Text(format!("/chat {message}")), received);
+        }
+        // the returned value is sent as the last message
+        let received = socket.next().await.unwrap().unwrap();
+        assert_eq!(Message::Text("bye".into()), received);
+        let received = socket.next().await.unwrap().unwrap();
+        assert!(received.is_close());
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let fixtures = PathBuf::from("./tests/fixtures/tls");
+        let files = TlsFiles {
+            cert: fixtures.join("cert.pem"),
+            key: fixtures.join("key.pem"),
+        };
+        let config = Arc::new(CertResolver::new(files).unwrap()).server_config();
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", "return 'secure'", "", store_options);
+        let router = init_route(&opts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Listener::Tcp(listener);
+        tokio::spawn(serve_listener(
+            listener,
+            router,
+            Some(config),
+            future::pending(),
+        ));
+
+        // a client stuck in the handshake doesn't block others
+        let _stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+        // a plain HTTP request is rejected by closing the connection
+        let mut plain = tokio::net::TcpStream::connect(addr).await.unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = vec![];
+        let _ = plain.read_to_end(&mut res).await;
+        assert!(!res.starts_with(b"HTTP/"));
+
+        let mut roots = rustls::RootCertStore::empty();
+        let ca = fs::read(fixtures.join("ca.pem")).unwrap();
+        for der in rustls_pemfile::certs(&mut ca.as_slice()) {
+            roots.add(der.unwrap()).unwrap();
+        }
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let agent = ureq::AgentBuilder::new()
+            .tls_config(Arc::new(client_config))
+            .build();
+        let body = task::spawn_blocking(move || {
+            let res = agent.get(&format!("https://{addr}/")).call().unwrap();
+            res.into_string().unwrap()
+        })
+        .await
+        .unwrap();
+        assert_eq!("secure", body);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        use tokio::net::UnixStream;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lmb.sock");
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", "return 'unix'", "", store_options);
+        opts.set_unix_socket(Some(path.clone()));
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            super::serve_file(&opts, async move {
+                let _ = rx.await;
+            })
+            .await
+        });
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("\r\n\r\nunix"), "{res}");
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        // the socket file is removed on shutdown
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_rate_limit() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lmb.sock");
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", "return 'unix'", "", store_options);
+        opts.set_unix_socket(Some(path.clone()));
+        opts.set_middleware(Middleware {
+            rate_limit: Some(1),
+            ..Default::default()
+        });
+        let err = super::serve_file(&opts, async {}).await.unwrap_err();
+        assert!(err.to_string().contains("rate limit"), "{err}");
+        assert!(!path.exists());
+    }
+
     #[tokio::test]
     async fn raw_string() {
         let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
@@ -379,6 +1787,68 @@ mod tests {
         assert_eq!("hello", res.text());
     }
 
+    #[tokio::test]
+    async fn request_json_body() {
+        let script = "return require('@lmb').request.json.a";
+        let store_options = StoreOptions::default();
+        let opts = ServeOptions::new("", script, "", store_options);
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.post("/").json(&json!({"a":1})).await;
+        assert_eq!(200, res.status_code());
+        assert_eq!("1", res.text());
+    }
+
+    #[tokio::test]
+    async fn route_table() {
+        let dir = TempDir::new().unwrap();
+        dir.child("show.lua")
+            .write_str("return 'show ' .. require('@lmb').request.params.id")
+            .unwrap();
+        dir.child("delete.lua")
+            .write_str("return 'delete ' .. require('@lmb').request.params.id")
+            .unwrap();
+        dir.child("files.lua")
+            .write_str("local m = require('@lmb'); return { m.request.method, m.request.params }")
+            .unwrap();
+        let config = dir.child("routes.toml");
+        config
+            .write_str(
+                r#"
+                [[routes]]
+                method = "GET"
+                path = "/users/:id"
+                file = "show.lua"
+
+                [[routes]]
+                method = "DELETE"
+                path = "/users/:id"
+                file = "delete.lua"
+
+                [[routes]]
+                path = "/files/*path"
+                file = "files.lua"
+                "#,
+            )
+            .unwrap();
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", "", "", store_options);
+        opts.set_json(true);
+        opts.set_routes(load_routes(config.path()).unwrap());
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        assert_eq!(r#""show 1""#, server.get("/users/1").await.text());
+        assert_eq!(r#""delete 1""#, server.delete("/users/1").await.text());
+        assert_eq!(405, server.post("/users/1").await.status_code());
+        assert_eq!(404, server.get("/").await.status_code());
+
+        let res = server.put("/files/a/b").await;
+        let value: Value = serde_json::from_str(&res.text()).unwrap();
+        let expected = json!(["PUT", { "path": "a/b" }]);
+        assert_eq!(expected, value);
+    }
+
     #[tokio::test]
     async fn serve() {
         let cli = Cli::parse_from(["lmb", "--json", "serve", "--file", "-"]);
@@ -392,4 +1862,47 @@ mod tests {
         assert_eq!(200, res.status_code());
         assert_eq!("1", res.text());
     }
+
+    #[tokio::test]
+    async fn watch_script() {
+        let script = NamedTempFile::new("script.lua").unwrap();
+        script.write_str("return 1").unwrap();
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new("", "return 1", "", store_options);
+        opts.set_watch(Some(script.to_path_buf()));
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        assert_eq!("1", server.get("/").await.text());
+
+        // modification time of some file systems is in seconds
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        script.write_str("ret 2").unwrap();
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert_eq!("1", server.get("/").await.text());
+
+        script.write_str("return 2").unwrap();
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert_eq!("2", server.get("/").await.text());
+    }
+
+    #[tokio::test]
+    async fn watch_script_cache() {
+        let cached = |n: u32| {
+            format!("require('@lmb').response = {{ cache = {{ max_age = 60 }} }}; return {n}")
+        };
+        let script = NamedTempFile::new("script.lua").unwrap();
+        script.write_str(&cached(1)).unwrap();
+        let store_options = StoreOptions::default();
+        let mut opts = ServeOptions::new(String::new(), cached(1), String::new(), store_options);
+        opts.set_watch(Some(script.to_path_buf()));
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        assert_eq!("1", server.get("/").await.text());
+
+        // the response cached from the previous script is not reused
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        script.write_str(&cached(2)).unwrap();
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert_eq!("2", server.get("/").await.text());
+    }
 }
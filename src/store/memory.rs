@@ -0,0 +1,389 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+use super::{expiry, StoreBackend, StoreRecord, StoreValueMetadata, TransactionFn, UpdateFn};
+use crate::Result;
+
+#[derive(Clone, Debug)]
+struct Entry {
+    value: Value,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    fn is_alive(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map_or(true, |t| t > now)
+    }
+}
+
+type Values = HashMap<String, Entry>;
+
+/// Backend keeping values in a [`HashMap`], which are lost when it's dropped.
+/// A transaction changes the values in place, and restores the replaced entries
+/// when it's rolled back.
+///
+/// ```rust
+/// # use serde_json::json;
+/// use lmb::*;
+///
+/// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// let store = Store::from(MemoryBackend::new());
+/// store.put("a", &true.into())?;
+/// assert_eq!(json!(true), store.get("a")?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    values: Mutex<Values>,
+}
+
+impl MemoryBackend {
+    /// Create an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StoreBackend for MemoryBackend {
+    fn delete(&self, name: &str) -> Result<usize> {
+        let mut values = self.values.lock();
+        Ok(delete_value(&mut values, name))
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        let values = self.values.lock();
+        Ok(get_value(&values, name))
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>> {
+        let values = self.values.lock();
+        Ok(list_values(&values, prefix, cursor, limit))
+    }
+
+    fn put(&self, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize> {
+        let mut values = self.values.lock();
+        insert(&mut values, name, value.clone(), ttl.map(expiry));
+        Ok(1)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: UpdateFn<'_>,
+        default_v: Option<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<Value> {
+        let mut values = self.values.lock();
+        Ok(update_value(&mut values, name, f, default_v, ttl))
+    }
+
+    fn transaction(&self, f: TransactionFn<'_>) -> Result<()> {
+        let mut values = self.values.lock();
+        let tx = MemoryTransaction {
+            undo: RefCell::default(),
+            values: RefCell::new(&mut values),
+        };
+        f(&tx)?;
+        // nothing is restored when the transaction is dropped
+        tx.undo.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn put_record(&self, record: &StoreRecord) -> Result<usize> {
+        let mut values = self.values.lock();
+        Ok(put_record(&mut values, record))
+    }
+
+    fn purge_expired(&self) -> Result<usize> {
+        let mut values = self.values.lock();
+        Ok(purge_expired(&mut values))
+    }
+}
+
+/// View of the values locked by a transaction. The replaced entries are recorded,
+/// and restored in reverse order when it's dropped without being committed,
+/// e.g. the function fails or panics.
+#[derive(Debug)]
+struct MemoryTransaction<'a> {
+    undo: RefCell<Vec<(String, Option<Entry>)>>,
+    values: RefCell<&'a mut Values>,
+}
+
+impl MemoryTransaction<'_> {
+    fn record(&self, values: &Values, name: &str) {
+        let entry = values.get(name).cloned();
+        self.undo.borrow_mut().push((name.to_string(), entry));
+    }
+}
+
+impl Drop for MemoryTransaction<'_> {
+    fn drop(&mut self) {
+        let values = self.values.get_mut();
+        for (name, entry) in self.undo.get_mut().drain(..).rev() {
+            match entry {
+                Some(entry) => values.insert(name, entry),
+                None => values.remove(&name),
+            };
+        }
+    }
+}
+
+impl StoreBackend for MemoryTransaction<'_> {
+    fn delete(&self, name: &str) -> Result<usize> {
+        let mut values = self.values.borrow_mut();
+        self.record(&values, name);
+        Ok(delete_value(&mut values, name))
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        Ok(get_value(&self.values.borrow(), name))
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>> {
+        Ok(list_values(&self.values.borrow(), prefix, cursor, limit))
+    }
+
+    fn put(&self, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize> {
+        let mut values = self.values.borrow_mut();
+        self.record(&values, name);
+        insert(&mut values, name, value.clone(), ttl.map(expiry));
+        Ok(1)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: UpdateFn<'_>,
+        default_v: Option<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<Value> {
+        let mut values = self.values.borrow_mut();
+        self.record(&values, name);
+        Ok(update_value(&mut values, name, f, default_v, ttl))
+    }
+
+    fn transaction(&self, f: TransactionFn<'_>) -> Result<()> {
+        f(self)
+    }
+
+    fn put_record(&self, record: &StoreRecord) -> Result<usize> {
+        let mut values = self.values.borrow_mut();
+        self.record(&values, &record.name);
+        Ok(put_record(&mut values, record))
+    }
+
+    fn purge_expired(&self) -> Result<usize> {
+        let mut values = self.values.borrow_mut();
+        let now = Utc::now();
+        let expired = values
+            .iter()
+            .filter(|(_, e)| !e.is_alive(now))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in &expired {
+            self.record(&values, name);
+            values.remove(name);
+        }
+        Ok(expired.len())
+    }
+}
+
+fn delete_value(values: &mut Values, name: &str) -> usize {
+    let deleted = values.remove(name).filter(|e| e.is_alive(Utc::now()));
+    usize::from(deleted.is_some())
+}
+
+fn get_value(values: &Values, name: &str) -> Value {
+    get_alive(values, name)
+        .map(|e| e.value.clone())
+        .unwrap_or_default()
+}
+
+fn list_values(
+    values: &Values,
+    prefix: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Vec<StoreValueMetadata> {
+    let now = Utc::now();
+    let mut entries = values
+        .iter()
+        .filter(|(name, e)| {
+            name.starts_with(prefix)
+                && cursor.map_or(true, |c| name.as_str() > c)
+                && e.is_alive(now)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_unstable_by_key(|(name, _)| name.as_str());
+    entries
+        .into_iter()
+        .take(limit)
+        .map(|(name, e)| {
+            StoreValueMetadata::new(
+                name.as_str(),
+                &e.value,
+                e.created_at,
+                e.updated_at,
+                e.expires_at,
+            )
+        })
+        .collect()
+}
+
+fn update_value(
+    values: &mut Values,
+    name: &str,
+    f: UpdateFn<'_>,
+    default_v: Option<Value>,
+    ttl: Option<Duration>,
+) -> Value {
+    let (mut value, expires_at) = match get_alive(values, name) {
+        Some(e) => (e.value.clone(), e.expires_at),
+        None => (default_v.unwrap_or_default(), None),
+    };
+    if !f(&mut value) {
+        // the function fails instead of returing a new value,
+        // return the old value instead.
+        return value;
+    }
+    let expires_at = ttl.map(expiry).or(expires_at);
+    insert(values, name, value.clone(), expires_at);
+    value
+}
+
+fn put_record(values: &mut Values, record: &StoreRecord) -> usize {
+    let entry = Entry {
+        value: record.value.clone(),
+        created_at: record.created_at,
+        updated_at: record.updated_at,
+        expires_at: record.expires_at,
+    };
+    values.insert(record.name.clone(), entry);
+    1
+}
+
+fn purge_expired(values: &mut Values) -> usize {
+    let count = values.len();
+    let now = Utc::now();
+    values.retain(|_, e| e.is_alive(now));
+    count - values.len()
+}
+
+fn get_alive<'a>(values: &'a Values, name: &str) -> Option<&'a Entry> {
+    values.get(name).filter(|e| e.is_alive(Utc::now()))
+}
+
+fn insert(values: &mut Values, name: &str, value: Value, expires_at: Option<DateTime<Utc>>) {
+    let now = Utc::now();
+    let created_at = get_alive(values, name).map_or(now, |e| e.created_at);
+    let entry = Entry {
+        value,
+        created_at,
+        updated_at: now,
+        expires_at,
+    };
+    values.insert(name.to_string(), entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::{io::empty, thread, time::Duration};
+
+    use super::MemoryBackend;
+    use crate::{EvaluationBuilder, Store};
+
+    #[test]
+    fn memory_backend() {
+        let script = r#"
+        local m = require('@lmb')
+        m:put('a', 1)
+        m:put('b', 2, { ttl = 0.001 })
+        m:put_many({ ['user:1:a'] = 1, ['user:1:b'] = 2 })
+        assert(m:update('a', function(v) return v + 1 end) == 2)
+        assert(m:update('a', function(v) error('failed') end) == 2)
+        assert(not pcall(function()
+            m:transaction(function(tx)
+                tx:put('a', 10)
+                error('failed')
+            end)
+        end))
+        local names = {}
+        for _, value in ipairs(m:list('user:1:', { cursor = 'user:1:a' })) do
+            table.insert(names, value.name)
+        end
+        return { m:get('a'), names, m:delete_prefix('user:') }
+        "#;
+        let store = Store::from(MemoryBackend::new());
+        let e = EvaluationBuilder::new(script, empty())
+            .store(store.clone())
+            .build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!([2, ["user:1:b"], 2]), res.payload());
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(json!(null), store.get("b").unwrap());
+        assert_eq!(1, store.list().unwrap().len());
+        assert_eq!(1, store.purge_expired().unwrap());
+    }
+
+    #[test]
+    fn memory_backend_rollback() {
+        let store = Store::from(MemoryBackend::new());
+        store.put("a", &json!(1)).unwrap();
+        store.put("b", &json!(2)).unwrap();
+        let res = store.transaction(|tx| {
+            tx.put("a", &json!(10))?;
+            tx.delete("b")?;
+            tx.put("c", &json!(3))?;
+            tx.put("a", &json!(20))?;
+            Err::<(), _>(mlua::Error::runtime("something went wrong").into())
+        });
+        assert!(res.is_err());
+        assert_eq!(json!(1), store.get("a").unwrap());
+        assert_eq!(json!(2), store.get("b").unwrap());
+        assert_eq!(json!(null), store.get("c").unwrap());
+        assert_eq!(2, store.list().unwrap().len());
+    }
+
+    #[test]
+    fn memory_backend_concurrency() {
+        let script = r#"
+        return require('@lmb'):transaction(function(tx)
+            tx:put('a', tx:get('a') - 1)
+            tx:put('b', (tx:get('b') or 0) + 1)
+        end)
+        "#;
+
+        let store = Store::from(MemoryBackend::new());
+        store.put("a", &json!(100)).unwrap();
+
+        let mut threads = vec![];
+        for _ in 0..100 {
+            let store = store.clone();
+            threads.push(thread::spawn(move || {
+                let e = EvaluationBuilder::new(script, empty()).store(store).build();
+                e.evaluate().unwrap();
+            }));
+        }
+        for t in threads {
+            let _ = t.join();
+        }
+        assert_eq!(json!(0), store.get("a").unwrap());
+        assert_eq!(json!(100), store.get("b").unwrap());
+    }
+}
@@ -1,18 +1,24 @@
 use chrono::{DateTime, Utc};
-use parking_lot::Mutex;
-use rusqlite::Connection;
 use rusqlite_migration::SchemaVersion;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
+    collections::HashMap,
+    fmt::Debug,
     mem::size_of,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
-use stmt::*;
-use tracing::{debug, trace, trace_span};
 
-use crate::{Result, MIGRATIONS};
+use crate::{Result, MIGRATIONS_DIR};
 
+pub use memory::MemoryBackend;
+pub use sqlite::SqliteBackend;
+
+mod memory;
+mod sqlite;
 mod stmt;
 
 /// Store options for command line.
@@ -42,14 +48,164 @@ impl StoreOptions {
     }
 }
 
-/// Store that persists data across executions.
+/// Function updating the value in place, which returns `false` to keep the value
+/// in the store unchanged, see [`StoreBackend::update`].
+pub type UpdateFn<'a> = Box<dyn FnOnce(&mut Value) -> bool + 'a>;
+
+/// Function called with the backend of the transaction, see [`StoreBackend::transaction`].
+pub type TransactionFn<'a> = Box<dyn FnOnce(&dyn StoreBackend) -> Result<()> + 'a>;
+
+/// Storage of values behind [`Store`]. [`SqliteBackend`] is the default,
+/// and [`MemoryBackend`] keeps values in memory for tests.
+///
+/// Absent and expired values are read as null. The store is locked while
+/// the function of `update` or `transaction` is running.
+pub trait StoreBackend: Debug {
+    /// Delete value by name, and return the number of deleted values.
+    fn delete(&self, name: &str) -> Result<usize>;
+
+    /// Get value by name.
+    fn get(&self, name: &str) -> Result<Value>;
+
+    /// List values whose names start with the prefix and are after the cursor, in order of name.
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>>;
+
+    /// Put the value, which expires after the time to live.
+    fn put(&self, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize>;
+
+    /// Update the value with the function atomically, see [`Store::update`].
+    fn update(
+        &self,
+        name: &str,
+        f: UpdateFn<'_>,
+        default_v: Option<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<Value>;
+
+    /// Call the function with a backend of the transaction, whose changes are committed
+    /// when the function succeeds, see [`Store::transaction`].
+    fn transaction(&self, f: TransactionFn<'_>) -> Result<()>;
+
+    /// Put the value with its timestamps. By default, timestamps are reset
+    /// and the value is put with the rest of the time to live.
+    fn put_record(&self, record: &StoreRecord) -> Result<usize> {
+        let ttl = match record.expires_at {
+            Some(expires_at) => match (expires_at - Utc::now()).to_std() {
+                Ok(ttl) => Some(ttl),
+                Err(_) => return Ok(0),
+            },
+            None => None,
+        };
+        self.put(&record.name, &record.value, ttl)
+    }
+
+    /// Return current version of migrations.
+    fn current_version(&self) -> Result<SchemaVersion> {
+        Ok(Store::latest_version())
+    }
+
+    /// Delete values whose names start with the prefix in a transaction.
+    fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut affected = 0;
+        self.transaction(Box::new(|tx| {
+            for value in tx.list(prefix, None, usize::MAX)? {
+                affected += tx.delete(value.name())?;
+            }
+            Ok(())
+        }))?;
+        Ok(affected)
+    }
+
+    /// Get values with metadata in order of name in a transaction.
+    fn export(&self) -> Result<Vec<StoreRecord>> {
+        let mut records = vec![];
+        self.transaction(Box::new(|tx| {
+            for m in tx.list("", None, usize::MAX)? {
+                let value = tx.get(m.name())?;
+                records.push(StoreRecord {
+                    name: m.name,
+                    value,
+                    type_hint: m.type_hint,
+                    created_at: m.created_at,
+                    updated_at: m.updated_at,
+                    expires_at: m.expires_at,
+                });
+            }
+            Ok(())
+        }))?;
+        Ok(records)
+    }
+
+    /// Get values by names. Absent values are left out.
+    fn get_many(&self, names: &[&str]) -> Result<HashMap<String, Value>> {
+        let mut res = HashMap::new();
+        self.transaction(Box::new(|tx| {
+            for name in names {
+                let value = tx.get(name)?;
+                if !value.is_null() {
+                    res.insert((*name).to_string(), value);
+                }
+            }
+            Ok(())
+        }))?;
+        Ok(res)
+    }
+
+    /// Put the records in a transaction, except expired ones. Existing values are kept,
+    /// unless they are overwritten.
+    fn import(&self, records: &[StoreRecord], overwrite: bool) -> Result<usize> {
+        let mut affected = 0;
+        self.transaction(Box::new(|tx| {
+            let now = Utc::now();
+            for record in records {
+                if record.expires_at.is_some_and(|t| t <= now)
+                    || (!overwrite && !tx.get(&record.name)?.is_null())
+                {
+                    continue;
+                }
+                affected += tx.put_record(record)?;
+            }
+            Ok(())
+        }))?;
+        Ok(affected)
+    }
+
+    /// Migrate the backend to the version, or the latest.
+    fn migrate(&self, _version: Option<usize>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Delete expired values.
+    fn purge_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Put values in a transaction.
+    fn put_many(&self, values: &[(&str, &Value)], ttl: Option<Duration>) -> Result<usize> {
+        let mut affected = 0;
+        self.transaction(Box::new(|tx| {
+            for (name, value) in values {
+                affected += tx.put(name, value, ttl)?;
+            }
+            Ok(())
+        }))?;
+        Ok(affected)
+    }
+}
+
+/// Store that persists data across executions. Clones share the backend.
 #[derive(Clone, Debug)]
 pub struct Store {
-    conn: Arc<Mutex<Connection>>,
+    backend: Arc<dyn StoreBackend + Send + Sync>,
 }
 
 impl Store {
-    /// Create a new store with path on the filesystem.
+    /// Create a new store with a `SQLite` database on the filesystem, see [`SqliteBackend`].
     ///
     /// ```rust
     /// # use assert_fs::NamedTempFile;
@@ -62,15 +218,7 @@ impl Store {
     /// # }
     /// ```
     pub fn new(path: &Path) -> Result<Self> {
-        debug!(?path, "open store");
-        let conn = Connection::open(path)?;
-        conn.pragma_update(None, "busy_timeout", 5000)?;
-        conn.pragma_update(None, "foreign_keys", "OFF")?;
-        conn.pragma_update(None, "journal_mode", "wal")?;
-        conn.pragma_update(None, "synchronous", "NORMAL")?;
-        Ok(Self {
-            conn: Arc::new(Mutex::new(conn)),
-        })
+        Ok(SqliteBackend::new(path)?.into())
     }
 
     /// Perform migration on the database. Migrations should be idempotent. If version is omitted,
@@ -88,22 +236,28 @@ impl Store {
     /// # }
     /// ```
     pub fn migrate(&self, version: Option<usize>) -> Result<()> {
-        let mut conn = self.conn.lock();
-        if let Some(version) = version {
-            let _s = trace_span!("migrate_to_version", version).entered();
-            MIGRATIONS.to_version(&mut conn, version)?;
-        } else {
-            let _s = trace_span!("migrate_to_latest").entered();
-            MIGRATIONS.to_latest(&mut conn)?;
-        }
-        Ok(())
+        self.backend.migrate(version)
     }
 
     /// Return current version of migrations.
     pub fn current_version(&self) -> Result<SchemaVersion> {
-        let conn = self.conn.lock();
-        let version = MIGRATIONS.current_version(&conn)?;
-        Ok(version)
+        self.backend.current_version()
+    }
+
+    /// Return version of the latest migration.
+    ///
+    /// ```rust
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// assert_eq!(Store::latest_version(), store.current_version()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn latest_version() -> SchemaVersion {
+        NonZeroUsize::new(MIGRATIONS_DIR.dirs().count())
+            .map_or(SchemaVersion::NoneSet, SchemaVersion::Inside)
     }
 
     /// Delete value by name.
@@ -123,13 +277,11 @@ impl Store {
     /// # }
     /// ```
     pub fn delete<S: AsRef<str>>(&self, name: S) -> Result<usize> {
-        let conn = self.conn.lock();
-        let affected = conn.execute(SQL_DELETE_VALUE_BY_NAME, (name.as_ref(),))?;
-        Ok(affected)
+        self.backend.delete(name.as_ref())
     }
 
     /// Get value from the store. A `nil` will be returned to Lua virtual machine
-    /// when the value is absent.
+    /// when the value is absent or expired.
     ///
     /// ```rust
     /// # use serde_json::json;
@@ -144,33 +296,10 @@ impl Store {
     /// # }
     /// ```
     pub fn get<S: AsRef<str>>(&self, name: S) -> Result<Value> {
-        let conn = self.conn.lock();
-
-        let name = name.as_ref();
-
-        let mut cached_stmt = conn.prepare_cached(SQL_GET_VALUE_BY_NAME)?;
-        let _s = trace_span!("store_get", name).entered();
-        let res = cached_stmt.query_row((name,), |row| {
-            let value: Vec<u8> = row.get_unwrap("value");
-            let type_hint: String = row.get_unwrap("type_hint");
-            Ok((value, type_hint))
-        });
-        let value: Vec<u8> = match res {
-            Err(rusqlite::Error::QueryReturnedNoRows) => {
-                trace!("no_value");
-                return Ok(Value::Null);
-            }
-            Err(e) => return Err(e.into()),
-            Ok((v, type_hint)) => {
-                trace!(type_hint, "value");
-                v
-            }
-        };
-
-        Ok(rmp_serde::from_slice::<Value>(&value)?)
+        self.backend.get(name.as_ref())
     }
 
-    /// List values.
+    /// List values, except expired ones.
     ///
     /// ```rust
     /// # use serde_json::json;
@@ -185,25 +314,56 @@ impl Store {
     /// # }
     /// ```
     pub fn list(&self) -> Result<Vec<StoreValueMetadata>> {
-        let conn = self.conn.lock();
-        let mut cached_stmt = conn.prepare_cached(SQL_GET_ALL_VALUES)?;
-        let mut rows = cached_stmt.query([])?;
-        let mut res = vec![];
-        while let Some(row) = rows.next()? {
-            let name: String = row.get_unwrap("name");
-            let type_hint: String = row.get_unwrap("type_hint");
-            let size: usize = row.get_unwrap("size");
-            let created_at: DateTime<Utc> = row.get_unwrap("created_at");
-            let updated_at: DateTime<Utc> = row.get_unwrap("updated_at");
-            res.push(StoreValueMetadata {
-                name,
-                size,
-                type_hint,
-                created_at,
-                updated_at,
-            });
-        }
-        Ok(res)
+        self.backend.list("", None, usize::MAX)
+    }
+
+    /// List values whose names start with the prefix in order of name, except expired ones.
+    /// Only values after the cursor are listed, so pass the name of the last value as the cursor
+    /// to get the next page.
+    ///
+    /// ```rust
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// for name in ["user:1:a", "user:1:b", "user:1:c", "user:2:a"] {
+    ///     store.put(name, &true.into())?;
+    /// }
+    /// let page = store.list_prefix("user:1:", None, 2)?;
+    /// let names = page.iter().map(|v| v.name()).collect::<Vec<_>>();
+    /// assert_eq!(vec!["user:1:a", "user:1:b"], names);
+    /// let page = store.list_prefix("user:1:", Some("user:1:b"), 2)?;
+    /// assert_eq!(1, page.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>> {
+        self.backend.list(prefix, cursor, limit)
+    }
+
+    /// Get values by names. Absent and expired values are left out.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put("a", &1.into())?;
+    /// let values = store.get_many(&["a", "b"])?;
+    /// assert_eq!(Some(&json!(1)), values.get("a"));
+    /// assert_eq!(None, values.get("b"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_many<S: AsRef<str>>(&self, names: &[S]) -> Result<HashMap<String, Value>> {
+        let names = names.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        self.backend.get_many(&names)
     }
 
     /// Put (insert or update) the value into the store.
@@ -227,18 +387,77 @@ impl Store {
     /// # }
     /// ```
     pub fn put<S: AsRef<str>>(&self, name: S, value: &Value) -> Result<usize> {
-        let conn = self.conn.lock();
+        self.backend.put(name.as_ref(), value, None)
+    }
 
-        let name = name.as_ref();
-        let size = Self::get_size(value);
-        let type_hint = Self::type_hint(value);
-        let value = rmp_serde::to_vec(&value)?;
+    /// Put the value into the store like [`Store::put`], which expires after the time to live.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// # use std::time::Duration;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put_with_ttl("a", &1.into(), Duration::from_secs(60))?;
+    /// assert_eq!(json!(1), store.get("a")?);
+    /// store.put_with_ttl("b", &"hello".into(), Duration::ZERO)?;
+    /// assert_eq!(json!(null), store.get("b")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn put_with_ttl<S: AsRef<str>>(
+        &self,
+        name: S,
+        value: &Value,
+        ttl: Duration,
+    ) -> Result<usize> {
+        self.backend.put(name.as_ref(), value, Some(ttl))
+    }
 
-        let mut cached_stmt = conn.prepare_cached(SQL_UPSERT_STORE)?;
-        let _s = trace_span!("store_insert", name, type_hint).entered();
-        let affected = cached_stmt.execute((name, value, size, type_hint))?;
+    /// Put values into the store in a transaction, so either all or none of them are put.
+    /// The values expire after the time to live, or never if it's omitted.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put_many([("a", json!(1)), ("b", json!(2))], None)?;
+    /// assert_eq!(json!(2), store.get("b")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn put_many<I, S>(&self, values: I, ttl: Option<Duration>) -> Result<usize>
+    where
+        I: IntoIterator<Item = (S, Value)>,
+        S: AsRef<str>,
+    {
+        let values = values.into_iter().collect::<Vec<_>>();
+        let values = values
+            .iter()
+            .map(|(name, value)| (name.as_ref(), value))
+            .collect::<Vec<_>>();
+        self.backend.put_many(&values, ttl)
+    }
 
-        Ok(affected)
+    /// Delete values whose names start with the prefix.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put_many([("user:1:a", json!(1)), ("user:2:a", json!(2))], None)?;
+    /// assert_eq!(1, store.delete_prefix("user:1:")?);
+    /// assert_eq!(json!(2), store.get("user:2:a")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        self.backend.delete_prefix(prefix)
     }
 
     /// Insert or update the value into the store.
@@ -247,7 +466,7 @@ impl Store {
     /// when the closure returns a new value. If the closure results in an error,
     /// the value in the store remains unchanged.
     ///
-    /// This function also takes a default value.
+    /// This function also takes a default value. The expiry of the value is kept.
     ///
     /// # Successfully update the value
     ///
@@ -300,80 +519,197 @@ impl Store {
         f: impl FnOnce(&mut Value) -> mlua::Result<()>,
         default_v: Option<Value>,
     ) -> Result<Value> {
-        let mut conn = self.conn.lock();
-        let tx = conn.transaction()?;
-
-        let name = name.as_ref();
-
-        let _s = trace_span!("store_update", name).entered();
-        let value: Vec<u8> = {
-            let mut cached_stmt = tx.prepare_cached(SQL_GET_VALUE_BY_NAME)?;
-            match cached_stmt.query_row((name,), |row| row.get(0)) {
-                Err(rusqlite::Error::QueryReturnedNoRows) => {
-                    trace!("default_value");
-                    rmp_serde::to_vec(default_v.as_ref().unwrap_or(&Value::Null))?
-                }
-                Err(e) => return Err(e.into()),
-                Ok(v) => {
-                    trace!("value");
-                    v
-                }
-            }
-        };
+        self.backend
+            .update(name.as_ref(), Box::new(|v| f(v).is_ok()), default_v, None)
+    }
 
-        let mut value: Value = rmp_serde::from_slice(&value)?;
-        {
-            let _s = trace_span!("call_function").entered();
-            let Ok(_) = f(&mut value) else {
-                // the function throws an error instead of returing a new value,
-                // return the old value instead.
-                trace!("failed");
-                return Ok(value);
-            };
-        }
-        let size = Self::get_size(&value);
-        let type_hint = Self::type_hint(&value);
-        {
-            let value = rmp_serde::to_vec(&value)?;
-            let mut cached_stmt = tx.prepare_cached(SQL_UPSERT_STORE)?;
-            cached_stmt.execute((name, value, size, type_hint))?;
-        }
-        tx.commit()?;
-        trace!(type_hint, "updated");
+    /// Update the value like [`Store::update`], and reset the expiry to the time to live.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// # use std::time::Duration;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// let ttl = Duration::from_secs(60);
+    /// let updated = store.update_with_ttl("a", |old| {
+    ///     *old = json!(old.as_i64().unwrap_or_default() + 1);
+    ///     Ok(())
+    /// }, Some(0.into()), ttl);
+    /// assert_eq!(json!(1), updated?);
+    /// assert!(store.list()?[0].expires_at().is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update_with_ttl<S: AsRef<str>>(
+        &self,
+        name: S,
+        f: impl FnOnce(&mut Value) -> mlua::Result<()>,
+        default_v: Option<Value>,
+        ttl: Duration,
+    ) -> Result<Value> {
+        self.backend.update(
+            name.as_ref(),
+            Box::new(|v| f(v).is_ok()),
+            default_v,
+            Some(ttl),
+        )
+    }
 
-        Ok(value)
+    /// Run the closure in a transaction, which is committed when the closure succeeds,
+    /// or rolled back when it fails. Values are read and written with [`StoreTransaction`].
+    ///
+    /// The store is locked until the transaction ends, so the closure must not use the store.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put("a", &10.into())?;
+    /// store.transaction(|tx| {
+    ///     let a = tx.get("a")?.as_i64().unwrap_or_default();
+    ///     tx.put("a", &(a - 3).into())?;
+    ///     tx.put("b", &3.into())?;
+    ///     Ok(())
+    /// })?;
+    /// let res = store.transaction(|tx| {
+    ///     tx.delete("a")?;
+    ///     Err::<(), _>(mlua::Error::runtime("something went wrong").into())
+    /// });
+    /// assert!(res.is_err());
+    /// assert_eq!(json!(7), store.get("a")?);
+    /// assert_eq!(json!(3), store.get("b")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn transaction<T>(&self, f: impl FnOnce(&StoreTransaction<'_>) -> Result<T>) -> Result<T> {
+        let mut res = None;
+        self.backend.transaction(Box::new(|backend| {
+            res = Some(f(&StoreTransaction { backend })?);
+            Ok(())
+        }))?;
+        Ok(res.expect("the function is not called in the transaction"))
     }
 
-    fn get_size(v: &Value) -> usize {
-        match v {
-            Value::Null => size_of::<()>(),
-            Value::Bool(_) => size_of::<bool>(),
-            Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
-                (Some(_), _, _) => size_of::<u64>(),
-                (_, Some(_), _) => size_of::<i64>(),
-                (_, _, Some(_)) => size_of::<f64>(),
-                (_, _, _) => unreachable!(),
-            },
-            Value::String(s) => s.capacity(),
-            Value::Array(a) => a.iter().fold(0, |acc, e| acc + Self::get_size(e)),
-            Value::Object(m) => m
-                .iter()
-                .fold(0, |acc, (k, v)| acc + k.capacity() + Self::get_size(v)),
-        }
+    /// Delete expired values, which are already invisible to reads.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put_with_ttl("a", &true.into(), Duration::ZERO)?;
+    /// assert_eq!(1, store.purge_expired()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn purge_expired(&self) -> Result<usize> {
+        self.backend.purge_expired()
     }
 
-    fn type_hint(v: &Value) -> &'static str {
-        match v {
-            Value::Null => "null",
-            Value::Bool(_) => "boolean",
-            Value::Number(_) => "number",
-            Value::String(_) => "string",
-            Value::Array(_) => "array",
-            Value::Object(_) => "object",
-        }
+    /// Export values with their metadata in order of name, except expired ones.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// store.put("a", &"hello".into())?;
+    /// let records = store.export()?;
+    /// assert_eq!("a", records[0].name);
+    /// assert_eq!(json!("hello"), records[0].value);
+    /// assert_eq!("string", records[0].type_hint);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn export(&self) -> Result<Vec<StoreRecord>> {
+        self.backend.export()
+    }
+
+    /// Import values exported by [`Store::export`] with their timestamps in a transaction,
+    /// and return the number of imported values. Expired values are skipped.
+    /// Values with the same names are kept, unless they are overwritten.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let source = Store::default();
+    /// source.put_many([("a", json!(1)), ("b", json!(2))], None)?;
+    /// let records = source.export()?;
+    ///
+    /// let store = Store::default();
+    /// store.put("a", &0.into())?;
+    /// assert_eq!(1, store.import(&records, false)?);
+    /// assert_eq!(json!(0), store.get("a")?);
+    /// assert_eq!(2, store.import(&records, true)?);
+    /// assert_eq!(json!(1), store.get("a")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import(&self, records: &[StoreRecord], overwrite: bool) -> Result<usize> {
+        self.backend.import(records, overwrite)
     }
 }
 
+/// Transaction of the store, see [`Store::transaction`].
+#[derive(Debug)]
+pub struct StoreTransaction<'a> {
+    backend: &'a dyn StoreBackend,
+}
+
+impl StoreTransaction<'_> {
+    /// Delete value by name. See [`Store::delete`].
+    pub fn delete<S: AsRef<str>>(&self, name: S) -> Result<usize> {
+        self.backend.delete(name.as_ref())
+    }
+
+    /// Get value, including the uncommitted value put in the transaction. See [`Store::get`].
+    pub fn get<S: AsRef<str>>(&self, name: S) -> Result<Value> {
+        self.backend.get(name.as_ref())
+    }
+
+    /// Put the value, which is visible outside after the transaction is committed.
+    /// See [`Store::put`].
+    pub fn put<S: AsRef<str>>(&self, name: S, value: &Value) -> Result<usize> {
+        self.backend.put(name.as_ref(), value, None)
+    }
+
+    /// Put the value, which expires after the time to live. See [`Store::put_with_ttl`].
+    pub fn put_with_ttl<S: AsRef<str>>(
+        &self,
+        name: S,
+        value: &Value,
+        ttl: Duration,
+    ) -> Result<usize> {
+        self.backend.put(name.as_ref(), value, Some(ttl))
+    }
+}
+
+/// Value with its metadata, which is exported from and imported into the store.
+/// The type hint is derived from the value on import.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoreRecord {
+    /// Name
+    pub name: String,
+    /// Value
+    pub value: Value,
+    /// Type hint
+    pub type_hint: String,
+    /// Timestamp that the value is created
+    pub created_at: DateTime<Utc>,
+    /// Timestamp that the value is updated
+    pub updated_at: DateTime<Utc>,
+    /// Timestamp that the value expires, if any
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
 /// Value metadata. The value itself is intentionally not included.
 #[derive(Debug)]
 pub struct StoreValueMetadata {
@@ -382,14 +718,48 @@ pub struct StoreValueMetadata {
     type_hint: String,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
 }
 
 impl StoreValueMetadata {
+    /// Create metadata of the value, for backends to list values.
+    ///
+    /// ```rust
+    /// # use chrono::Utc;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    /// let now = Utc::now();
+    /// let metadata = StoreValueMetadata::new("a", &json!("hello"), now, now, None);
+    /// assert_eq!("string", metadata.type_hint());
+    /// assert_eq!(5, metadata.size());
+    /// ```
+    pub fn new<S: Into<String>>(
+        name: S,
+        value: &Value,
+        created_at: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            size: value_size(value),
+            type_hint: type_hint(value).to_string(),
+            created_at,
+            updated_at,
+            expires_at,
+        }
+    }
+
     /// Get the timestamp that the value is created.
     pub fn created_at(&self) -> &DateTime<Utc> {
         &self.created_at
     }
 
+    /// Get the timestamp that the value expires, if any.
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+
     /// Get name.
     pub fn name(&self) -> &str {
         &self.name
@@ -411,14 +781,67 @@ impl StoreValueMetadata {
     }
 }
 
+fn value_size(v: &Value) -> usize {
+    match v {
+        Value::Null => size_of::<()>(),
+        Value::Bool(_) => size_of::<bool>(),
+        Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(_), _, _) => size_of::<u64>(),
+            (_, Some(_), _) => size_of::<i64>(),
+            (_, _, Some(_)) => size_of::<f64>(),
+            (_, _, _) => unreachable!(),
+        },
+        Value::String(s) => s.capacity(),
+        Value::Array(a) => a.iter().fold(0, |acc, e| acc + value_size(e)),
+        Value::Object(m) => m
+            .iter()
+            .fold(0, |acc, (k, v)| acc + k.capacity() + value_size(v)),
+    }
+}
+
+fn type_hint(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Get the timestamp after the time to live, up to [`max_expiry`].
+fn expiry(ttl: Duration) -> DateTime<Utc> {
+    let max = max_expiry();
+    chrono::Duration::from_std(ttl)
+        .ok()
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+        .map_or(max, |t| t.min(max))
+}
+
+/// Get the latest expiry, `9999-12-31 23:59:59.999`. Later years are formatted with a sign
+/// e.g. `+10000-01-01`, which is sorted before others when compared as text by `SQLite`.
+fn max_expiry() -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(253_402_300_799_999).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+impl<B> From<B> for Store
+where
+    B: StoreBackend + Send + Sync + 'static,
+{
+    fn from(backend: B) -> Self {
+        Self {
+            backend: Arc::new(backend),
+        }
+    }
+}
+
 impl Default for Store {
     /// Open and initialize a `SQLite` database in memory.
     fn default() -> Self {
-        debug!("open store in memory");
-        let conn = Connection::open_in_memory().expect("failed to open SQLite database in memory");
-        let store = Self {
-            conn: Arc::new(Mutex::new(conn)),
-        };
+        let backend =
+            SqliteBackend::open_in_memory().expect("failed to open SQLite database in memory");
+        let store = Self::from(backend);
         store
             .migrate(None)
             .expect("failed to migrate SQLite database in memory");
@@ -430,10 +853,38 @@ impl Default for Store {
 mod tests {
     use assert_fs::NamedTempFile;
     use serde_json::{json, Value};
-    use std::{io::empty, thread};
+    use std::{io::empty, thread, time::Duration};
     use test_case::test_case;
 
-    use crate::{EvaluationBuilder, Store};
+    use crate::{EvaluationBuilder, MemoryBackend, Store};
+
+    #[test]
+    fn bulk() {
+        let script = r#"
+        local m = require('@lmb')
+        m:put_many({ ['user:1:a'] = 1, ['user:1:b'] = 2, ['user:1:c'] = 3, ['user:2:a'] = 4 })
+        local page = m:list('user:1:', { limit = 2 })
+        local names = { page[1].name, page[2].name }
+        page = m:list('user:1:', { cursor = page[2].name })
+        table.insert(names, page[1].name)
+        local values = m:get_many({ 'user:1:a', 'user:2:a', 'user:3:a' })
+        assert(not pcall(function() m:delete_prefix('') end))
+        return { names, values, m:delete_prefix('user:1:'), #m:list() }
+        "#;
+
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty())
+            .store(store.clone())
+            .build();
+        let res = e.evaluate().unwrap();
+        let expected = json!([
+            ["user:1:a", "user:1:b", "user:1:c"],
+            { "user:1:a": 1, "user:2:a": 4 },
+            3,
+            1,
+        ]);
+        assert_eq!(&expected, res.payload());
+    }
 
     #[test]
     fn concurrency() {
@@ -459,6 +910,33 @@ mod tests {
         assert_eq!(json!(1001), store.get("a").unwrap());
     }
 
+    #[test]
+    fn concurrency_transaction() {
+        let script = r#"
+        return require('@lmb'):transaction(function(tx)
+            tx:put('a', tx:get('a') - 1)
+            tx:put('b', (tx:get('b') or 0) + 1)
+        end)
+        "#;
+
+        let store = Store::default();
+        store.put("a", &json!(1001)).unwrap();
+
+        let mut threads = vec![];
+        for _ in 0..=1000 {
+            let store = store.clone();
+            threads.push(thread::spawn(move || {
+                let e = EvaluationBuilder::new(script, empty()).store(store).build();
+                e.evaluate().unwrap();
+            }));
+        }
+        for t in threads {
+            let _ = t.join();
+        }
+        assert_eq!(json!(0), store.get("a").unwrap());
+        assert_eq!(json!(1001), store.get("b").unwrap());
+    }
+
     #[test_case("a", json!([true, 1, 1.23, "hello"]), 1+8+8+5)]
     #[test_case("o", json!({ "bool": true, "num": 1.23, "str": "hello" }), (4+1)+(3+8)+(3+5))]
     fn collective_types(key: &'static str, value: Value, size: usize) {
@@ -471,6 +949,41 @@ mod tests {
         assert_eq!(size, value.size());
     }
 
+    #[test]
+    fn export_import() {
+        let source = Store::default();
+        source.put("a", &json!(1)).unwrap();
+        source
+            .put_with_ttl("b", &json!("hello"), Duration::from_secs(60))
+            .unwrap();
+        source
+            .put_with_ttl("c", &json!(true), Duration::ZERO)
+            .unwrap();
+        let records = source.export().unwrap();
+        assert_eq!(
+            vec!["a", "b"],
+            records.iter().map(|r| &r.name).collect::<Vec<_>>()
+        );
+
+        // timestamps are kept across backends
+        let memory = Store::from(MemoryBackend::new());
+        assert_eq!(2, memory.import(&records, false).unwrap());
+        let store = Store::default();
+        assert_eq!(2, store.import(&memory.export().unwrap(), false).unwrap());
+        let exported = store.export().unwrap();
+        for (expected, actual) in records.iter().zip(&exported) {
+            assert_eq!(expected.value, actual.value);
+            assert_eq!(expected.type_hint, actual.type_hint);
+            let millis = |t: &chrono::DateTime<chrono::Utc>| t.timestamp_millis();
+            assert_eq!(millis(&expected.created_at), millis(&actual.created_at));
+            assert_eq!(millis(&expected.updated_at), millis(&actual.updated_at));
+            assert_eq!(
+                expected.expires_at.as_ref().map(millis),
+                actual.expires_at.as_ref().map(millis)
+            );
+        }
+    }
+
     #[test]
     fn get_put() {
         let script = r#"
@@ -498,8 +1011,9 @@ mod tests {
     fn migrate() {
         let store = Store::default();
         store.migrate(None).unwrap(); // duplicated
-        store.current_version().unwrap();
+        assert_eq!(Store::latest_version(), store.current_version().unwrap());
         store.migrate(Some(0)).unwrap();
+        assert_ne!(Store::latest_version(), store.current_version().unwrap());
     }
 
     #[test]
@@ -554,6 +1068,157 @@ mod tests {
         }
     }
 
+    #[test]
+    fn ttl() {
+        let script = r#"
+        local m = require('@lmb')
+        m:put('a', 1, { ttl = 60 })
+        m:put('b', 1, { ttl = 0.001 })
+        m:update('c', function(v) return v + 1 end, 0, { ttl = 60 })
+        m:update('c', function(v) return v + 1 end, 0)
+        assert(not pcall(function() m:put('d', 1, { ttl = -1 }) end))
+        "#;
+
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty())
+            .store(store.clone())
+            .build();
+        e.evaluate().unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(json!(1), store.get("a").unwrap());
+        assert_eq!(json!(null), store.get("b").unwrap());
+        assert_eq!(json!(2), store.get("c").unwrap());
+        let values = store.list().unwrap();
+        let names = values.iter().map(|v| v.name()).collect::<Vec<_>>();
+        assert_eq!(vec!["a", "c"], names);
+        // the expiry is kept when the value is updated without ttl
+        assert!(values.iter().all(|v| v.expires_at().is_some()));
+
+        // expired values are not counted as deleted, and left to be purged
+        assert_eq!(0, store.delete("b").unwrap());
+        assert_eq!(0, store.delete_prefix("b").unwrap());
+        assert_eq!(1, store.purge_expired().unwrap());
+        store.put("b", &2.into()).unwrap();
+        assert_eq!(json!(2), store.get("b").unwrap());
+    }
+
+    #[test_case(Store::default(); "sqlite")]
+    #[test_case(Store::from(MemoryBackend::new()); "memory")]
+    fn ttl_max(store: Store) {
+        let script = r#"
+        local m = require('@lmb')
+        m:put('a', 1, { ttl = 1e12 })
+        m:update('b', function(v) return v + 1 end, 0, { ttl = 1e18 })
+        return { m:get('a'), m:get('b') }
+        "#;
+        let e = EvaluationBuilder::new(script, empty())
+            .store(store.clone())
+            .build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!([1, 1]), res.payload());
+        let values = store.list().unwrap();
+        assert_eq!(2, values.len());
+        for value in values {
+            let expires_at = value.expires_at().unwrap();
+            assert_eq!(
+                "9999-12-31 23:59:59.999",
+                expires_at.format("%F %T%.3f").to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn transaction() {
+        let script = r#"
+        local m = require('@lmb')
+        m:put('a', 1)
+        m:put('b', 2)
+        local ok = pcall(function()
+            m:transaction(function(tx)
+                tx:put('a', 10)
+                tx:delete('b')
+                error('something went wrong')
+            end)
+        end)
+        assert(not ok)
+        local moved = m:transaction(function(tx)
+            assert(not pcall(function() m:get('a') end))
+            tx:put('c', tx:get('a') + tx:get('b'))
+            return { tx:delete('a'), tx:delete('d'), tx:get('c') }
+        end)
+        return { moved, m:get('a') == nil, m:get('b'), m:get('c') }
+        "#;
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty()).store(store).build();
+        let res = e.evaluate().unwrap();
+        assert_eq!(&json!([[true, false, 3], true, 2, 3]), res.payload());
+    }
+
+    #[test]
+    fn store_nested_in_transaction() {
+        let script = r#"
+        local m = require('@lmb')
+        local ok, update_err = pcall(function()
+            m:transaction(function(tx)
+                m:update('a', function(v) return v + 1 end, 0)
+            end)
+        end)
+        assert(not ok)
+        local ok, transaction_err = pcall(function()
+            m:transaction(function(tx)
+                m:transaction(function(tx) end)
+            end)
+        end)
+        assert(not ok)
+        return { tostring(update_err), tostring(transaction_err), m:update('a', function(v) return v + 1 end, 0) }
+        "#;
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty()).store(store).build();
+        let res = e.evaluate().unwrap();
+        let payload = res.payload().as_array().unwrap();
+        for err in &payload[0..2] {
+            assert!(err
+                .as_str()
+                .unwrap()
+                .contains("store is locked by the transaction"));
+        }
+        assert_eq!(json!(1), payload[2]);
+    }
+
+    #[test]
+    fn store_nested_in_update() {
+        let script = r#"
+        local m = require('@lmb')
+        local ok, update_err = pcall(function()
+            m:update('a', function(v)
+                m:update('a', function(v) return v + 1 end, 0)
+                return v + 1
+            end, 0)
+        end)
+        assert(not ok)
+        local ok, transaction_err = pcall(function()
+            m:update('a', function(v)
+                m:transaction(function(tx) end)
+                return v + 1
+            end, 0)
+        end)
+        assert(not ok)
+        return { tostring(update_err), tostring(transaction_err), m:update('a', function(v) return v + 1 end, 0) }
+        "#;
+        let store = Store::default();
+        let e = EvaluationBuilder::new(script, empty()).store(store).build();
+        let res = e.evaluate().unwrap();
+        let payload = res.payload().as_array().unwrap();
+        for err in &payload[0..2] {
+            assert!(err
+                .as_str()
+                .unwrap()
+                .contains("store is locked by the update"));
+        }
+        assert_eq!(json!(1), payload[2]);
+    }
+
     #[test]
     fn update_without_default_value() {
         let script = r#"
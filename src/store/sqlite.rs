@@ -0,0 +1,409 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rusqlite::{
+    backup::{Backup, StepResult},
+    Connection, Row, Transaction,
+};
+use rusqlite_migration::SchemaVersion;
+use serde_json::Value;
+use std::{path::Path, thread, time::Duration};
+use tracing::{debug, trace, trace_span};
+
+use super::{
+    expiry, max_expiry, stmt::*, type_hint, value_size, StoreBackend, StoreRecord,
+    StoreValueMetadata, TransactionFn, UpdateFn,
+};
+use crate::{Result, MIGRATIONS};
+
+/// Interval to retry the backup when the database is locked.
+const BACKUP_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Backend storing values in a `SQLite` database, which is the default of [`crate::Store`].
+#[derive(Debug)]
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    /// Open the database with path on the filesystem.
+    ///
+    /// ```rust
+    /// # use assert_fs::NamedTempFile;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store_file = NamedTempFile::new("db.sqlite3")?;
+    /// let store = Store::from(SqliteBackend::new(store_file.path())?);
+    /// store.migrate(None)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new(path: &Path) -> Result<Self> {
+        debug!(?path, "open store");
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "busy_timeout", 5000)?;
+        conn.pragma_update(None, "foreign_keys", "OFF")?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Back up the database to the path with the online backup API of `SQLite`,
+    /// which copies a consistent snapshot while other connections are using the database.
+    ///
+    /// ref: <https://www.sqlite.org/backup.html>
+    ///
+    /// ```rust
+    /// # use assert_fs::{prelude::*, TempDir};
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let dir = TempDir::new()?;
+    /// let backend = SqliteBackend::new(dir.child("db.sqlite3").path())?;
+    /// backend.migrate(None)?;
+    /// backend.put("a", &true.into(), None)?;
+    /// backend.backup(dir.child("backup.sqlite3").path())?;
+    /// let store = Store::new(dir.child("backup.sqlite3").path())?;
+    /// assert_eq!(json!(true), store.get("a")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        let conn = self.conn.lock();
+        let mut dst = Connection::open(path)?;
+        let backup = Backup::new(&conn, &mut dst)?;
+        let _s = trace_span!("store_backup", ?path).entered();
+        loop {
+            // copy all pages in one step, or the backup restarts whenever the database is written
+            match backup.step(-1)? {
+                StepResult::Done => return Ok(()),
+                StepResult::Busy | StepResult::Locked => thread::sleep(BACKUP_RETRY_INTERVAL),
+                _ => {}
+            }
+        }
+    }
+
+    /// Open the database in memory, which should be migrated before use.
+    pub fn open_in_memory() -> Result<Self> {
+        debug!("open store in memory");
+        let conn = Connection::open_in_memory()?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl StoreBackend for SqliteBackend {
+    fn delete(&self, name: &str) -> Result<usize> {
+        let conn = self.conn.lock();
+        delete_value(&conn, name)
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        let conn = self.conn.lock();
+        get_value(&conn, name)
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>> {
+        let conn = self.conn.lock();
+        list_values(&conn, prefix, cursor, limit)
+    }
+
+    fn put(&self, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize> {
+        let conn = self.conn.lock();
+        put_value(&conn, name, value, ttl)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: UpdateFn<'_>,
+        default_v: Option<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<Value> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let value = update_value(&tx, name, f, default_v, ttl)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    fn transaction(&self, f: TransactionFn<'_>) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = SqliteTransaction {
+            tx: conn.transaction()?,
+        };
+        let _s = trace_span!("store_transaction").entered();
+        // the transaction is rolled back when it's dropped without being committed
+        f(&tx)?;
+        tx.tx.commit()?;
+        trace!("committed");
+        Ok(())
+    }
+
+    fn current_version(&self) -> Result<SchemaVersion> {
+        let conn = self.conn.lock();
+        let version = MIGRATIONS.current_version(&conn)?;
+        Ok(version)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        let conn = self.conn.lock();
+        let mut cached_stmt = conn.prepare_cached(SQL_DELETE_VALUES_BY_PREFIX)?;
+        let _s = trace_span!("store_delete_prefix", prefix).entered();
+        let affected = cached_stmt.execute((prefix, prefix_end(prefix)))?;
+        Ok(affected)
+    }
+
+    fn migrate(&self, version: Option<usize>) -> Result<()> {
+        let mut conn = self.conn.lock();
+        if let Some(version) = version {
+            let _s = trace_span!("migrate_to_version", version).entered();
+            MIGRATIONS.to_version(&mut conn, version)?;
+        } else {
+            let _s = trace_span!("migrate_to_latest").entered();
+            MIGRATIONS.to_latest(&mut conn)?;
+        }
+        Ok(())
+    }
+
+    fn purge_expired(&self) -> Result<usize> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(SQL_DELETE_EXPIRED_VALUES, ())?;
+        debug!(affected, "purge expired values");
+        Ok(affected)
+    }
+
+    fn put_record(&self, record: &StoreRecord) -> Result<usize> {
+        let conn = self.conn.lock();
+        put_record(&conn, record)
+    }
+}
+
+/// Backend of a transaction in progress. Transactions in it are flattened into it.
+#[derive(Debug)]
+struct SqliteTransaction<'a> {
+    tx: Transaction<'a>,
+}
+
+impl StoreBackend for SqliteTransaction<'_> {
+    fn delete(&self, name: &str) -> Result<usize> {
+        delete_value(&self.tx, name)
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        get_value(&self.tx, name)
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoreValueMetadata>> {
+        list_values(&self.tx, prefix, cursor, limit)
+    }
+
+    fn put(&self, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize> {
+        put_value(&self.tx, name, value, ttl)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: UpdateFn<'_>,
+        default_v: Option<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<Value> {
+        update_value(&self.tx, name, f, default_v, ttl)
+    }
+
+    fn transaction(&self, f: TransactionFn<'_>) -> Result<()> {
+        f(self)
+    }
+
+    fn put_record(&self, record: &StoreRecord) -> Result<usize> {
+        put_record(&self.tx, record)
+    }
+}
+
+fn delete_value(conn: &Connection, name: &str) -> Result<usize> {
+    let mut cached_stmt = conn.prepare_cached(SQL_DELETE_VALUE_BY_NAME)?;
+    let _s = trace_span!("store_delete", name).entered();
+    let affected = cached_stmt.execute((name,))?;
+    Ok(affected)
+}
+
+fn get_value(conn: &Connection, name: &str) -> Result<Value> {
+    let mut cached_stmt = conn.prepare_cached(SQL_GET_VALUE_BY_NAME)?;
+    let _s = trace_span!("store_get", name).entered();
+    let res = cached_stmt.query_row((name,), |row| {
+        let value: Vec<u8> = row.get_unwrap("value");
+        let type_hint: String = row.get_unwrap("type_hint");
+        Ok((value, type_hint))
+    });
+    let value: Vec<u8> = match res {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            trace!("no_value");
+            return Ok(Value::Null);
+        }
+        Err(e) => return Err(e.into()),
+        Ok((v, type_hint)) => {
+            trace!(type_hint, "value");
+            v
+        }
+    };
+
+    Ok(rmp_serde::from_slice::<Value>(&value)?)
+}
+
+fn list_values(
+    conn: &Connection,
+    prefix: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<Vec<StoreValueMetadata>> {
+    let mut cached_stmt = conn.prepare_cached(SQL_GET_VALUES_BY_PREFIX)?;
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let _s = trace_span!("store_list", prefix, cursor, limit).entered();
+    let mut rows = cached_stmt.query((prefix, prefix_end(prefix), cursor, limit))?;
+    let mut res = vec![];
+    while let Some(row) = rows.next()? {
+        res.push(metadata_from_row(row));
+    }
+    Ok(res)
+}
+
+fn put_value(conn: &Connection, name: &str, value: &Value, ttl: Option<Duration>) -> Result<usize> {
+    let size = value_size(value);
+    let type_hint = type_hint(value);
+    let value = rmp_serde::to_vec(&value)?;
+    let expires_at = ttl.map(expires_at);
+
+    let mut cached_stmt = conn.prepare_cached(SQL_UPSERT_STORE)?;
+    let _s = trace_span!("store_insert", name, type_hint).entered();
+    let affected = cached_stmt.execute((name, value, size, type_hint, expires_at))?;
+
+    Ok(affected)
+}
+
+fn put_record(conn: &Connection, record: &StoreRecord) -> Result<usize> {
+    let name = record.name.as_str();
+    let size = value_size(&record.value);
+    let type_hint = type_hint(&record.value);
+    let value = rmp_serde::to_vec(&record.value)?;
+    let created_at = format_timestamp(&record.created_at);
+    let updated_at = format_timestamp(&record.updated_at);
+    let expires_at = record
+        .expires_at
+        .map(|t| format_timestamp(&t.min(max_expiry())));
+
+    let mut cached_stmt = conn.prepare_cached(SQL_UPSERT_RECORD)?;
+    let _s = trace_span!("store_put_record", name, type_hint).entered();
+    let params = (
+        name, value, size, type_hint, created_at, updated_at, expires_at,
+    );
+    let affected = cached_stmt.execute(params)?;
+
+    Ok(affected)
+}
+
+fn update_value(
+    conn: &Connection,
+    name: &str,
+    f: UpdateFn<'_>,
+    default_v: Option<Value>,
+    ttl: Option<Duration>,
+) -> Result<Value> {
+    let _s = trace_span!("store_update", name).entered();
+    let (value, expires): (Vec<u8>, Option<String>) = {
+        let mut cached_stmt = conn.prepare_cached(SQL_GET_VALUE_BY_NAME)?;
+        match cached_stmt.query_row((name,), |row| Ok((row.get(0)?, row.get(2)?))) {
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                trace!("default_value");
+                let value = rmp_serde::to_vec(default_v.as_ref().unwrap_or(&Value::Null))?;
+                (value, None)
+            }
+            Err(e) => return Err(e.into()),
+            Ok(v) => {
+                trace!("value");
+                v
+            }
+        }
+    };
+
+    let mut value: Value = rmp_serde::from_slice(&value)?;
+    {
+        let _s = trace_span!("call_function").entered();
+        if !f(&mut value) {
+            // the function fails instead of returing a new value,
+            // return the old value instead.
+            trace!("failed");
+            return Ok(value);
+        }
+    }
+    let size = value_size(&value);
+    let type_hint = type_hint(&value);
+    {
+        let value = rmp_serde::to_vec(&value)?;
+        let expires_at = ttl.map(expires_at).or(expires);
+        let mut cached_stmt = conn.prepare_cached(SQL_UPSERT_STORE)?;
+        cached_stmt.execute((name, value, size, type_hint, expires_at))?;
+    }
+    trace!(type_hint, "updated");
+
+    Ok(value)
+}
+
+fn metadata_from_row(row: &Row<'_>) -> StoreValueMetadata {
+    StoreValueMetadata {
+        name: row.get_unwrap("name"),
+        size: row.get_unwrap("size"),
+        type_hint: row.get_unwrap("type_hint"),
+        created_at: row.get_unwrap("created_at"),
+        updated_at: row.get_unwrap("updated_at"),
+        expires_at: row.get_unwrap::<_, Option<DateTime<Utc>>>("expires_at"),
+    }
+}
+
+/// Get the smallest name greater than all names starting with the prefix,
+/// as strings are compared in order of code points. There's none for an empty prefix.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars().collect::<Vec<_>>();
+    while let Some(c) = chars.pop() {
+        // skip surrogates, which are not characters
+        if let Some(next) = (u32::from(c) + 1..=u32::from(char::MAX)).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+fn expires_at(ttl: Duration) -> String {
+    format_timestamp(&expiry(ttl))
+}
+
+/// Format the timestamp in the same way as `SQLite`, so timestamps are compared as text.
+fn format_timestamp(t: &DateTime<Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn prefix_end() {
+        assert_eq!(None, super::prefix_end(""));
+        assert_eq!(Some("user;".to_string()), super::prefix_end("user:"));
+        assert_eq!(Some("b".to_string()), super::prefix_end("a\u{10FFFF}"));
+        assert_eq!(Some("\u{E000}".to_string()), super::prefix_end("\u{D7FF}"));
+    }
+}
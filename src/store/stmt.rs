@@ -1,12 +1,38 @@
-pub(crate) const SQL_DELETE_VALUE_BY_NAME: &str = "DELETE FROM store WHERE name = ?1";
+pub(crate) const SQL_DELETE_VALUE_BY_NAME: &str = "
+    DELETE FROM store
+    WHERE name = ?1 AND (expires_at IS NULL OR expires_at > strftime('%Y-%m-%d %H:%M:%f', 'now'))
+";
+
+pub(crate) const SQL_DELETE_VALUES_BY_PREFIX: &str = "
+    DELETE FROM store WHERE name >= ?1 AND (?2 IS NULL OR name < ?2)
+    AND (expires_at IS NULL OR expires_at > strftime('%Y-%m-%d %H:%M:%f', 'now'))
+";
+
+pub(crate) const SQL_DELETE_EXPIRED_VALUES: &str = "
+    DELETE FROM store WHERE expires_at <= strftime('%Y-%m-%d %H:%M:%f', 'now')
+";
+
+pub(crate) const SQL_GET_VALUES_BY_PREFIX: &str = "
+    SELECT name, size, type_hint, created_at, updated_at, expires_at FROM store
+    WHERE name >= ?1 AND (?2 IS NULL OR name < ?2) AND (?3 IS NULL OR name > ?3)
+    AND (expires_at IS NULL OR expires_at > strftime('%Y-%m-%d %H:%M:%f', 'now'))
+    ORDER BY name LIMIT ?4
+";
 
-pub(crate) const SQL_GET_ALL_VALUES: &str = "
-    SELECT name, size, type_hint, created_at, updated_at FROM store
+pub(crate) const SQL_GET_VALUE_BY_NAME: &str = "
+    SELECT value, type_hint, expires_at FROM store
+    WHERE name = ?1 AND (expires_at IS NULL OR expires_at > strftime('%Y-%m-%d %H:%M:%f', 'now'))
 ";
 
-pub(crate) const SQL_GET_VALUE_BY_NAME: &str = "SELECT value, type_hint FROM store WHERE name = ?1";
+pub(crate) const SQL_UPSERT_RECORD: &str = "
+    INSERT INTO store (name, value, size, type_hint, created_at, updated_at, expires_at)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+    ON CONFLICT(name) DO UPDATE SET value = ?2, size = ?3, type_hint = ?4,
+        created_at = ?5, updated_at = ?6, expires_at = ?7
+";
 
 pub(crate) const SQL_UPSERT_STORE: &str = r#"
-    INSERT INTO store (name, value, size, type_hint) VALUES (?1, ?2, ?3, ?4)
-    ON CONFLICT(name) DO UPDATE SET value = ?2, size = ?3, type_hint = ?4, updated_at = CURRENT_TIMESTAMP
+    INSERT INTO store (name, value, size, type_hint, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)
+    ON CONFLICT(name) DO UPDATE SET value = ?2, size = ?3, type_hint = ?4, expires_at = ?5, updated_at = CURRENT_TIMESTAMP,
+        created_at = iif(expires_at <= strftime('%Y-%m-%d %H:%M:%f', 'now'), CURRENT_TIMESTAMP, created_at)
 "#;
@@ -0,0 +1,140 @@
+use anyhow::{bail, Context as _};
+use parking_lot::RwLock;
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{error, info};
+
+use crate::watch::{Snapshot, POLL_INTERVAL};
+
+/// Certificate chain and private key files in PEM format.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    /// Certificate chain, from the server certificate to the intermediate certificates
+    pub cert: PathBuf,
+    /// Private key in PKCS#1, PKCS#8, or SEC1 format
+    pub key: PathBuf,
+}
+
+/// Resolve the certificate, which is reloaded when the files are changed.
+#[derive(Debug)]
+pub struct CertResolver {
+    files: TlsFiles,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Load the certificate and the private key.
+    pub fn new(files: TlsFiles) -> anyhow::Result<Self> {
+        let key = load_certified_key(&files)?;
+        Ok(Self {
+            files,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Reload the certificate and the private key.
+    /// The previous ones are kept when the files are invalid.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let key = load_certified_key(&self.files)?;
+        *self.key.write() = Arc::new(key);
+        Ok(())
+    }
+
+    /// Build the server configuration accepting HTTP/2 and HTTP/1.1.
+    pub fn server_config(self: &Arc<Self>) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Arc::new(config)
+    }
+
+    /// Reload the certificate whenever the files are changed.
+    pub async fn watch(self: Arc<Self>) {
+        let files = || [self.files.cert.clone(), self.files.key.clone()];
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut snapshot = Snapshot::new(files());
+        loop {
+            interval.tick().await;
+            if !snapshot.is_changed() {
+                continue;
+            }
+            snapshot = Snapshot::new(files());
+            match self.reload() {
+                Ok(()) => info!(cert = ?self.files.cert, "certificate reloaded"),
+                Err(err) => error!(?err, "failed to reload certificate"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().clone())
+    }
+}
+
+fn load_certified_key(files: &TlsFiles) -> anyhow::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut open(&files.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {}", files.cert.display()))?;
+    if certs.is_empty() {
+        bail!("no certificate in {}", files.cert.display());
+    }
+    let key = rustls_pemfile::private_key(&mut open(&files.key)?)
+        .with_context(|| format!("invalid private key in {}", files.key.display()))?
+        .with_context(|| format!("no private key in {}", files.key.display()))?;
+    let key = any_supported_type(&key)
+        .with_context(|| format!("unsupported private key in {}", files.key.display()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::{CertResolver, TlsFiles};
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from("./tests/fixtures/tls").join(name)
+    }
+
+    #[test]
+    fn reload_certificate() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let files = TlsFiles {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        fs::copy(fixture("cert.pem"), &files.cert).unwrap();
+        fs::copy(fixture("key.pem"), &files.key).unwrap();
+        let resolver = CertResolver::new(files.clone()).unwrap();
+        let first = resolver.key.read().cert.clone();
+
+        // the previous certificate is kept when the files are invalid
+        fs::write(&files.key, "").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(first, resolver.key.read().cert);
+
+        // a RSA key in PKCS#1 format
+        fs::copy(fixture("cert2.pem"), &files.cert).unwrap();
+        fs::copy(fixture("key2.pem"), &files.key).unwrap();
+        resolver.reload().unwrap();
+        assert_ne!(first, resolver.key.read().cert);
+    }
+}
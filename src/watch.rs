@@ -0,0 +1,126 @@
+use lmb::LuaCheck;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+/// Interval to poll the modification time of watched files.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification times of watched files.
+pub struct Snapshot {
+    files: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the files.
+    pub fn new<I>(files: I) -> Self
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let files = files
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Watch more files, e.g. modules required after the snapshot is taken.
+    /// Files already in the snapshot are not updated.
+    pub fn extend<I>(&mut self, files: I)
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        for path in files {
+            if let Entry::Vacant(entry) = self.files.entry(path) {
+                let modified = modified(entry.key());
+                entry.insert(modified);
+            }
+        }
+    }
+
+    /// Check whether any file is modified, created or removed since the snapshot.
+    pub fn is_changed(&self) -> bool {
+        self.files
+            .iter()
+            .any(|(path, modified)| &self::modified(path) != modified)
+    }
+
+    /// Get the watched files.
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.keys()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read the script and check the syntax of the script and the watched modules.
+/// Return `None` and log the diagnostic when any of them is broken,
+/// so the last good version is kept.
+pub fn reload(path: &Path, snapshot: &Snapshot, no_color: bool) -> Option<String> {
+    let mut script = None;
+    for file in snapshot.files() {
+        // the module could be removed after it's no longer required
+        if file != path && !file.exists() {
+            continue;
+        }
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(err) => {
+                error!(?file, %err, "failed to read file, keep the last good version");
+                return None;
+            }
+        };
+        let name = file.to_string_lossy();
+        let check = LuaCheck::new(name.as_ref(), source.as_str());
+        if let Err(err) = check.check() {
+            let mut buf = Vec::new();
+            let _ = check.write_error(&mut buf, err, no_color);
+            error!(
+                ?file,
+                "syntax error, keep the last good version\n{}",
+                String::from_utf8_lossy(&buf).trim_end()
+            );
+            return None;
+        }
+        if file == path {
+            script = Some(source);
+        }
+    }
+    info!(?path, "script reloaded");
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, NamedTempFile};
+    use std::{thread, time::Duration};
+
+    use super::{reload, Snapshot};
+
+    #[test]
+    fn reload_script() {
+        let script = NamedTempFile::new("script.lua").unwrap();
+        script.write_str("return 1").unwrap();
+        let snapshot = Snapshot::new([script.to_path_buf()]);
+        assert!(!snapshot.is_changed());
+
+        // modification time of some file systems is in seconds
+        thread::sleep(Duration::from_millis(1100));
+        script.write_str("ret 2").unwrap();
+        assert!(snapshot.is_changed());
+        assert!(reload(script.path(), &snapshot, true).is_none());
+
+        script.write_str("return 2").unwrap();
+        let reloaded = reload(script.path(), &snapshot, true);
+        assert_eq!(Some("return 2".to_string()), reloaded);
+    }
+}
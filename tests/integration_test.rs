@@ -1,7 +1,7 @@
-use assert_fs::NamedTempFile;
+use assert_fs::{prelude::*, NamedTempFile, TempDir};
 use snapbox::{
     cmd::{cargo_bin, Command},
-    str,
+    str, Assert,
 };
 use std::time::Duration;
 
@@ -44,12 +44,98 @@ fn eval_file() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 nullhello, world!
 
 "#]]);
 }
 
+#[test]
+fn eval_args_and_env() {
+    Command::new(cargo_bin("lmb"))
+        .env("NAME", "lmb")
+        .env("SECRET", "secret")
+        .stdin("local m = require('@lmb'); return { m.args[1], m.env.NAME, m.env.SECRET }")
+        .args([
+            "--no-color",
+            "--json",
+            "--env",
+            "NAME",
+            "eval",
+            "--file",
+            "-",
+            "--",
+            "hello",
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+["hello","lmb"]
+"#]]);
+}
+
+#[test]
+fn eval_each_json() {
+    let script = NamedTempFile::new("script.lua").unwrap();
+    script
+        .write_str("return require('@lmb').record.a * 2")
+        .unwrap();
+    // keep the escaped quotes around the chunk name in the JSON output
+    Command::new(cargo_bin("lmb"))
+        .with_assert(Assert::new().normalize_paths(false))
+        .stdin("{\"a\":1}\n{\"b\":1}\n{\"a\":3}\n")
+        .args(["--no-color", "eval", "--each-json", "--on-error", "emit", "--file"])
+        .arg(script.path())
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+2
+{"error":"lua error: runtime error: [string \"[..]\"]:1: attempt to perform arithmetic (mul) on nil and number","line":2}
+6
+
+"#]]);
+}
+
+#[test]
+fn eval_each_line() {
+    let script = NamedTempFile::new("script.lua").unwrap();
+    script.write_str("return io.read('*a'):upper()").unwrap();
+    Command::new(cargo_bin("lmb"))
+        .stdin("a\nb\n")
+        .args(["--no-color", "eval", "--each-line", "--file"])
+        .arg(script.path())
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+A
+B
+
+"#]]);
+}
+
+#[test]
+fn eval_each_line_multiple_values() {
+    let script = NamedTempFile::new("script.lua").unwrap();
+    script
+        .write_str("local r = io.read('*a'); return r, r")
+        .unwrap();
+    Command::new(cargo_bin("lmb"))
+        .stdin("a\nb\n")
+        .args(["--no-color", "eval", "--each-line", "--file"])
+        .arg(script.path())
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+a
+b
+
+"#]]);
+}
+
 #[test]
 fn eval_json_output() {
     Command::new(cargo_bin("lmb"))
@@ -64,7 +150,7 @@ fn eval_json_output() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 {"bool":true,"num":1.23,"str":"hello"}
 "#]]);
 }
@@ -77,7 +163,7 @@ fn eval_stdin() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 2
 "#]]);
 }
@@ -132,7 +218,7 @@ fn eval_store_migrate() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 true
 "#]]);
 }
@@ -172,7 +258,7 @@ fn example_eval() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 3798601
 "#]]);
 }
@@ -218,9 +304,9 @@ fn example_serve() {
         ])
         .assert()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 [..]  WARN lmb::serve: no store path is specified, an in-memory store will be used and values will be lost when process ends
-[..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3000
+[..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3000 tls=false
 
 "#]]);
 }
@@ -288,6 +374,27 @@ fn guide_list() {
         .success();
 }
 
+#[test]
+fn repl() {
+    Command::new(cargo_bin("lmb"))
+        .stdin("x = 1\nfunction f(a)\n  return a + x\nend\nf(1)\nnil + 1\n")
+        .args(["--no-color", "repl"])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+2
+
+"#]])
+        .stderr_eq(str![[r#"
+Error: attempt to perform arithmetic (add) on nil and number
+   ,-[repl:1:1]
+ 1 |return nil + 1
+   |       `-------- attempt to perform arithmetic (add) on nil and number
+
+"#]]);
+}
+
 #[test]
 fn schedule() {
     let store = NamedTempFile::new("db.sqlite3").unwrap();
@@ -339,11 +446,55 @@ fn serve() {
         .timeout(Duration::from_secs(2))
         .assert()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 [..]  WARN lmb::serve: no store path is specified, an in-memory store will be used and values will be lost when process ends
-[..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3001
+[..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3001 tls=false
+
+"#]]);
+}
+
+#[test]
+fn store_backup() {
+    let dir = TempDir::new().unwrap();
+    let store_path = dir.child("db.sqlite3");
+    let store_path = store_path.path().to_string_lossy();
+    let backup_path = dir.child("backup.sqlite3");
+    let backup_path = backup_path.path().to_string_lossy();
+    Command::new(cargo_bin("lmb"))
+        .stdin("1")
+        .args([
+            "--store-path",
+            &store_path,
+            "--run-migrations",
+            "store",
+            "put",
+            "--name",
+            "a",
+        ])
+        .assert()
+        .success();
+    let args = [
+        "--store-path",
+        &store_path,
+        "store",
+        "backup",
+        "--to",
+        &backup_path,
+    ];
+    Command::new(cargo_bin("lmb")).args(args).assert().success();
+    Command::new(cargo_bin("lmb"))
+        .args(args)
+        .assert()
+        .failure()
+        .stderr_eq(str![[r#"
+backup already exists: [..]
 
 "#]]);
+    Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &backup_path, "store", "get", "--name", "a"])
+        .assert()
+        .success()
+        .stdout_eq(str!["1"]);
 }
 
 #[test]
@@ -368,7 +519,7 @@ fn store_delete() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 1
 "#]]);
 
@@ -387,6 +538,126 @@ fn store_delete() {
         .stdout_eq(str!["1"]);
 }
 
+#[test]
+fn store_export_import() {
+    let dir = TempDir::new().unwrap();
+    let store_path = dir.child("db.sqlite3");
+    let store_path = store_path.path().to_string_lossy();
+    for (name, value) in [("a", "1"), ("b", "\"hello\"")] {
+        Command::new(cargo_bin("lmb"))
+            .stdin(value)
+            .args([
+                "--store-path",
+                &store_path,
+                "--run-migrations",
+                "store",
+                "put",
+                "--name",
+                name,
+            ])
+            .assert()
+            .success();
+    }
+    let output = Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &store_path, "store", "export"])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+{"name":"a","value":1,"type_hint":"number","created_at":"[..]","updated_at":"[..]","expires_at":null}
+{"name":"b","value":"hello","type_hint":"string","created_at":"[..]","updated_at":"[..]","expires_at":null}
+
+"#]])
+        .get_output()
+        .stdout
+        .clone();
+
+    let imported = dir.child("imported.sqlite3");
+    let imported = imported.path().to_string_lossy();
+    Command::new(cargo_bin("lmb"))
+        .stdin("2")
+        .args([
+            "--store-path",
+            &imported,
+            "--run-migrations",
+            "store",
+            "put",
+            "--name",
+            "a",
+        ])
+        .assert()
+        .success();
+    Command::new(cargo_bin("lmb"))
+        .stdin(output.clone())
+        .args(["--store-path", &imported, "store", "import"])
+        .assert()
+        .success()
+        .stdout_eq(str!["1"]);
+    Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &imported, "store", "get", "--name", "a"])
+        .assert()
+        .success()
+        .stdout_eq(str!["2"]);
+    Command::new(cargo_bin("lmb"))
+        .stdin(output)
+        .args([
+            "--store-path",
+            &imported,
+            "store",
+            "import",
+            "--mode",
+            "overwrite",
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str!["2"]);
+    Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &imported, "store", "get", "--name", "a"])
+        .assert()
+        .success()
+        .stdout_eq(str!["1"]);
+
+    let exported = dir.child("export.msgpack");
+    let exported = exported.path().to_string_lossy();
+    Command::new(cargo_bin("lmb"))
+        .args([
+            "--store-path",
+            &store_path,
+            "store",
+            "export",
+            "--format",
+            "msgpack",
+            "--output",
+            &exported,
+        ])
+        .assert()
+        .success();
+    let msgpack = dir.child("msgpack.sqlite3");
+    let msgpack = msgpack.path().to_string_lossy();
+    Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &msgpack, "store", "migrate"])
+        .assert()
+        .success();
+    Command::new(cargo_bin("lmb"))
+        .args([
+            "--store-path",
+            &msgpack,
+            "store",
+            "import",
+            "--format",
+            "msgpack",
+            "--input",
+            &exported,
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str!["2"]);
+    Command::new(cargo_bin("lmb"))
+        .args(["--store-path", &msgpack, "store", "get", "--name", "b"])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#""hello""#]]);
+}
+
 #[test]
 fn store_get() {
     let store = NamedTempFile::new("db.sqlite3").unwrap();
@@ -405,7 +676,7 @@ fn store_get() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 null
 "#]]);
 }
@@ -432,7 +703,7 @@ fn store_get_list_put() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 1
 "#]]);
 
@@ -448,7 +719,7 @@ fn store_get_list_put() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
- name  type    size  created at                 updated at                
+ name  type    size  created at                 updated at                 expires at 
  a     number  8     [..]
 
 "#]]);
@@ -485,8 +756,48 @@ fn store_list() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
- name  type  size  created at  updated at 
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+ name  type  size  created at  updated at  expires at 
+
+"#]]);
+}
+
+#[test]
+fn store_list_prefix() {
+    let store = NamedTempFile::new("db.sqlite3").unwrap();
+    let store_path = store.path().to_string_lossy();
+    for name in ["user:1:a", "user:1:b", "user:2:a"] {
+        Command::new(cargo_bin("lmb"))
+            .stdin("1")
+            .args([
+                "--store-path",
+                &store_path,
+                "--run-migrations",
+                "store",
+                "put",
+                "--name",
+                name,
+            ])
+            .assert()
+            .success();
+    }
+    Command::new(cargo_bin("lmb"))
+        .args([
+            "--no-color",
+            "--store-path",
+            &store_path,
+            "store",
+            "list",
+            "--prefix",
+            "user:1:",
+            "--limit",
+            "1",
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+ name      type    size  created at                 updated at                 expires at 
+ user:1:a  number  8     [..]
 
 "#]]);
 }
@@ -506,7 +817,7 @@ fn store_migrate() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 
 "#]]);
 }